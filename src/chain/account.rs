@@ -0,0 +1,34 @@
+//! Account fields without storage
+
+use crate::prelude::*;
+
+/// Hash of the empty code, `keccak256("")`
+pub const EMPTY_CODE_HASH: U256 =
+    u256h!("c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470");
+
+/// Account as read with [`ChainState::account`]
+///
+/// [`ChainState::account`]: super::ChainState::account
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Account {
+    pub nonce:     usize,
+    pub balance:   U256,
+    pub code_hash: U256,
+}
+
+impl Default for Account {
+    fn default() -> Self {
+        Self {
+            nonce:     0,
+            balance:   U256::zero(),
+            code_hash: EMPTY_CODE_HASH,
+        }
+    }
+}
+
+impl Account {
+    /// No nonce, balance or code, see <https://eips.ethereum.org/EIPS/eip-161>
+    pub fn is_empty(&self) -> bool {
+        self.nonce == 0 && self.balance.is_zero() && self.code_hash == EMPTY_CODE_HASH
+    }
+}
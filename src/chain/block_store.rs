@@ -0,0 +1,179 @@
+//! Local block store
+//!
+//! Keeps every block mined or imported locally together with its receipts.
+//! Blocks are indexed by hash, canonical blocks also by number, and the
+//! transactions of canonical blocks by hash.
+
+use super::types::{
+    rpc::{BlockNumber, TransactionReceipt},
+    Block,
+};
+use crate::{prelude::*, utils::RlpHash};
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredBlock {
+    pub hash:     U256,
+    pub block:    Block,
+    pub receipts: Vec<TransactionReceipt>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct BlockStore {
+    blocks:       HashMap<U256, StoredBlock>,
+    /// Hashes of canonical blocks by number
+    canonical:    BTreeMap<u64, U256>,
+    /// Block hash and index of transactions in canonical blocks
+    transactions: HashMap<U256, (U256, usize)>,
+    head:         Option<U256>,
+}
+
+impl BlockStore {
+    /// Store a block and return its hash
+    ///
+    /// The block becomes the canonical head unless the current head is
+    /// higher. Known ancestors of the new head replace canonical blocks at
+    /// the same height.
+    pub fn insert(&mut self, block: Block, receipts: Vec<TransactionReceipt>) -> U256 {
+        let hash = block.header.rlp_hash();
+        let number = block.header.number;
+        let _previous = self.blocks.insert(hash.clone(), StoredBlock {
+            hash: hash.clone(),
+            block,
+            receipts,
+        });
+        if self
+            .head()
+            .map_or(true, |head| head.block.header.number <= number)
+        {
+            self.set_head(&hash);
+        }
+        hash
+    }
+
+    pub fn head(&self) -> Option<&StoredBlock> {
+        self.head.as_ref().and_then(|hash| self.blocks.get(hash))
+    }
+
+    pub fn by_hash(&self, hash: &U256) -> Option<&StoredBlock> {
+        self.blocks.get(hash)
+    }
+
+    /// Canonical block at height `number`
+    pub fn by_number(&self, number: u64) -> Option<&StoredBlock> {
+        self.canonical
+            .get(&number)
+            .and_then(|hash| self.blocks.get(hash))
+    }
+
+    /// There is no pending block, `Pending` resolves to the head
+    pub fn resolve(&self, number: BlockNumber) -> Option<&StoredBlock> {
+        match number {
+            BlockNumber::Latest | BlockNumber::Pending => self.head(),
+            BlockNumber::Earliest => self.by_number(0),
+            BlockNumber::Number(number) => self.by_number(number),
+        }
+    }
+
+    /// Canonical block containing a transaction and its index in the block
+    pub fn transaction(&self, hash: &U256) -> Option<(&StoredBlock, usize)> {
+        let (block, index) = self.transactions.get(hash)?;
+        Some((self.blocks.get(block)?, *index))
+    }
+
+    pub fn receipt(&self, hash: &U256) -> Option<&TransactionReceipt> {
+        let (block, index) = self.transaction(hash)?;
+        block.receipts.get(index)
+    }
+
+    fn set_head(&mut self, hash: &U256) {
+        let number = self.blocks[hash].block.header.number;
+        for (_, stale) in self.canonical.split_off(&(number + 1)) {
+            unindex(&mut self.transactions, &self.blocks[&stale]);
+        }
+        let mut current = Some(hash.clone());
+        while let Some(hash) = current {
+            let stored = match self.blocks.get(&hash) {
+                Some(stored) => stored,
+                None => break,
+            };
+            let number = stored.block.header.number;
+            if self.canonical.get(&number) == Some(&hash) {
+                break;
+            }
+            if let Some(replaced) = self.canonical.insert(number, hash.clone()) {
+                unindex(&mut self.transactions, &self.blocks[&replaced]);
+            }
+            for (index, transaction) in stored.block.transactions.iter().enumerate() {
+                let _previous = self
+                    .transactions
+                    .insert(transaction.rlp_hash(), (hash.clone(), index));
+            }
+            current = if number == 0 {
+                None
+            } else {
+                Some(stored.block.header.parent_hash.clone())
+            };
+        }
+        self.head = Some(hash.clone());
+    }
+}
+
+fn unindex(transactions: &mut HashMap<U256, (U256, usize)>, stored: &StoredBlock) {
+    for transaction in &stored.block.transactions {
+        let hash = transaction.rlp_hash();
+        if transactions.get(&hash).map(|(block, _)| block) == Some(&stored.hash) {
+            let _removed = transactions.remove(&hash);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        chain::types::{BlockHeader, Transaction},
+        test::prelude::assert_eq,
+    };
+
+    fn block(parent: &U256, number: u64, timestamp: u64, transactions: usize) -> Block {
+        Block {
+            header:       BlockHeader {
+                parent_hash: parent.clone(),
+                number,
+                timestamp,
+                ..BlockHeader::default()
+            },
+            transactions: vec![Transaction::default(); transactions],
+            ommers:       Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_reorg() {
+        let mut store = BlockStore::default();
+        let genesis = store.insert(block(&U256::zero(), 0, 0, 0), Vec::new());
+        let first = store.insert(
+            block(&genesis, 1, 1, 1),
+            vec![TransactionReceipt::default()],
+        );
+        let tx_hash = Transaction::default().rlp_hash();
+        assert_eq!(store.resolve(BlockNumber::Latest).unwrap().hash, first);
+        assert_eq!(store.resolve(BlockNumber::Earliest).unwrap().hash, genesis);
+        assert_eq!(store.transaction(&tx_hash).unwrap().0.hash, first);
+        assert!(store.receipt(&tx_hash).is_some());
+
+        // A sibling at the same height replaces the head
+        let sibling = store.insert(block(&genesis, 1, 2, 0), Vec::new());
+        let second = store.insert(block(&sibling, 2, 3, 0), Vec::new());
+        assert_eq!(store.resolve(BlockNumber::Number(1)).unwrap().hash, sibling);
+        assert_eq!(store.resolve(BlockNumber::Pending).unwrap().hash, second);
+        assert!(store.transaction(&tx_hash).is_none());
+        assert_eq!(store.by_hash(&first).unwrap().block.header.timestamp, 1);
+
+        // Lower blocks do not become the head
+        let _stale = store.insert(block(&genesis, 1, 4, 0), Vec::new());
+        assert_eq!(store.head().unwrap().hash, second);
+        assert!(store.resolve(BlockNumber::Number(3)).is_none());
+    }
+}
@@ -1,75 +1,825 @@
 //! Cached chain
 //!
 //! Takes a given read-only chain implementation and implements and in-memory
-//! cache on top of it. Every read is cached and never discarded.
+//! cache on top of it. Successful reads are cached until evicted by the
+//! [`CachePolicy`]. Failed reads are not cached and will be retried on the
+//! next read. When the base moves to a new block, cached entries can be
+//! updated with a [`StateDiff`] instead of starting over.
+//!
+//! The cache is sharded and can be shared between many concurrent executions,
+//! for example as `Fork<Arc<Cache<_>>>` overlays. Concurrent reads of the same
+//! key wait on a single upstream fetch.
+
+use super::{types::Address, Account, BlockInfo, ChainState, Error, StateDiff, StateSet};
+use crate::{prelude::*, utils::keccak256};
+use async_trait::async_trait;
+use bytesize::ByteSize;
+use std::{
+    collections::{hash_map::RandomState, BTreeMap, HashMap},
+    fmt,
+    hash::{BuildHasher, Hash, Hasher},
+    mem::size_of,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+};
+use tokio::sync::OnceCell;
+
+const SHARDS: usize = 64;
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+enum Key {
+    Block,
+    Nonce(U256),
+    Balance(U256),
+    Code(U256),
+    CodeHash(U256),
+    Storage(U256, U256),
+}
+
+#[derive(Clone, Debug)]
+enum Value {
+    Block(BlockInfo),
+    Nonce(usize),
+    Balance(U256),
+    Code(Vec<u8>),
+    CodeHash(U256),
+    Storage(U256),
+}
+
+/// Unwrap a `Value` of the variant that matches its `Key`
+macro_rules! expect_value {
+    ($value:expr, $variant:ident) => {
+        match $value {
+            Value::$variant(value) => value,
+            _ => unreachable!("Cache entry does not match its key."),
+        }
+    };
+}
+
+/// Entries are created empty and filled by the first successful read
+type Entry = Arc<OnceCell<Value>>;
 
-use super::{BlockInfo, ChainState, StateSet};
-use crate::prelude::*;
-use std::cell::{RefCell, RefMut};
+/// Limits on the memory used by a [`Cache`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CachePolicy {
+    /// Approximate memory budget. Least recently used entries are evicted
+    /// when it is exceeded. Unbounded if `None`.
+    pub max_bytes: Option<ByteSize>,
+}
+
+/// Counters for a [`Cache`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub entries:   usize,
+    pub bytes:     ByteSize,
+    pub hits:      u64,
+    pub misses:    u64,
+    pub evictions: u64,
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} entries using {}, {} hits, {} misses, {} evictions",
+            self.entries, self.bytes, self.hits, self.misses, self.evictions
+        )
+    }
+}
+
+#[derive(Debug)]
+struct Slot {
+    entry:  Entry,
+    /// Last access, the key in `Shard::order`
+    tick:   u64,
+    /// Zero until the entry is filled
+    weight: u64,
+}
+
+#[derive(Debug, Default)]
+struct Shard {
+    slots: HashMap<Key, Slot>,
+    order: BTreeMap<u64, Key>,
+    tick:  u64,
+    bytes: u64,
+}
+
+impl Shard {
+    /// Get or create the entry for `key` and mark it most recently used.
+    /// Also returns whether the entry was already filled.
+    fn touch(&mut self, key: Key) -> (Entry, bool) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(slot) = self.slots.get_mut(&key) {
+            let _previous = self.order.remove(&slot.tick);
+            slot.tick = tick;
+            let _previous = self.order.insert(tick, key);
+            return (slot.entry.clone(), slot.entry.initialized());
+        }
+        let entry = Entry::default();
+        let _previous = self.order.insert(tick, key.clone());
+        let _previous = self.slots.insert(key, Slot {
+            entry: entry.clone(),
+            tick,
+            weight: 0,
+        });
+        (entry, false)
+    }
+
+    /// Account for a newly filled entry
+    fn fill(&mut self, key: &Key, entry: &Entry) {
+        if let Some(slot) = self.slots.get_mut(key) {
+            if let (0, Some(value), true) =
+                (slot.weight, entry.get(), Arc::ptr_eq(&slot.entry, entry))
+            {
+                slot.weight = weight(value);
+                self.bytes += slot.weight;
+            }
+        }
+    }
+
+    /// Evict least recently used entries until at least `excess` bytes are
+    /// freed or the shard is empty. Returns the number of evictions.
+    fn evict(&mut self, excess: u64) -> u64 {
+        let target = self.bytes.saturating_sub(excess);
+        let mut evictions = 0;
+        while self.bytes > target {
+            let (tick, key) = match self.order.iter().next() {
+                Some((tick, key)) => (*tick, key.clone()),
+                None => break,
+            };
+            let _key = self.order.remove(&tick);
+            if let Some(slot) = self.slots.remove(&key) {
+                self.bytes -= slot.weight;
+            }
+            evictions += 1;
+        }
+        evictions
+    }
+
+    /// Remove `entry` if it is still empty, for example after a failed read
+    fn discard(&mut self, key: &Key, entry: &Entry) {
+        let empty = match self.slots.get(key) {
+            Some(slot) => Arc::ptr_eq(&slot.entry, entry) && !slot.entry.initialized(),
+            None => false,
+        };
+        if empty {
+            if let Some(slot) = self.slots.remove(key) {
+                let _key = self.order.remove(&slot.tick);
+            }
+        }
+    }
+
+    /// Replace the value of a cached entry, entries not cached are left alone
+    fn replace(&mut self, key: &Key, value: Value) {
+        if let Some(slot) = self.slots.get_mut(key) {
+            let weight = weight(&value);
+            self.bytes = self.bytes - slot.weight + weight;
+            slot.entry = Arc::new(OnceCell::new_with(Some(value)));
+            slot.weight = weight;
+        }
+    }
+
+    /// Remove all entries matching `predicate`
+    fn remove_where(&mut self, predicate: impl Fn(&Key) -> bool) {
+        let keys = self
+            .slots
+            .keys()
+            .filter(|key| predicate(key))
+            .cloned()
+            .collect::<Vec<_>>();
+        for key in keys {
+            if let Some(slot) = self.slots.remove(&key) {
+                let _key = self.order.remove(&slot.tick);
+                self.bytes -= slot.weight;
+            }
+        }
+    }
+}
+
+/// Approximate memory used by a filled entry, including its key twice
+fn weight(value: &Value) -> u64 {
+    let heap = match value {
+        Value::Code(code) => code.len(),
+        _ => 0,
+    };
+    (2 * size_of::<Key>() + size_of::<Slot>() + size_of::<Value>() + heap) as u64
+}
 
-/// # Panics
-///
-/// Panics if reads are called re-entrantly.
 #[derive(Debug)]
 pub struct Cache<Base: ChainState> {
-    base:  Base,
-    state: RefCell<StateSet>,
+    base:      Base,
+    policy:    CachePolicy,
+    hasher:    RandomState,
+    shards:    Vec<Mutex<Shard>>,
+    /// Sum of the shard sizes, the policy applies to all shards together
+    bytes:     AtomicU64,
+    hits:      AtomicU64,
+    misses:    AtomicU64,
+    evictions: AtomicU64,
 }
 
 impl<Base: ChainState> Cache<Base> {
-    fn state_set_mut(&self) -> RefMut<StateSet> {
-        self.state
-            .try_borrow_mut()
-            .expect("Can not re-enter Cache.")
+    pub fn with_policy(base: Base, policy: CachePolicy) -> Self {
+        Self {
+            base,
+            policy,
+            hasher: RandomState::new(),
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            bytes: AtomicU64::default(),
+            hits: AtomicU64::default(),
+            misses: AtomicU64::default(),
+            evictions: AtomicU64::default(),
+        }
+    }
+
+    pub fn inner(&self) -> &Base {
+        &self.base
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let entries = self
+            .shards
+            .iter()
+            .map(|shard| shard.lock().expect("Cache lock poisoned.").slots.len())
+            .sum();
+        CacheStats {
+            entries,
+            bytes: ByteSize(self.bytes.load(Ordering::Relaxed)),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+        }
+    }
+
+    fn shard_index(&self, key: &Key) -> usize {
+        let mut hasher = self.hasher.build_hasher();
+        key.hash(&mut hasher);
+        (hasher.finish() as usize) % SHARDS
+    }
+
+    /// The shard lock is never held across an await point.
+    fn shard(&self, key: &Key) -> MutexGuard<Shard> {
+        self.shards[self.shard_index(key)]
+            .lock()
+            .expect("Cache lock poisoned.")
+    }
+
+    /// Run `f` on a shard and add the change in its size to the total
+    fn resize_shard<T>(&self, shard: &Mutex<Shard>, f: impl FnOnce(&mut Shard) -> T) -> T {
+        let mut shard = shard.lock().expect("Cache lock poisoned.");
+        let before = shard.bytes;
+        let result = f(&mut shard);
+        let after = shard.bytes;
+        // Update the total under the lock so a concurrent eviction from this
+        // shard can not subtract before the matching addition.
+        let _previous = if after >= before {
+            self.bytes.fetch_add(after - before, Ordering::Relaxed)
+        } else {
+            self.bytes.fetch_sub(before - after, Ordering::Relaxed)
+        };
+        result
+    }
+
+    /// Get the entry for a key, counting hits and misses
+    fn entry(&self, key: &Key) -> (Entry, bool) {
+        let (entry, hit) = self.shard(key).touch(key.clone());
+        let counter = if hit { &self.hits } else { &self.misses };
+        let _previous = counter.fetch_add(1, Ordering::Relaxed);
+        (entry, hit)
+    }
+
+    /// Account for a filled entry and apply the policy
+    ///
+    /// Evicts from the shard of `key` first, then from the following shards
+    /// until the total is within budget.
+    fn fill(&self, key: &Key, entry: &Entry) {
+        let index = self.shard_index(key);
+        self.resize_shard(&self.shards[index], |shard| shard.fill(key, entry));
+        let max_bytes = match self.policy.max_bytes {
+            Some(max_bytes) => max_bytes.as_u64(),
+            None => return,
+        };
+        for offset in 0..SHARDS {
+            let bytes = self.bytes.load(Ordering::Relaxed);
+            if bytes <= max_bytes {
+                break;
+            }
+            let shard = &self.shards[(index + offset) % SHARDS];
+            let evictions = self.resize_shard(shard, |shard| shard.evict(bytes - max_bytes));
+            if evictions > 0 {
+                let _previous = self.evictions.fetch_add(evictions, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Read a value, with at most one `fetch` for the key in flight
+    async fn get<F>(&self, key: Key, fetch: F) -> Result<Value, Error>
+    where
+        F: Future<Output = Result<Value, Error>>,
+    {
+        let (entry, hit) = self.entry(&key);
+        let value = match entry.get_or_try_init(|| fetch).await {
+            Ok(value) => value.clone(),
+            Err(error) => {
+                self.shard(&key).discard(&key, &entry);
+                return Err(error);
+            }
+        };
+        if !hit {
+            self.fill(&key, &entry);
+        }
+        Ok(value)
+    }
+
+    /// All values currently cached
+    pub fn to_state_set(&self) -> StateSet {
+        let mut result = StateSet::default();
+        for shard in &self.shards {
+            let shard = shard.lock().expect("Cache lock poisoned.");
+            for (key, slot) in &shard.slots {
+                let value = match slot.entry.get() {
+                    Some(value) => value.clone(),
+                    None => continue,
+                };
+                match key.clone() {
+                    Key::Block => result.block = Some(expect_value!(value, Block)),
+                    Key::Nonce(address) => {
+                        let _previous = result.nonces.insert(address, expect_value!(value, Nonce));
+                    }
+                    Key::Balance(address) => {
+                        let _previous = result
+                            .balances
+                            .insert(address, expect_value!(value, Balance));
+                    }
+                    Key::Code(address) => {
+                        let _previous = result.codes.insert(address, expect_value!(value, Code));
+                    }
+                    // Derived from the code
+                    Key::CodeHash(_) => {}
+                    Key::Storage(address, slot) => {
+                        let _previous = result
+                            .storages
+                            .insert((address, slot), expect_value!(value, Storage));
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// Add previously read values, for example from [`to_state_set`]
+    ///
+    /// [`to_state_set`]: Cache::to_state_set
+    pub fn insert_state_set(&self, state: StateSet) {
+        let StateSet {
+            block,
+            nonces,
+            balances,
+            codes,
+            storages,
+        } = state;
+        let entries = block
+            .map(|block| (Key::Block, Value::Block(block)))
+            .into_iter()
+            .chain(
+                nonces
+                    .into_iter()
+                    .map(|(address, nonce)| (Key::Nonce(address), Value::Nonce(nonce))),
+            )
+            .chain(
+                balances
+                    .into_iter()
+                    .map(|(address, balance)| (Key::Balance(address), Value::Balance(balance))),
+            )
+            .chain(
+                codes
+                    .into_iter()
+                    .map(|(address, code)| (Key::Code(address), Value::Code(code))),
+            )
+            .chain(storages.into_iter().map(|((address, slot), value)| {
+                (Key::Storage(address, slot), Value::Storage(value))
+            }));
+        for (key, value) in entries {
+            let (entry, _filled) = self.shard(&key).touch(key.clone());
+            // Values already read take precedence
+            let _result = entry.set(value);
+            self.fill(&key, &entry);
+        }
+    }
+
+    /// Write the after values of `diff` to the entries already cached
+    ///
+    /// Reads in flight may still fill their entries with values from before
+    /// the diff.
+    pub fn update(&self, diff: &StateDiff) {
+        let values = diff
+            .nonces
+            .iter()
+            .map(|(address, delta)| (Key::Nonce(address.clone()), Value::Nonce(delta.after)))
+            .chain(diff.balances.iter().map(|(address, delta)| {
+                (
+                    Key::Balance(address.clone()),
+                    Value::Balance(delta.after.clone()),
+                )
+            }))
+            .chain(diff.codes.iter().flat_map(|(address, delta)| {
+                vec![
+                    (Key::Code(address.clone()), Value::Code(delta.after.clone())),
+                    (
+                        Key::CodeHash(address.clone()),
+                        Value::CodeHash(keccak256(&delta.after)),
+                    ),
+                ]
+            }))
+            .chain(diff.storages.iter().flat_map(|(address, slots)| {
+                slots.iter().map(move |(slot, delta)| {
+                    (
+                        Key::Storage(address.clone(), slot.clone()),
+                        Value::Storage(delta.after.clone()),
+                    )
+                })
+            }));
+        for (key, value) in values {
+            let shard = &self.shards[self.shard_index(&key)];
+            self.resize_shard(shard, |shard| shard.replace(&key, value));
+        }
+    }
+
+    /// Replace the cached block constants
+    pub fn set_block(&self, block: BlockInfo) {
+        let key = Key::Block;
+        let (entry, filled) = self.shard(&key).touch(key.clone());
+        if filled {
+            let shard = &self.shards[self.shard_index(&key)];
+            self.resize_shard(shard, |shard| shard.replace(&key, Value::Block(block)));
+        } else {
+            let _result = entry.set(Value::Block(block));
+            self.fill(&key, &entry);
+        }
+    }
+
+    /// Drop all cached storage of `address`, for example when it is deleted
+    pub fn invalidate_storage(&self, address: &U256) {
+        for shard in &self.shards {
+            self.resize_shard(shard, |shard| {
+                shard.remove_where(|key| matches!(key, Key::Storage(owner, _) if owner == address));
+            });
+        }
+    }
+
+    /// Drop all cached entries
+    pub fn clear(&self) {
+        for shard in &self.shards {
+            self.resize_shard(shard, |shard| shard.remove_where(|_| true));
+        }
+    }
+
+    /// Storage slots currently cached, to prefetch on a later execution
+    pub fn storage_keys(&self) -> Vec<(Address, U256)> {
+        let mut result = Vec::new();
+        for shard in &self.shards {
+            let shard = shard.lock().expect("Cache lock poisoned.");
+            for (key, slot) in &shard.slots {
+                if let (Key::Storage(address, slot), true) = (key, slot.entry.initialized()) {
+                    result.push((Address::from_word(address), slot.clone()));
+                }
+            }
+        }
+        result
     }
 }
 
 impl<Base: ChainState> From<Base> for Cache<Base> {
     fn from(base: Base) -> Self {
-        Self {
-            base,
-            state: RefCell::new(StateSet::default()),
-        }
+        Self::with_policy(base, CachePolicy::default())
     }
 }
 
+#[async_trait]
 impl<Base: ChainState> ChainState for Cache<Base> {
-    fn block(&self) -> BlockInfo {
-        self.state_set_mut()
-            .block
-            .get_or_insert_with(|| self.base.block())
-            .clone()
+    async fn block(&self) -> Result<BlockInfo, Error> {
+        let fetch = self.base.block().map_ok(Value::Block);
+        Ok(expect_value!(self.get(Key::Block, fetch).await?, Block))
     }
 
-    fn nonce(&self, address: &U256) -> usize {
-        *self
-            .state_set_mut()
-            .nonces
-            .entry(address.clone())
-            .or_insert_with(|| self.base.nonce(address))
+    async fn nonce(&self, address: &Address) -> Result<usize, Error> {
+        let fetch = self.base.nonce(address).map_ok(Value::Nonce);
+        let key = Key::Nonce(address.to_word());
+        Ok(expect_value!(self.get(key, fetch).await?, Nonce))
+    }
+
+    async fn balance(&self, address: &Address) -> Result<U256, Error> {
+        let fetch = self.base.balance(address).map_ok(Value::Balance);
+        let key = Key::Balance(address.to_word());
+        Ok(expect_value!(self.get(key, fetch).await?, Balance))
+    }
+
+    async fn code(&self, address: &Address) -> Result<Vec<u8>, Error> {
+        let fetch = self.base.code(address).map_ok(Value::Code);
+        let key = Key::Code(address.to_word());
+        Ok(expect_value!(self.get(key, fetch).await?, Code))
+    }
+
+    async fn code_hash(&self, address: &Address) -> Result<U256, Error> {
+        let fetch = self.base.code_hash(address).map_ok(Value::CodeHash);
+        let key = Key::CodeHash(address.to_word());
+        Ok(expect_value!(self.get(key, fetch).await?, CodeHash))
+    }
+
+    /// Fills the nonce, balance and code hash entries from a single read of
+    /// the base. Unlike single reads, this is not deduplicated against reads
+    /// already in flight.
+    async fn account(&self, address: &Address) -> Result<Account, Error> {
+        let word = address.to_word();
+        let keys = [
+            Key::Nonce(word.clone()),
+            Key::Balance(word.clone()),
+            Key::CodeHash(word),
+        ];
+        let entries = keys.iter().map(|key| self.entry(key).0).collect::<Vec<_>>();
+        if let (
+            Some(Value::Nonce(nonce)),
+            Some(Value::Balance(balance)),
+            Some(Value::CodeHash(code_hash)),
+        ) = (entries[0].get(), entries[1].get(), entries[2].get())
+        {
+            return Ok(Account {
+                nonce:     *nonce,
+                balance:   balance.clone(),
+                code_hash: code_hash.clone(),
+            });
+        }
+        let account = match self.base.account(address).await {
+            Ok(account) => account,
+            Err(error) => {
+                for (key, entry) in keys.iter().zip(&entries) {
+                    self.shard(key).discard(key, entry);
+                }
+                return Err(error);
+            }
+        };
+        let values = vec![
+            Value::Nonce(account.nonce),
+            Value::Balance(account.balance.clone()),
+            Value::CodeHash(account.code_hash.clone()),
+        ];
+        for ((key, entry), value) in keys.iter().zip(&entries).zip(values) {
+            // Ignore failure, the entry is already filled
+            let _result = entry.set(value);
+            self.fill(key, entry);
+        }
+        Ok(account)
+    }
+
+    async fn storage(&self, address: &Address, slot: &U256) -> Result<U256, Error> {
+        let fetch = self.base.storage(address, slot).map_ok(Value::Storage);
+        let key = Key::Storage(address.to_word(), slot.clone());
+        Ok(expect_value!(self.get(key, fetch).await?, Storage))
+    }
+
+    /// Misses are fetched in one batch. Unlike single reads, batches are not
+    /// deduplicated against reads already in flight.
+    async fn storage_batch(&self, keys: &[(Address, U256)]) -> Result<Vec<U256>, Error> {
+        let cache_keys = keys
+            .iter()
+            .map(|(address, slot)| Key::Storage(address.to_word(), slot.clone()))
+            .collect::<Vec<_>>();
+        let entries = cache_keys
+            .iter()
+            .map(|key| self.entry(key).0)
+            .collect::<Vec<_>>();
+        let mut values = entries
+            .iter()
+            .map(|entry| {
+                entry
+                    .get()
+                    .map(|value| expect_value!(value.clone(), Storage))
+            })
+            .collect::<Vec<_>>();
+
+        // Fetch misses in one batch
+        let misses = (0..keys.len())
+            .filter(|index| values[*index].is_none())
+            .collect::<Vec<_>>();
+        let miss_keys = misses
+            .iter()
+            .map(|index| keys[*index].clone())
+            .collect::<Vec<_>>();
+        let fetched = match self.base.storage_batch(&miss_keys).await {
+            Ok(fetched) => fetched,
+            Err(error) => {
+                for index in misses {
+                    self.shard(&cache_keys[index])
+                        .discard(&cache_keys[index], &entries[index]);
+                }
+                return Err(error);
+            }
+        };
+        for (index, value) in misses.into_iter().zip(fetched) {
+            // Ignore failure, the entry was filled concurrently
+            let _result = entries[index].set(Value::Storage(value.clone()));
+            self.fill(&cache_keys[index], &entries[index]);
+            values[index] = Some(value);
+        }
+        Ok(values
+            .into_iter()
+            .map(|value| value.expect("One value per key"))
+            .collect())
+    }
+
+    async fn prefetch(&self, keys: &[(Address, U256)]) -> Result<(), Error> {
+        let _values = self.storage_batch(keys).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        chain::{Delta, Empty, Fork},
+        test::prelude::assert_eq,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Fails every other storage read
+    #[derive(Default)]
+    struct Flaky {
+        reads: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl ChainState for Flaky {
+        async fn block(&self) -> Result<BlockInfo, Error> {
+            Empty.block().await
+        }
+
+        async fn nonce(&self, address: &Address) -> Result<usize, Error> {
+            Empty.nonce(address).await
+        }
+
+        async fn balance(&self, address: &Address) -> Result<U256, Error> {
+            Empty.balance(address).await
+        }
+
+        async fn code(&self, address: &Address) -> Result<Vec<u8>, Error> {
+            Empty.code(address).await
+        }
+
+        async fn storage(&self, _address: &Address, _slot: &U256) -> Result<U256, Error> {
+            // Give concurrent reads a chance to run
+            tokio::task::yield_now().await;
+            if self.reads.fetch_add(1, Ordering::SeqCst) % 2 == 0 {
+                Err(Error::Upstream("flaky".into()))
+            } else {
+                Ok(U256::one())
+            }
+        }
     }
 
-    fn balance(&self, address: &U256) -> U256 {
-        self.state_set_mut()
-            .balances
-            .entry(address.clone())
-            .or_insert_with(|| self.base.balance(address))
-            .clone()
+    #[tokio::test]
+    async fn test_errors_are_not_cached() {
+        let cache = Cache::from(Flaky::default());
+        let (address, slot) = (Address::default(), U256::zero());
+        assert_eq!(
+            cache.storage(&address, &slot).await,
+            Err(Error::Upstream("flaky".into()))
+        );
+        assert_eq!(cache.storage(&address, &slot).await, Ok(U256::one()));
+        assert_eq!(cache.storage(&address, &slot).await, Ok(U256::one()));
+        assert_eq!(cache.base.reads.load(Ordering::SeqCst), 2);
     }
 
-    fn code(&self, address: &U256) -> Vec<u8> {
-        self.state_set_mut()
-            .codes
-            .entry(address.clone())
-            .or_insert_with(|| self.base.code(address))
-            .clone()
+    #[tokio::test]
+    async fn test_errors_leave_no_entry() {
+        let cache = Cache::from(Flaky::default());
+        let keys = vec![
+            (Address::default(), U256::zero()),
+            (Address::from_word(&U256::one()), U256::zero()),
+        ];
+        assert!(cache.storage(&keys[0].0, &keys[0].1).await.is_err());
+        assert_eq!(cache.stats().entries, 0);
+        assert_eq!(cache.storage(&keys[0].0, &keys[0].1).await, Ok(U256::one()));
+        assert!(cache.storage_batch(&keys).await.is_err());
+        assert_eq!(cache.stats().entries, 1);
+        assert_eq!(cache.storage_keys(), vec![keys[0].clone()]);
     }
 
-    fn storage(&self, address: &U256, slot: &U256) -> U256 {
-        self.state_set_mut()
-            .storages
-            .entry((address.clone(), slot.clone()))
-            .or_insert_with(|| self.base.storage(address, slot))
-            .clone()
+    #[tokio::test]
+    async fn test_concurrent_reads_are_deduplicated() {
+        let cache = Cache::from(Flaky::default());
+        let (address, slot) = (Address::default(), U256::zero());
+        assert!(cache.storage(&address, &slot).await.is_err());
+        let reads = (0..8).map(|_| cache.storage(&address, &slot));
+        for result in future::join_all(reads).await {
+            assert_eq!(result, Ok(U256::one()));
+        }
+        assert_eq!(cache.base.reads.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_eviction() {
+        let (address, slot) = (Address::default(), U256::zero());
+
+        // Unbounded
+        let cache = Cache::from(Empty);
+        cache.storage(&address, &slot).await.unwrap();
+        cache.storage(&address, &slot).await.unwrap();
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (1, 1, 0));
+        assert_eq!(stats.entries, 1);
+
+        // No budget evicts every entry once filled
+        let cache = Cache::with_policy(Empty, CachePolicy {
+            max_bytes: Some(ByteSize::b(0)),
+        });
+        cache.storage(&address, &slot).await.unwrap();
+        cache.storage(&address, &slot).await.unwrap();
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (0, 2, 2));
+        assert_eq!((stats.entries, stats.bytes), (0, ByteSize::b(0)));
+
+        // The budget is shared by all shards
+        let entry_bytes = weight(&Value::Storage(U256::zero()));
+        let cache = Cache::with_policy(Empty, CachePolicy {
+            max_bytes: Some(ByteSize::b(3 * entry_bytes)),
+        });
+        for slot in 0..8_u64 {
+            cache.storage(&address, &slot.into()).await.unwrap();
+        }
+        let stats = cache.stats();
+        assert_eq!(stats.evictions, 5);
+        assert_eq!(
+            (stats.entries, stats.bytes),
+            (3, ByteSize::b(3 * entry_bytes))
+        );
+    }
+
+    #[test]
+    fn test_least_recently_used() {
+        let mut shard = Shard::default();
+        let keys = (0..3_u64).map(|n| Key::Nonce(n.into())).collect::<Vec<_>>();
+        for key in &keys {
+            let (entry, _filled) = shard.touch(key.clone());
+            entry.set(Value::Nonce(0)).unwrap();
+            shard.fill(key, &entry);
+        }
+        let _entry = shard.touch(keys[0].clone());
+
+        // Evicts the least recently used key
+        let _entry = shard.touch(keys[2].clone());
+        assert_eq!(shard.evict(1), 1);
+        assert!(!shard.slots.contains_key(&keys[1]));
+        assert!(shard.slots.contains_key(&keys[0]));
+    }
+
+    #[tokio::test]
+    async fn test_prefetch() {
+        let cache = Cache::from(Fork::from(Empty));
+        let keys = (0..3_u64)
+            .map(|slot| (Address::from_word(&U256::one()), U256::from(slot)))
+            .collect::<Vec<_>>();
+        cache.prefetch(&keys[..2]).await.unwrap();
+        assert_eq!(cache.storage_keys().len(), 2);
+        assert_eq!(cache.storage_batch(&keys).await, Ok(vec![U256::zero(); 3]));
+        assert_eq!(cache.storage_keys().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_update() {
+        let (word, slot) = (U256::one(), U256::zero());
+        let address = Address::from_word(&word);
+        let cache = Cache::from(Empty);
+        cache.storage(&address, &slot).await.unwrap();
+        let mut diff = StateDiff::default();
+        let _previous = diff.balances.insert(word.clone(), Delta {
+            before: U256::zero(),
+            after:  U256::from(3_u64),
+        });
+        let _previous =
+            diff.storages
+                .entry(word.clone())
+                .or_default()
+                .insert(slot.clone(), Delta {
+                    before: U256::zero(),
+                    after:  U256::from(5_u64),
+                });
+        cache.update(&diff);
+        cache.set_block(BlockInfo {
+            number:    7,
+            timestamp: 0,
+        });
+
+        // Only entries already cached are updated
+        assert_eq!(cache.storage(&address, &slot).await, Ok(U256::from(5_u64)));
+        assert_eq!(cache.balance(&address).await, Ok(U256::zero()));
+        assert_eq!(cache.block().await.map(|block| block.number), Ok(7));
+
+        cache.invalidate_storage(&word);
+        assert_eq!(cache.storage(&address, &slot).await, Ok(U256::zero()));
+        assert_eq!(cache.stats().entries, 3);
     }
 }
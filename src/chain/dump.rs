@@ -0,0 +1,311 @@
+//! Load state from geth dumps and genesis allocations
+//!
+//! Reads the output of `geth dump` (one account per line), of
+//! `debug_dumpBlock` (accounts in an `accounts` object), genesis files
+//! (accounts in an `alloc` object) and bare allocation objects keyed by
+//! address. Accounts are written to the chain as they are parsed, without
+//! buffering the file or the parsed accounts. The resulting chain does hold
+//! the full state in memory.
+
+use super::{types::Address, Empty, Fork, WriteableChainState};
+use crate::{prelude::*, serde::bytes};
+use serde::de::{self, DeserializeSeed, IgnoredAny, MapAccess, Visitor};
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+};
+
+/// Largest value that can be multiplied by ten without overflow
+const MAX_DIV_10: U256 = u256h!("1999999999999999999999999999999999999999999999999999999999999999");
+
+/// Load a dump or genesis file into a new chain
+pub fn load_file(path: &Path) -> AnyResult<Fork<Empty>> {
+    let file = File::open(path).with_context(|| format!("Opening {}", path.display()))?;
+    let mut chain = Fork::from(Empty);
+    let accounts = load(BufReader::new(file), &mut chain)
+        .with_context(|| format!("Loading state from {}", path.display()))?;
+    info!("Loaded {} accounts from {}", accounts, path.display());
+    Ok(chain)
+}
+
+/// Write all accounts in `reader` to `chain` and return their number
+pub fn load<R: Read, C: WriteableChainState + ?Sized>(
+    reader: R,
+    chain: &mut C,
+) -> AnyResult<usize> {
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    let mut accounts = 0;
+    // `geth dump` writes one JSON object per line
+    while deserializer.end().is_err() {
+        accounts += Document { chain: &mut *chain }.deserialize(&mut deserializer)?;
+    }
+    Ok(accounts)
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct Account {
+    balance: Quantity,
+    nonce:   Quantity,
+    #[serde(with = "bytes")]
+    code:    Vec<u8>,
+    storage: HashMap<Word, Word>,
+}
+
+impl Account {
+    fn write<C: WriteableChainState + ?Sized>(self, chain: &mut C, address: &Address) {
+        chain.set_nonce(address, self.nonce.0.as_usize());
+        chain.set_balance(address, &self.balance.0);
+        if !self.code.is_empty() {
+            chain.set_code(address, &self.code);
+        }
+        for (slot, value) in self.storage {
+            chain.set_storage(address, &slot.0, &value.0);
+        }
+    }
+}
+
+/// A top level JSON object
+struct Document<'a, C: ?Sized> {
+    chain: &'a mut C,
+}
+
+impl<'de, 'a, C: WriteableChainState + ?Sized> DeserializeSeed<'de> for Document<'a, C> {
+    type Value = usize;
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<usize, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, 'a, C: WriteableChainState + ?Sized> Visitor<'de> for Document<'a, C> {
+    type Value = usize;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a state dump, genesis file or allocation object")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<usize, A::Error> {
+        let mut accounts = 0;
+        // Fields of a single account line from `geth dump`
+        let mut address = None;
+        let mut account = Account::default();
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "accounts" | "alloc" => {
+                    accounts += map.next_value_seed(Accounts {
+                        chain: &mut *self.chain,
+                    })?;
+                }
+                "address" => address = Some(map.next_value::<Address>()?),
+                "balance" => account.balance = map.next_value()?,
+                "nonce" => account.nonce = map.next_value()?,
+                "code" => account.code = map.next_value::<Bytes>()?.0,
+                "storage" => account.storage = map.next_value()?,
+                other => {
+                    match other.parse::<Address>() {
+                        Ok(address) => {
+                            map.next_value::<Account>()?
+                                .write(&mut *self.chain, &address);
+                            accounts += 1;
+                        }
+                        Err(_) => {
+                            let _ignored = map.next_value::<IgnoredAny>()?;
+                        }
+                    }
+                }
+            }
+        }
+        if let Some(address) = address {
+            account.write(&mut *self.chain, &address);
+            accounts += 1;
+        }
+        Ok(accounts)
+    }
+}
+
+/// An object of accounts keyed by address
+struct Accounts<'a, C: ?Sized> {
+    chain: &'a mut C,
+}
+
+impl<'de, 'a, C: WriteableChainState + ?Sized> DeserializeSeed<'de> for Accounts<'a, C> {
+    type Value = usize;
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<usize, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, 'a, C: WriteableChainState + ?Sized> Visitor<'de> for Accounts<'a, C> {
+    type Value = usize;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an object of accounts keyed by address")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<usize, A::Error> {
+        let mut accounts = 0;
+        while let Some(key) = map.next_key::<String>()? {
+            let address = key.parse::<Address>().map_err(de::Error::custom)?;
+            map.next_value::<Account>()?
+                .write(&mut *self.chain, &address);
+            accounts += 1;
+        }
+        Ok(accounts)
+    }
+}
+
+#[derive(Deserialize)]
+struct Bytes(#[serde(with = "bytes")] Vec<u8>);
+
+/// A number as JSON number, hexadecimal string with prefix or decimal string
+///
+/// Geth dumps balances as decimal, genesis files as hexadecimal.
+#[derive(Default)]
+struct Quantity(U256);
+
+impl<'de> Deserialize<'de> for Quantity {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct QuantityVisitor;
+        impl<'de> Visitor<'de> for QuantityVisitor {
+            type Value = Quantity;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a number or a decimal or hexadecimal string")
+            }
+
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<Quantity, E> {
+                Ok(Quantity(U256::from(value)))
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Quantity, E> {
+                let parsed = match value.strip_prefix("0x") {
+                    Some(hex) => parse_hex(hex),
+                    None => parse_decimal(value),
+                };
+                parsed
+                    .map(Quantity)
+                    .ok_or_else(|| de::Error::invalid_value(de::Unexpected::Str(value), &self))
+            }
+        }
+        deserializer.deserialize_any(QuantityVisitor)
+    }
+}
+
+/// A hexadecimal string with optional prefix, as geth dumps storage
+#[derive(PartialEq, Eq, Hash)]
+struct Word(U256);
+
+impl<'de> Deserialize<'de> for Word {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        parse_hex(value.strip_prefix("0x").unwrap_or(&value))
+            .map(Word)
+            .ok_or_else(|| de::Error::custom(format!("invalid storage word {}", value)))
+    }
+}
+
+fn parse_hex(hex: &str) -> Option<U256> {
+    if hex.len() > 64 || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    if hex.is_empty() {
+        return Some(U256::zero());
+    }
+    Some(U256::from_hex_str(hex))
+}
+
+fn parse_decimal(decimal: &str) -> Option<U256> {
+    if decimal.is_empty() {
+        return None;
+    }
+    decimal.bytes().try_fold(U256::zero(), |result, byte| {
+        if !byte.is_ascii_digit() {
+            return None;
+        }
+        let digit = byte - b'0';
+        // The maximum value is `10 * MAX_DIV_10 + 5`
+        if result > MAX_DIV_10 || (result == MAX_DIV_10 && digit > 5) {
+            return None;
+        }
+        Some(result * U256::from(10_u64) + U256::from(u64::from(digit)))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{chain::ChainState, test::prelude::assert_eq};
+
+    fn address() -> Address {
+        Address::from(hex!("a94f5374fce5edbc8e2a8697c15331677e6ebf0b"))
+    }
+
+    fn load_str(json: &str) -> Fork<Empty> {
+        let mut chain = Fork::from(Empty);
+        assert_eq!(load(json.as_bytes(), &mut chain).unwrap(), 1);
+        chain
+    }
+
+    #[tokio::test]
+    async fn test_geth_dump_lines() {
+        let chain = load_str(concat!(
+            r#"{"root":"0x56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421"}"#,
+            "\n",
+            r#"{"balance":"1000","nonce":3,"root":"0x00","codeHash":"0x00","code":"0x6000","storage":{"0x0000000000000000000000000000000000000000000000000000000000000001":"0a"},"address":"0xa94f5374fce5edbc8e2a8697c15331677e6ebf0b","key":"0x00"}"#,
+            "\n"
+        ));
+        assert_eq!(chain.balance(&address()).await, Ok(U256::from(1000_u64)));
+        assert_eq!(chain.nonce(&address()).await, Ok(3));
+        assert_eq!(chain.code(&address()).await, Ok(vec![0x60, 0x00]));
+        assert_eq!(
+            chain.storage(&address(), &U256::one()).await,
+            Ok(U256::from(10_u64))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dump_block() {
+        let chain = load_str(
+            r#"{"root":"0x00","accounts":{"0xa94f5374fce5edbc8e2a8697c15331677e6ebf0b":{"balance":"42","nonce":0}}}"#,
+        );
+        assert_eq!(chain.balance(&address()).await, Ok(U256::from(42_u64)));
+    }
+
+    #[tokio::test]
+    async fn test_genesis_alloc() {
+        let chain = load_str(
+            r#"{"config":{"chainId":1},"alloc":{"a94f5374fce5edbc8e2a8697c15331677e6ebf0b":{"balance":"0x2a","nonce":"0x1"}}}"#,
+        );
+        assert_eq!(chain.balance(&address()).await, Ok(U256::from(42_u64)));
+        assert_eq!(chain.nonce(&address()).await, Ok(1));
+
+        let chain = load_str(r#"{"0xa94f5374fce5edbc8e2a8697c15331677e6ebf0b":{"balance":"7"}}"#);
+        assert_eq!(chain.balance(&address()).await, Ok(U256::from(7_u64)));
+    }
+
+    #[test]
+    fn test_parse_decimal() {
+        let max = "115792089237316195423570985008687907853269984665640564039457584007913129639935";
+        assert_eq!(
+            parse_decimal(max),
+            Some(u256h!(
+                "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff"
+            ))
+        );
+        assert!(parse_decimal(&format!("000{}", max)).is_some());
+        assert_eq!(
+            parse_decimal(
+                "115792089237316195423570985008687907853269984665640564039457584007913129639936"
+            ),
+            None
+        );
+        assert_eq!(parse_decimal(&format!("{}0", max)), None);
+        assert_eq!(parse_decimal("12a"), None);
+        assert_eq!(parse_decimal(""), None);
+    }
+}
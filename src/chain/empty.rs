@@ -2,8 +2,9 @@
 //!
 //! Chain with no state.
 
-use super::{BlockInfo, ChainState};
+use super::{types::Address, BlockInfo, ChainState, Error};
 use crate::prelude::*;
+use async_trait::async_trait;
 
 /// An empty chain state with a given block header
 ///
@@ -11,24 +12,25 @@ use crate::prelude::*;
 #[derive(Clone, Default, Debug)]
 pub struct Empty;
 
+#[async_trait]
 impl ChainState for Empty {
-    fn block(&self) -> BlockInfo {
-        BlockInfo::default()
+    async fn block(&self) -> Result<BlockInfo, Error> {
+        Ok(BlockInfo::default())
     }
 
-    fn nonce(&self, _address: &U256) -> usize {
-        0
+    async fn nonce(&self, _address: &Address) -> Result<usize, Error> {
+        Ok(0)
     }
 
-    fn balance(&self, _address: &U256) -> U256 {
-        U256::zero()
+    async fn balance(&self, _address: &Address) -> Result<U256, Error> {
+        Ok(U256::zero())
     }
 
-    fn code(&self, _address: &U256) -> Vec<u8> {
-        Vec::new()
+    async fn code(&self, _address: &Address) -> Result<Vec<u8>, Error> {
+        Ok(Vec::new())
     }
 
-    fn storage(&self, _address: &U256, _slot: &U256) -> U256 {
-        U256::zero()
+    async fn storage(&self, _address: &Address, _slot: &U256) -> Result<U256, Error> {
+        Ok(U256::zero())
     }
 }
@@ -0,0 +1,21 @@
+use thiserror::Error;
+
+/// Error reading chain state
+///
+/// A failed read is reported only to the caller that issued it. Callers
+/// waiting on the same read in a [`Cache`](super::Cache) retry it with their
+/// own read of the base.
+#[derive(Clone, Error, Debug, Eq, PartialEq)]
+pub enum Error {
+    #[error("upstream read failed: {0}")]
+    Upstream(String),
+
+    #[error("execution diverged from recording: {0}")]
+    Diverged(String),
+}
+
+impl Error {
+    pub fn upstream(err: anyhow::Error) -> Self {
+        Self::Upstream(format!("{:#}", err))
+    }
+}
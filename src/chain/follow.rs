@@ -0,0 +1,666 @@
+//! Follow the upstream head
+//!
+//! A fork is pinned to the block it was created at. A [`Follower`] polls the
+//! upstream node for new heads and moves the pinned block forward. Cached
+//! entries are updated with the state diff of each block, taken from geth's
+//! `prestateTracer`, instead of discarding the whole cache. Reorgs are rolled
+//! back to the common ancestor using the diffs of recent blocks. Deeper reorgs
+//! clear the cache and continue from the upstream head.
+
+use super::{BlockInfo, Cache, ChainState, Delta, RpcChain, StateDiff};
+use crate::{
+    chain::types::{
+        rpc::{BlockNumber, Bytes, Hex, PrestateAccount, PrestateDiff, PrestateTrace},
+        ConciseBlock,
+    },
+    prelude::*,
+};
+use async_trait::async_trait;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::broadcast;
+
+/// Number of followed blocks kept to roll back reorgs
+const HISTORY: usize = 128;
+
+/// Subscribers that fall further behind miss the oldest updates
+const CHANNEL_SIZE: usize = 64;
+
+/// Change of the pinned block, sent to subscribers
+#[derive(Clone, Debug)]
+pub struct HeadUpdate {
+    pub number:    u64,
+    pub hash:      U256,
+    /// The block was rolled back in a reorg and `diff` undoes it
+    pub reverted:  bool,
+    /// The cache was cleared after a reorg deeper than the followed history,
+    /// `diff` is empty
+    pub cleared:   bool,
+    /// Accounts and slots changed by the block
+    pub diff:      Arc<StateDiff>,
+    /// Accounts deleted by the block, their storage is no longer cached
+    pub destroyed: Arc<Vec<U256>>,
+}
+
+#[derive(Clone, Debug)]
+struct Head {
+    number: u64,
+    hash:   U256,
+    block:  BlockInfo,
+}
+
+impl From<&ConciseBlock> for Head {
+    fn from(block: &ConciseBlock) -> Self {
+        Self {
+            number: block.header.number,
+            hash:   block.hash.clone(),
+            block:  BlockInfo {
+                number:    block.header.number,
+                timestamp: block.header.timestamp,
+            },
+        }
+    }
+}
+
+/// A block applied on top of the block the fork was created at
+#[derive(Clone, Debug)]
+struct Followed {
+    head:      Head,
+    parent:    Head,
+    diff:      Arc<StateDiff>,
+    destroyed: Arc<Vec<U256>>,
+}
+
+/// Upstream chain with headers and block diffs, for example an [`RpcChain`]
+#[async_trait]
+pub trait HeadSource: ChainState {
+    /// Block that state is read at
+    fn block_number(&self) -> BlockNumber;
+
+    fn set_block_number(&self, block_number: BlockNumber);
+
+    async fn fetch_header(&self, block_number: BlockNumber) -> AnyResult<Option<ConciseBlock>>;
+
+    /// Traces of all transactions in the block, see [`RpcChain`]
+    async fn fetch_prestate_traces(&self, block_number: u64) -> AnyResult<Vec<PrestateTrace>>;
+}
+
+#[async_trait]
+impl HeadSource for RpcChain {
+    fn block_number(&self) -> BlockNumber {
+        self.block_number()
+    }
+
+    fn set_block_number(&self, block_number: BlockNumber) {
+        self.set_block_number(block_number)
+    }
+
+    async fn fetch_header(&self, block_number: BlockNumber) -> AnyResult<Option<ConciseBlock>> {
+        self.fetch_header(block_number).await
+    }
+
+    async fn fetch_prestate_traces(&self, block_number: u64) -> AnyResult<Vec<PrestateTrace>> {
+        self.fetch_prestate_traces(block_number).await
+    }
+}
+
+pub struct Follower<Base: HeadSource = RpcChain> {
+    cache:   Arc<Cache<Base>>,
+    tip:     Head,
+    history: VecDeque<Followed>,
+    sender:  broadcast::Sender<HeadUpdate>,
+}
+
+impl<Base: HeadSource> Follower<Base> {
+    /// Follow from the block `cache` is pinned to
+    pub async fn new(cache: Arc<Cache<Base>>) -> AnyResult<Self> {
+        let number = cache.inner().block_number();
+        let block = cache
+            .inner()
+            .fetch_header(number)
+            .await?
+            .ok_or_else(|| anyhow!("Block {:?} not found", number))?;
+        let (sender, _receiver) = broadcast::channel(CHANNEL_SIZE);
+        Ok(Self {
+            cache,
+            tip: Head::from(&block),
+            history: VecDeque::new(),
+            sender,
+        })
+    }
+
+    /// Receive an update for every block applied or rolled back
+    pub fn subscribe(&self) -> broadcast::Receiver<HeadUpdate> {
+        self.sender.subscribe()
+    }
+
+    /// Number of the pinned block
+    pub fn head(&self) -> u64 {
+        self.tip.number
+    }
+
+    /// Poll every `interval` until dropped. Failed polls are retried.
+    pub async fn run(mut self, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            let _instant = interval.tick().await;
+            if let Err(err) = self.poll().await {
+                warn!("Following upstream head: {:#}", err);
+            }
+        }
+    }
+
+    /// Move the pinned block to the latest upstream block
+    pub async fn poll(&mut self) -> AnyResult<()> {
+        let cache = self.cache.clone();
+        let chain = cache.inner();
+        let latest = chain
+            .fetch_header(BlockNumber::Latest)
+            .await?
+            .ok_or_else(|| anyhow!("Latest block not found"))?;
+        if latest.hash == self.tip.hash {
+            return Ok(());
+        }
+        // Load balanced upstreams can answer from a node that is behind. Wait
+        // for it to catch up instead of rolling back to its head.
+        if latest.header.number < self.tip.number {
+            debug!(
+                "Upstream head {} is behind pinned block {}",
+                latest.header.number, self.tip.number
+            );
+            return Ok(());
+        }
+
+        // Roll back to the common ancestor
+        while !self.is_canonical().await? {
+            if !self.revert() {
+                self.resync(&latest);
+                return Ok(());
+            }
+        }
+
+        // Apply new blocks in order
+        for number in self.tip.number + 1..=latest.header.number {
+            let block = chain
+                .fetch_header(BlockNumber::Number(number))
+                .await?
+                .ok_or_else(|| anyhow!("Block {} not found", number))?;
+            // A reorg while catching up is rolled back on the next poll
+            require!(
+                block.header.parent_hash == self.tip.hash,
+                anyhow!("Block {} does not extend the pinned block", number)
+            );
+            let traces = chain.fetch_prestate_traces(number).await?;
+            self.apply(&block, &traces);
+        }
+        Ok(())
+    }
+
+    /// Is the pinned block still part of the upstream chain
+    ///
+    /// Only a different block at the same height is a reorg. A missing block
+    /// is an error, the upstream is behind.
+    async fn is_canonical(&self) -> AnyResult<bool> {
+        let number = self.tip.number;
+        let block = self
+            .cache
+            .inner()
+            .fetch_header(BlockNumber::Number(number))
+            .await?
+            .ok_or_else(|| anyhow!("Block {} not found", number))?;
+        Ok(block.hash == self.tip.hash)
+    }
+
+    fn apply(&mut self, block: &ConciseBlock, traces: &[PrestateTrace]) {
+        let (diff, destroyed) = block_diff(traces);
+        let followed = Followed {
+            head:      Head::from(block),
+            parent:    self.tip.clone(),
+            diff:      Arc::new(diff),
+            destroyed: Arc::new(destroyed),
+        };
+        self.pin(followed.head.clone(), &followed.diff, &followed.destroyed);
+        self.notify(&followed, false);
+        self.history.push_back(followed);
+        if self.history.len() > HISTORY {
+            let _oldest = self.history.pop_front();
+        }
+    }
+
+    /// Roll back the pinned block. Returns `false` if there is no history
+    /// left to roll back.
+    fn revert(&mut self) -> bool {
+        let followed = match self.history.pop_back() {
+            Some(followed) => followed,
+            None => return false,
+        };
+        let inverse = followed.diff.inverse();
+        self.pin(followed.parent.clone(), &inverse, &followed.destroyed);
+        self.notify(
+            &Followed {
+                diff: Arc::new(inverse),
+                ..followed
+            },
+            true,
+        );
+        true
+    }
+
+    /// Start over at `latest` with an empty cache
+    fn resync(&mut self, latest: &ConciseBlock) {
+        warn!(
+            "Block {} was reorged out, more than {} followed blocks ago. Clearing the cache.",
+            self.tip.number, HISTORY
+        );
+        let head = Head::from(latest);
+        // Entries read at the old block are dropped after the move
+        self.cache
+            .inner()
+            .set_block_number(BlockNumber::Number(head.number));
+        self.cache.clear();
+        self.cache.set_block(head.block.clone());
+        self.tip = head.clone();
+        self.history.clear();
+        // Fails only if there are no subscribers
+        let _result = self.sender.send(HeadUpdate {
+            number:    head.number,
+            hash:      head.hash,
+            reverted:  false,
+            cleared:   true,
+            diff:      Arc::default(),
+            destroyed: Arc::default(),
+        });
+    }
+
+    /// Move the pinned block and update the cache to match
+    ///
+    /// The cache is updated first, so reads that start at the new block never
+    /// see cached values of the old one. Reads already in flight may still
+    /// fill entries with values of the old block.
+    fn pin(&mut self, head: Head, diff: &StateDiff, destroyed: &[U256]) {
+        for address in destroyed {
+            self.cache.invalidate_storage(address);
+        }
+        self.cache.update(diff);
+        self.cache.set_block(head.block.clone());
+        // Reads not cached go to the new block from here on
+        self.cache
+            .inner()
+            .set_block_number(BlockNumber::Number(head.number));
+        self.tip = head;
+    }
+
+    fn notify(&self, followed: &Followed, reverted: bool) {
+        debug!(
+            "{} block {}: {} nonces, {} balances, {} codes and {} storage slots changed",
+            if reverted { "Reverted" } else { "Applied" },
+            followed.head.number,
+            followed.diff.nonces.len(),
+            followed.diff.balances.len(),
+            followed.diff.codes.len(),
+            followed
+                .diff
+                .storages
+                .values()
+                .map(HashMap::len)
+                .sum::<usize>(),
+        );
+        // Fails only if there are no subscribers
+        let _result = self.sender.send(HeadUpdate {
+            number: followed.head.number,
+            hash: followed.head.hash.clone(),
+            reverted,
+            cleared: false,
+            diff: followed.diff.clone(),
+            destroyed: followed.destroyed.clone(),
+        });
+    }
+}
+
+/// Combine the diffs of all transactions in a block
+fn block_diff(traces: &[PrestateTrace]) -> (StateDiff, Vec<U256>) {
+    let mut result = StateDiff::default();
+    let mut destroyed = Vec::new();
+    for trace in traces {
+        let (diff, deleted) = transaction_diff(&trace.result);
+        result.merge(diff);
+        destroyed.extend(deleted);
+    }
+    destroyed.sort();
+    destroyed.dedup();
+    (result, destroyed)
+}
+
+/// Diff of a single transaction and the accounts it deleted
+fn transaction_diff(trace: &PrestateDiff) -> (StateDiff, Vec<U256>) {
+    let mut diff = StateDiff::default();
+    let mut destroyed = Vec::new();
+    let created = PrestateAccount::default();
+    let addresses = trace.pre.keys().chain(
+        trace
+            .post
+            .keys()
+            .filter(|address| !trace.pre.contains_key(address)),
+    );
+    for address in addresses {
+        let pre = trace.pre.get(address).unwrap_or(&created);
+        let key = address.to_word();
+        let post = match trace.post.get(address) {
+            Some(post) => post.clone(),
+            None => {
+                // Deleted accounts are empty
+                destroyed.push(key.clone());
+                PrestateAccount {
+                    balance: Some(U256::zero().into()),
+                    nonce:   Some(0),
+                    code:    Some(Vec::new().into()),
+                    storage: HashMap::new(),
+                }
+            }
+        };
+        if let Some(after) = post.balance {
+            let before = pre.balance.clone().map_or_else(U256::zero, Hex::into_inner);
+            insert_delta(&mut diff.balances, &key, before, after.into_inner());
+        }
+        if let Some(after) = post.nonce {
+            let before = pre.nonce.unwrap_or_default();
+            insert_delta(&mut diff.nonces, &key, before as usize, after as usize);
+        }
+        if let Some(after) = post.code {
+            let before = pre.code.clone().map_or_else(Vec::new, Bytes::to_vec);
+            insert_delta(&mut diff.codes, &key, before, after.to_vec());
+        }
+        let mut slots = HashMap::new();
+        for (slot, before) in &pre.storage {
+            let after = post.storage.get(slot).cloned().unwrap_or_else(U256::zero);
+            insert_delta(&mut slots, slot, before.clone(), after);
+        }
+        for (slot, after) in &post.storage {
+            if !pre.storage.contains_key(slot) {
+                insert_delta(&mut slots, slot, U256::zero(), after.clone());
+            }
+        }
+        if !slots.is_empty() {
+            let _previous = diff.storages.insert(key, slots);
+        }
+    }
+    (diff, destroyed)
+}
+
+fn insert_delta<T: PartialEq>(map: &mut HashMap<U256, Delta<T>>, key: &U256, before: T, after: T) {
+    if before != after {
+        let _previous = map.insert(key.clone(), Delta { before, after });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        chain::{types::Address, Account, Empty, Error},
+        test::prelude::assert_eq,
+    };
+    use serde_json::{from_value, json};
+    use std::sync::Mutex;
+
+    /// Canonical chain where block `n` writes `value` to slot one of account
+    /// one, with empty state otherwise
+    #[derive(Default)]
+    struct Upstream {
+        blocks:       Mutex<Vec<(ConciseBlock, U256)>>,
+        block_number: Mutex<BlockNumber>,
+    }
+
+    impl Upstream {
+        /// Replace the blocks from `number` on, `fork` makes the hashes unique
+        fn reorg(&self, number: u64, values: &[u64], fork: u64) {
+            let mut blocks = self.blocks.lock().unwrap();
+            blocks.truncate(number as usize);
+            for value in values {
+                let mut block = ConciseBlock::default();
+                block.header.number = blocks.len() as u64;
+                block.header.parent_hash = blocks
+                    .last()
+                    .map_or_else(U256::zero, |(parent, _)| parent.hash.clone());
+                block.hash = U256::from(1000 * fork + blocks.len() as u64 + 1);
+                blocks.push((block, U256::from(*value)));
+            }
+        }
+    }
+
+    #[async_trait]
+    impl ChainState for Upstream {
+        async fn block(&self) -> Result<BlockInfo, Error> {
+            Empty.block().await
+        }
+
+        async fn nonce(&self, address: &Address) -> Result<usize, Error> {
+            Empty.nonce(address).await
+        }
+
+        async fn balance(&self, address: &Address) -> Result<U256, Error> {
+            Empty.balance(address).await
+        }
+
+        async fn code(&self, address: &Address) -> Result<Vec<u8>, Error> {
+            Empty.code(address).await
+        }
+
+        async fn account(&self, address: &Address) -> Result<Account, Error> {
+            Empty.account(address).await
+        }
+
+        async fn storage(&self, _address: &Address, _slot: &U256) -> Result<U256, Error> {
+            let number = match *self.block_number.lock().unwrap() {
+                BlockNumber::Number(number) => number as usize,
+                _ => unreachable!("Followed chains are pinned to a number"),
+            };
+            Ok(self.blocks.lock().unwrap()[number].1.clone())
+        }
+    }
+
+    #[async_trait]
+    impl HeadSource for Upstream {
+        fn block_number(&self) -> BlockNumber {
+            *self.block_number.lock().unwrap()
+        }
+
+        fn set_block_number(&self, block_number: BlockNumber) {
+            *self.block_number.lock().unwrap() = block_number;
+        }
+
+        async fn fetch_header(&self, block_number: BlockNumber) -> AnyResult<Option<ConciseBlock>> {
+            let blocks = self.blocks.lock().unwrap();
+            Ok(match block_number {
+                BlockNumber::Number(number) => blocks.get(number as usize),
+                _ => blocks.last(),
+            }
+            .map(|(block, _)| block.clone()))
+        }
+
+        async fn fetch_prestate_traces(&self, block_number: u64) -> AnyResult<Vec<PrestateTrace>> {
+            let blocks = self.blocks.lock().unwrap();
+            let account = |value: &U256| {
+                let mut storage = HashMap::new();
+                let _previous = storage.insert(U256::one(), value.clone());
+                let mut accounts = HashMap::new();
+                let _previous =
+                    accounts.insert(Address::from_word(&U256::one()), PrestateAccount {
+                        storage,
+                        ..PrestateAccount::default()
+                    });
+                accounts
+            };
+            let number = block_number as usize;
+            Ok(vec![PrestateTrace {
+                result: PrestateDiff {
+                    pre:  account(&blocks[number - 1].1),
+                    post: account(&blocks[number].1),
+                },
+            }])
+        }
+    }
+
+    fn updates(receiver: &mut broadcast::Receiver<HeadUpdate>) -> Vec<(u64, bool, bool)> {
+        let mut result = Vec::new();
+        while let Ok(update) = receiver.try_recv() {
+            result.push((update.number, update.reverted, update.cleared));
+        }
+        result
+    }
+
+    #[tokio::test]
+    async fn test_poll() {
+        let upstream = Upstream::default();
+        upstream.reorg(0, &[1], 0);
+        upstream.set_block_number(BlockNumber::Number(0));
+        let cache = Arc::new(Cache::from(upstream));
+        let mut follower = Follower::new(cache.clone()).await.unwrap();
+        let mut receiver = follower.subscribe();
+        let (address, slot) = (Address::from_word(&U256::one()), U256::one());
+        assert_eq!(cache.storage(&address, &slot).await, Ok(U256::from(1)));
+
+        // New blocks are applied to the cache
+        cache.inner().reorg(1, &[2, 3], 0);
+        follower.poll().await.unwrap();
+        assert_eq!(follower.head(), 2);
+        assert_eq!(cache.inner().block_number(), BlockNumber::Number(2));
+        assert_eq!(cache.block().await.map(|block| block.number), Ok(2));
+        assert_eq!(cache.storage(&address, &slot).await, Ok(U256::from(3)));
+        assert_eq!(updates(&mut receiver), vec![
+            (1, false, false),
+            (2, false, false)
+        ]);
+
+        // Unchanged head
+        follower.poll().await.unwrap();
+        assert_eq!(updates(&mut receiver), vec![]);
+
+        // Reorgs are rolled back to the common ancestor
+        cache.inner().reorg(2, &[20, 30], 1);
+        follower.poll().await.unwrap();
+        assert_eq!(follower.head(), 3);
+        assert_eq!(cache.storage(&address, &slot).await, Ok(U256::from(30)));
+        assert_eq!(updates(&mut receiver), vec![
+            (2, true, false),
+            (2, false, false),
+            (3, false, false)
+        ]);
+        assert_eq!(cache.stats().entries, 2);
+    }
+
+    #[tokio::test]
+    async fn test_deep_reorg() {
+        let upstream = Upstream::default();
+        upstream.reorg(0, &[1, 2], 0);
+        upstream.set_block_number(BlockNumber::Number(1));
+        let cache = Arc::new(Cache::from(upstream));
+        let mut follower = Follower::new(cache.clone()).await.unwrap();
+        let mut receiver = follower.subscribe();
+        let (address, slot) = (Address::from_word(&U256::one()), U256::one());
+        assert_eq!(cache.storage(&address, &slot).await, Ok(U256::from(2)));
+
+        // The pinned block is reorged out, without history to roll back
+        cache.inner().reorg(1, &[5, 6, 7], 1);
+        follower.poll().await.unwrap();
+        assert_eq!(follower.head(), 3);
+        assert_eq!(updates(&mut receiver), vec![(3, false, true)]);
+        assert_eq!(cache.stats().entries, 1);
+        assert_eq!(cache.storage(&address, &slot).await, Ok(U256::from(7)));
+
+        // Polling continues from the new head
+        cache.inner().reorg(4, &[8], 1);
+        follower.poll().await.unwrap();
+        assert_eq!(follower.head(), 4);
+        assert_eq!(cache.storage(&address, &slot).await, Ok(U256::from(8)));
+    }
+
+    #[tokio::test]
+    async fn test_lagging_upstream() {
+        let upstream = Upstream::default();
+        upstream.reorg(0, &[1, 2, 3], 0);
+        upstream.set_block_number(BlockNumber::Number(2));
+        let cache = Arc::new(Cache::from(upstream));
+        let mut follower = Follower::new(cache.clone()).await.unwrap();
+        let mut receiver = follower.subscribe();
+        let (address, slot) = (Address::from_word(&U256::one()), U256::one());
+        assert_eq!(cache.storage(&address, &slot).await, Ok(U256::from(3)));
+
+        // An upstream node behind the pinned block is not a reorg
+        cache.inner().blocks.lock().unwrap().truncate(2);
+        follower.poll().await.unwrap();
+        assert_eq!(follower.head(), 2);
+        assert_eq!(cache.inner().block_number(), BlockNumber::Number(2));
+        assert_eq!(updates(&mut receiver), vec![]);
+        assert_eq!(cache.storage(&address, &slot).await, Ok(U256::from(3)));
+
+        // Following continues once it caught up
+        cache.inner().reorg(2, &[3, 4], 0);
+        follower.poll().await.unwrap();
+        assert_eq!(follower.head(), 3);
+        assert_eq!(updates(&mut receiver), vec![(3, false, false)]);
+        assert_eq!(cache.storage(&address, &slot).await, Ok(U256::from(4)));
+    }
+
+    #[test]
+    fn test_block_diff() {
+        let traces: Vec<PrestateTrace> = from_value(json!([
+            { "result": {
+                "pre": {
+                    "0x0000000000000000000000000000000000000001": {
+                        "balance": "0x10",
+                        "nonce": 1,
+                        "storage": {
+                            "0x0000000000000000000000000000000000000000000000000000000000000001":
+                                "0x0000000000000000000000000000000000000000000000000000000000000005",
+                            "0x0000000000000000000000000000000000000000000000000000000000000002":
+                                "0x0000000000000000000000000000000000000000000000000000000000000006",
+                        },
+                    },
+                    "0x0000000000000000000000000000000000000002": { "balance": "0x1" },
+                },
+                "post": {
+                    "0x0000000000000000000000000000000000000001": {
+                        "nonce": 2,
+                        "storage": {
+                            "0x0000000000000000000000000000000000000000000000000000000000000001":
+                                "0x0000000000000000000000000000000000000000000000000000000000000007",
+                        },
+                    },
+                },
+            }},
+            { "result": {
+                "pre": {
+                    "0x0000000000000000000000000000000000000001": { "balance": "0x10", "nonce": 2 },
+                },
+                "post": {
+                    "0x0000000000000000000000000000000000000001": { "nonce": 3 },
+                },
+            }},
+        ]))
+        .unwrap();
+        let (diff, destroyed) = block_diff(&traces);
+        let (one, two) = (U256::one(), U256::from(2_u64));
+        assert_eq!(destroyed, vec![two.clone()]);
+        assert_eq!(diff.nonces[&one], Delta {
+            before: 1,
+            after:  3,
+        });
+        assert_eq!(diff.balances[&two], Delta {
+            before: U256::one(),
+            after:  U256::zero(),
+        });
+        assert!(!diff.balances.contains_key(&one));
+        assert_eq!(diff.storages[&one][&one], Delta {
+            before: U256::from(5_u64),
+            after:  U256::from(7_u64),
+        });
+        assert_eq!(diff.storages[&one][&two], Delta {
+            before: U256::from(6_u64),
+            after:  U256::zero(),
+        });
+    }
+}
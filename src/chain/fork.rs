@@ -3,14 +3,120 @@
 //! Takes a given read-only chain implementation and implements and in-memory
 //! change buffer on top of it. The new chain acts as a fork of the underlying
 //! chain.
+//!
+//! Writes are kept in a stack of immutable layers under a mutable top layer.
+//! Cloning a fork shares all of them, so it takes constant time regardless of
+//! how much was written. The first write after a clone freezes the shared top
+//! into a new layer instead of copying it. Checkpoints freeze the top as well,
+//! so reverting to one only restores the layer stack. Deep stacks are merged
+//! once per shared layer, so clones that write on top of it reuse the result.
+
+use super::{types::Address, Account, BlockInfo, ChainState, Error, StateSet, WriteableChainState};
+use crate::{prelude::*, utils::keccak256};
+use async_trait::async_trait;
+use once_cell::sync::OnceCell;
+use std::{collections::HashSet, iter, mem, sync::Arc};
+
+/// Layer stacks deeper than this are merged into a single layer
+const MAX_DEPTH: usize = 32;
+
+/// Writes to a fork
+#[derive(Clone, Debug, Default)]
+pub struct Writes {
+    pub state:   StateSet,
+    /// Accounts whose storage in lower layers and the base is hidden, except
+    /// for the slots in `state`
+    pub cleared: HashSet<U256>,
+}
+
+impl Writes {
+    fn is_empty(&self) -> bool {
+        let state = &self.state;
+        state.block.is_none()
+            && state.nonces.is_empty()
+            && state.balances.is_empty()
+            && state.codes.is_empty()
+            && state.storages.is_empty()
+            && self.cleared.is_empty()
+    }
 
-use super::{BlockInfo, ChainState, StateSet, WriteableChainState};
-use crate::prelude::*;
+    /// Apply the writes of a newer layer on top
+    fn merge(&mut self, newer: &Self) {
+        for address in &newer.cleared {
+            self.state.storages.retain(|(owner, _), _| owner != address);
+            let _inserted = self.cleared.insert(address.clone());
+        }
+        if let Some(block) = &newer.state.block {
+            self.state.block = Some(block.clone());
+        }
+        self.state.nonces.extend(
+            newer
+                .state
+                .nonces
+                .iter()
+                .map(|(address, nonce)| (address.clone(), *nonce)),
+        );
+        self.state.balances.extend(newer.state.balances.clone());
+        self.state.codes.extend(newer.state.codes.clone());
+        self.state.storages.extend(newer.state.storages.clone());
+    }
+}
+
+impl From<StateSet> for Writes {
+    fn from(state: StateSet) -> Self {
+        Self {
+            state,
+            cleared: HashSet::new(),
+        }
+    }
+}
+
+/// Frozen writes, shared between clones and checkpoints
+#[derive(Debug)]
+struct Layer {
+    writes:    Arc<Writes>,
+    parent:    Option<Arc<Layer>>,
+    /// Number of layers including this one
+    depth:     usize,
+    /// This layer and its parents merged into one
+    compacted: OnceCell<Arc<Layer>>,
+}
+
+impl Layer {
+    fn new(writes: Arc<Writes>, parent: Option<Arc<Layer>>) -> Self {
+        let depth = parent.as_ref().map_or(0, |layer| layer.depth) + 1;
+        Self {
+            writes,
+            parent,
+            depth,
+            compacted: OnceCell::new(),
+        }
+    }
+
+    /// A single layer with the writes of the whole stack
+    ///
+    /// Computed once, no matter how many forks freeze on top of this layer.
+    fn compacted(&self) -> Arc<Layer> {
+        self.compacted
+            .get_or_init(|| {
+                let levels = iter::successors(Some(self), |layer| layer.parent.as_deref())
+                    .map(|layer| &*layer.writes);
+                Arc::new(Self::new(Arc::new(flatten(levels)), None))
+            })
+            .clone()
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct Fork<Base: ChainState> {
-    base:  Base,
-    state: StateSet,
+    base:        Base,
+    /// Writes since the last freeze, shared with clones until written to
+    top:         Arc<Writes>,
+    layers:      Option<Arc<Layer>>,
+    /// Layer stack at each checkpoint
+    checkpoints: Vec<Option<Arc<Layer>>>,
+    /// All writes merged, until the next write
+    merged:      OnceCell<Arc<Writes>>,
 }
 
 impl<Base: ChainState> Fork<Base> {
@@ -25,75 +131,391 @@ impl<Base: ChainState> Fork<Base> {
     pub fn into_inner(self) -> Base {
         self.base
     }
+
+    /// Everything written to this fork, merged from all layers
+    ///
+    /// The result is kept until the next write, so reading it repeatedly is
+    /// cheap.
+    pub fn writes(&self) -> Arc<Writes> {
+        self.merged
+            .get_or_init(|| Arc::new(flatten(self.levels())))
+            .clone()
+    }
+
+    /// Take a checkpoint and return its id
+    ///
+    /// Ids start at one and are the number of checkpoints, like Ganache's
+    /// `evm_snapshot`.
+    pub fn checkpoint(&mut self) -> usize {
+        self.freeze();
+        self.checkpoints.push(self.layers.clone());
+        self.checkpoints.len()
+    }
+
+    /// Undo all writes since checkpoint `id`
+    ///
+    /// Consumes the checkpoint and all later ones. Returns `false` if there is
+    /// no such checkpoint.
+    pub fn revert(&mut self, id: usize) -> bool {
+        if id == 0 || id > self.checkpoints.len() {
+            return false;
+        }
+        self.layers = self.checkpoints[id - 1].clone();
+        self.top = Arc::default();
+        self.merged = OnceCell::new();
+        self.checkpoints.truncate(id - 1);
+        true
+    }
+
+    /// Treat all storage of `address` as zero, except for later writes
+    ///
+    /// Used to replace the full storage of an account. Cleared accounts are
+    /// not part of [`Fork::diff`].
+    pub fn clear_storage(&mut self, address: &Address) {
+        let address = address.to_word();
+        let top = self.top_mut();
+        top.state.storages.retain(|(owner, _), _| *owner != address);
+        let _inserted = top.cleared.insert(address);
+    }
+
+    /// Writes go to the top layer. If it is shared with a clone it is frozen
+    /// first, so neither side has to copy it.
+    fn top_mut(&mut self) -> &mut Writes {
+        if Arc::get_mut(&mut self.top).is_none() {
+            self.freeze();
+        }
+        self.merged = OnceCell::new();
+        Arc::get_mut(&mut self.top).expect("Top layer is not shared")
+    }
+
+    /// Push the top writes onto the layer stack and start an empty top
+    fn freeze(&mut self) {
+        if self.top.is_empty() {
+            self.top = Arc::default();
+            return;
+        }
+        // Keeps reads fast, at the cost of copying the writes once. The
+        // parent is usually shared with other clones, which reuse the copy.
+        let parent = self.layers.take().map(|layer| {
+            if layer.depth >= MAX_DEPTH {
+                layer.compacted()
+            } else {
+                layer
+            }
+        });
+        self.layers = Some(Arc::new(Layer::new(mem::take(&mut self.top), parent)));
+    }
+
+    /// Writes from newest to oldest
+    fn levels(&self) -> impl Iterator<Item = &Writes> {
+        let layers = iter::successors(self.layers.as_deref(), |layer| layer.parent.as_deref());
+        iter::once(&*self.top).chain(layers.map(|layer| &*layer.writes))
+    }
+
+    /// Newest write of a value other than storage
+    fn written<'a, T>(&'a self, get: impl Fn(&'a StateSet) -> Option<&'a T>) -> Option<&'a T> {
+        self.levels().find_map(|writes| get(&writes.state))
+    }
+
+    /// Newest write to a storage slot, zero if it was cleared since
+    fn written_storage(&self, address: &U256, slot: &U256) -> Option<U256> {
+        let key = (address.clone(), slot.clone());
+        for writes in self.levels() {
+            if let Some(value) = writes.state.storages.get(&key) {
+                return Some(value.clone());
+            }
+            if writes.cleared.contains(address) {
+                return Some(U256::zero());
+            }
+        }
+        None
+    }
+
+    /// Keys that are neither written nor cleared in this fork
+    fn unwritten(&self, keys: &[(Address, U256)]) -> Vec<(Address, U256)> {
+        keys.iter()
+            .filter(|(address, slot)| self.written_storage(&address.to_word(), slot).is_none())
+            .cloned()
+            .collect()
+    }
 }
 
 impl<Base: ChainState> From<Base> for Fork<Base> {
     fn from(base: Base) -> Self {
         Self {
             base,
-            state: StateSet::default(),
+            top: Arc::default(),
+            layers: None,
+            checkpoints: Vec::new(),
+            merged: OnceCell::new(),
         }
     }
 }
 
+/// Writes from newest to oldest merged into one
+fn flatten<'a>(levels: impl Iterator<Item = &'a Writes>) -> Writes {
+    let levels = levels.collect::<Vec<_>>();
+    let mut result = Writes::default();
+    for writes in levels.into_iter().rev() {
+        result.merge(writes);
+    }
+    result
+}
+
+#[async_trait]
 impl<Base: ChainState> ChainState for Fork<Base> {
-    fn block(&self) -> BlockInfo {
-        self.state
-            .block
-            .clone()
-            .unwrap_or_else(|| self.base.block())
+    async fn block(&self) -> Result<BlockInfo, Error> {
+        match self.written(|state| state.block.as_ref()) {
+            Some(block) => Ok(block.clone()),
+            None => self.base.block().await,
+        }
     }
 
-    fn nonce(&self, address: &U256) -> usize {
-        self.state
-            .nonces
-            .get(address)
-            .cloned()
-            .unwrap_or_else(|| self.base.nonce(address))
+    async fn nonce(&self, address: &Address) -> Result<usize, Error> {
+        let word = address.to_word();
+        match self.written(|state| state.nonces.get(&word)) {
+            Some(nonce) => Ok(*nonce),
+            None => self.base.nonce(address).await,
+        }
     }
 
-    fn balance(&self, address: &U256) -> U256 {
-        self.state
-            .balances
-            .get(address)
-            .cloned()
-            .unwrap_or_else(|| self.base.balance(address))
+    async fn balance(&self, address: &Address) -> Result<U256, Error> {
+        let word = address.to_word();
+        match self.written(|state| state.balances.get(&word)) {
+            Some(balance) => Ok(balance.clone()),
+            None => self.base.balance(address).await,
+        }
     }
 
-    fn code(&self, address: &U256) -> Vec<u8> {
-        self.state
-            .codes
-            .get(address)
-            .cloned()
-            .unwrap_or_else(|| self.base.code(address))
+    async fn code(&self, address: &Address) -> Result<Vec<u8>, Error> {
+        let word = address.to_word();
+        match self.written(|state| state.codes.get(&word)) {
+            Some(code) => Ok(code.clone()),
+            None => self.base.code(address).await,
+        }
     }
 
-    fn storage(&self, address: &U256, slot: &U256) -> U256 {
-        self.state
-            .storages
-            .get(&(address.clone(), slot.clone()))
-            .cloned()
-            .unwrap_or_else(|| self.base.storage(address, slot))
+    async fn code_hash(&self, address: &Address) -> Result<U256, Error> {
+        let word = address.to_word();
+        match self.written(|state| state.codes.get(&word)) {
+            Some(code) => Ok(keccak256(code)),
+            None => self.base.code_hash(address).await,
+        }
+    }
+
+    /// Only reads the base if some field is not written in this fork
+    async fn account(&self, address: &Address) -> Result<Account, Error> {
+        let word = address.to_word();
+        let nonce = self.written(|state| state.nonces.get(&word));
+        let balance = self.written(|state| state.balances.get(&word));
+        let code = self.written(|state| state.codes.get(&word));
+        let mut account = match (nonce, balance, code) {
+            (Some(_), Some(_), Some(_)) => Account::default(),
+            _ => self.base.account(address).await?,
+        };
+        if let Some(nonce) = nonce {
+            account.nonce = *nonce;
+        }
+        if let Some(balance) = balance {
+            account.balance = balance.clone();
+        }
+        if let Some(code) = code {
+            account.code_hash = keccak256(code);
+        }
+        Ok(account)
+    }
+
+    async fn storage(&self, address: &Address, slot: &U256) -> Result<U256, Error> {
+        match self.written_storage(&address.to_word(), slot) {
+            Some(value) => Ok(value),
+            None => self.base.storage(address, slot).await,
+        }
+    }
+
+    async fn storage_batch(&self, keys: &[(Address, U256)]) -> Result<Vec<U256>, Error> {
+        let misses = self.unwritten(keys);
+        let mut fetched = self.base.storage_batch(&misses).await?.into_iter();
+        Ok(keys
+            .iter()
+            .map(|(address, slot)| {
+                self.written_storage(&address.to_word(), slot)
+                    .unwrap_or_else(|| fetched.next().expect("One value per key"))
+            })
+            .collect())
+    }
+
+    async fn prefetch(&self, keys: &[(Address, U256)]) -> Result<(), Error> {
+        self.base.prefetch(&self.unwritten(keys)).await
     }
 }
 
 impl<Base: ChainState> WriteableChainState for Fork<Base> {
-    fn set_nonce(&mut self, address: &U256, nonce: usize) {
-        let _previous = self.state.nonces.insert(address.clone(), nonce);
+    fn set_nonce(&mut self, address: &Address, nonce: usize) {
+        let _previous = self.top_mut().state.nonces.insert(address.to_word(), nonce);
     }
 
-    fn set_balance(&mut self, address: &U256, balance: &U256) {
-        let _previous = self.state.balances.insert(address.clone(), balance.clone());
+    fn set_balance(&mut self, address: &Address, balance: &U256) {
+        let _previous = self
+            .top_mut()
+            .state
+            .balances
+            .insert(address.to_word(), balance.clone());
     }
 
-    fn set_code(&mut self, address: &U256, code: &[u8]) {
-        let _previous = self.state.codes.insert(address.clone(), code.to_vec());
+    fn set_code(&mut self, address: &Address, code: &[u8]) {
+        let _previous = self
+            .top_mut()
+            .state
+            .codes
+            .insert(address.to_word(), code.to_vec());
     }
 
-    fn set_storage(&mut self, address: &U256, slot: &U256, value: &U256) {
+    fn set_storage(&mut self, address: &Address, slot: &U256, value: &U256) {
         let _previous = self
+            .top_mut()
             .state
             .storages
-            .insert((address.clone(), slot.clone()), value.clone());
+            .insert((address.to_word(), slot.clone()), value.clone());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        chain::{types::Address, Empty},
+        test::prelude::assert_eq,
+    };
+
+    #[tokio::test]
+    async fn test_checkpoints() {
+        let (address, slot) = (Address::from_word(&U256::one()), U256::zero());
+        let mut chain = Fork::from(Empty);
+        chain.set_storage(&address, &slot, &U256::from(1_u64));
+        assert_eq!(chain.checkpoint(), 1);
+        chain.set_storage(&address, &slot, &U256::from(2_u64));
+        chain.set_balance(&address, &U256::from(5_u64));
+        assert_eq!(chain.checkpoint(), 2);
+        chain.set_storage(&address, &slot, &U256::from(3_u64));
+
+        // Reverting consumes the checkpoint and all later ones
+        assert!(chain.revert(1));
+        assert_eq!(chain.storage(&address, &slot).await, Ok(U256::from(1_u64)));
+        assert_eq!(chain.balance(&address).await, Ok(U256::zero()));
+        assert!(!chain.revert(2));
+        assert!(!chain.revert(1));
+        assert_eq!(chain.checkpoint(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_clear_storage() {
+        let address = Address::from_word(&U256::one());
+        let (slot, other) = (U256::zero(), U256::one());
+        let mut base = Fork::from(Empty);
+        base.set_storage(&address, &slot, &U256::from(1_u64));
+        base.set_storage(&address, &other, &U256::from(2_u64));
+        let mut chain: Fork<Fork<Empty>> = Fork::from(base);
+        chain.set_storage(&address, &slot, &U256::from(3_u64));
+        assert_eq!(chain.checkpoint(), 1);
+        chain.clear_storage(&address);
+        chain.set_storage(&address, &other, &U256::from(4_u64));
+        assert_eq!(
+            chain
+                .storage_batch(&[
+                    (address.clone(), slot.clone()),
+                    (address.clone(), other.clone())
+                ])
+                .await,
+            Ok(vec![U256::zero(), U256::from(4_u64)])
+        );
+
+        assert!(chain.revert(1));
+        assert_eq!(
+            chain
+                .storage_batch(&[(address.clone(), slot), (address, other)])
+                .await,
+            Ok(vec![U256::from(3_u64), U256::from(2_u64)])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_account() {
+        let address = Address::from(hex!("0f572e5295c57f15886f9b263e2f6d2d6c7b5ec6"));
+        let mut chain = Fork::from(Empty);
+        assert_eq!(chain.account(&address).await, Ok(Account::default()));
+        chain.set_balance(&address, &U256::from(5_u64));
+        chain.set_code(&address, &[0x00]);
+        let account = chain.account(&address).await.unwrap();
+        assert_eq!(account.balance, U256::from(5_u64));
+        assert_eq!(account.code_hash, keccak256(&[0x00]));
+        assert_eq!(
+            chain.code_hash(&address).await,
+            Ok(account.code_hash.clone())
+        );
+        assert!(!account.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_clone() {
+        let address = Address::from_word(&U256::one());
+        let mut chain = Fork::from(Empty);
+        chain.set_balance(&address, &U256::from(1_u64));
+        let mut branch = chain.clone();
+        branch.set_balance(&address, &U256::from(2_u64));
+        chain.set_nonce(&address, 3);
+        assert_eq!(chain.balance(&address).await, Ok(U256::from(1_u64)));
+        assert_eq!(chain.nonce(&address).await, Ok(3));
+        assert_eq!(branch.balance(&address).await, Ok(U256::from(2_u64)));
+        assert_eq!(branch.nonce(&address).await, Ok(0));
+
+        // Writes before the clone are shared, not copied
+        let (ours, theirs) = (chain.layers.as_ref(), branch.layers.as_ref());
+        assert!(Arc::ptr_eq(&ours.unwrap().writes, &theirs.unwrap().writes));
+
+        // Deep stacks are merged
+        for nonce in 0..2 * MAX_DEPTH {
+            let _clone = chain.clone();
+            chain.set_nonce(&address, nonce);
+        }
+        assert!(chain.layers.as_ref().unwrap().depth <= MAX_DEPTH);
+        assert_eq!(chain.nonce(&address).await, Ok(2 * MAX_DEPTH - 1));
+        assert_eq!(chain.balance(&address).await, Ok(U256::from(1_u64)));
+        assert_eq!(
+            chain.writes().state.nonces[&address.to_word()],
+            2 * MAX_DEPTH - 1
+        );
+
+        // Clones writing on top of a deep stack share its merged copy
+        let mut deep = Fork::from(Empty);
+        for nonce in 0..MAX_DEPTH {
+            deep.set_nonce(&address, nonce);
+            let _id = deep.checkpoint();
+        }
+        let (mut first, mut second) = (deep.clone(), deep.clone());
+        first.set_balance(&address, &U256::from(1_u64));
+        let _id = first.checkpoint();
+        second.set_balance(&address, &U256::from(2_u64));
+        let _id = second.checkpoint();
+        let parent = |fork: &Fork<Empty>| fork.layers.as_ref().unwrap().parent.clone().unwrap();
+        assert!(Arc::ptr_eq(&parent(&first), &parent(&second)));
+        assert_eq!(parent(&first).depth, 1);
+        assert_eq!(second.nonce(&address).await, Ok(MAX_DEPTH - 1));
+    }
+
+    #[test]
+    fn test_writes() {
+        let address = Address::from_word(&U256::one());
+        let mut chain = Fork::from(Empty);
+        chain.set_balance(&address, &U256::from(1_u64));
+        let writes = chain.writes();
+        assert!(Arc::ptr_eq(&writes, &chain.writes()));
+
+        chain.clear_storage(&address);
+        assert!(!Arc::ptr_eq(&writes, &chain.writes()));
+        assert!(chain.writes().cleared.contains(&address.to_word()));
+        assert_eq!(
+            chain.writes().state.balances[&address.to_word()],
+            U256::from(1_u64)
+        );
     }
 }
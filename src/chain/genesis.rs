@@ -0,0 +1,149 @@
+//! Genesis state
+//!
+//! Builds the initial state and block of a local chain from the configuration
+//! passed to `test_setChainParams`.
+
+use super::{
+    dump,
+    trie::{StateTrie, EMPTY_ROOT},
+    types::{
+        rpc::{GenesisAccount, GenesisConfig},
+        Address, Block, BlockHeader,
+    },
+    Empty, Fork, WriteableChainState,
+};
+use crate::{prelude::*, utils::RlpHash};
+
+/// Genesis state and block with the state root of that state
+pub fn build(config: &GenesisConfig) -> AnyResult<(Fork<Empty>, Block)> {
+    let mut state = match &config.state_dump {
+        Some(path) => dump::load_file(path)?,
+        None => Fork::from(Empty),
+    };
+    for (address, account) in &config.accounts {
+        write_account(&mut state, address, account);
+    }
+    let state_root = StateTrie::from(&state.writes().state).root();
+    let block = Block {
+        header:       header(&config.genesis, state_root),
+        transactions: Vec::new(),
+        ommers:       Vec::new(),
+    };
+    Ok((state, block))
+}
+
+fn write_account<C: WriteableChainState>(
+    chain: &mut C,
+    address: &Address,
+    account: &GenesisAccount,
+) {
+    chain.set_nonce(address, account.nonce as usize);
+    chain.set_balance(address, &account.balance);
+    if !account.code.is_empty() {
+        chain.set_code(address, &account.code);
+    }
+    for (slot, value) in &account.storage {
+        chain.set_storage(address, slot, value);
+    }
+}
+
+/// The configured header with the fields a genesis block must have
+fn header(genesis: &BlockHeader, state_root: U256) -> BlockHeader {
+    BlockHeader {
+        parent_hash: U256::zero(),
+        ommers_hash: Vec::<BlockHeader>::new().rlp_hash(),
+        state_root,
+        transactions_root: EMPTY_ROOT,
+        receipts_root: EMPTY_ROOT,
+        number: 0,
+        gas_used: 0,
+        ..genesis.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{chain::ChainState, test::prelude::assert_eq};
+    use serde_json::{from_value, json};
+
+    #[tokio::test]
+    async fn test_build() {
+        let config: GenesisConfig = from_value(json!({
+            "accounts": {
+                "0x095e7baea6a6c7c4c2dfeb977efac326af552d87": {
+                    "balance": "0x0de0b6b3a7640000",
+                    "code": "0x600160005500",
+                    "nonce": "0x00",
+                    "storage": {}
+                },
+                "0xa94f5374fce5edbc8e2a8697c15331677e6ebf0b": {
+                    "balance": "0x0de0b6b3a7640000",
+                    "code": "0x",
+                    "nonce": "0x00",
+                    "storage": {}
+                }
+            },
+            "genesis": {
+                "author": "0x2adc25665018aa1fe0e6bc666dac8fc2697ff9ba",
+                "difficulty": "0x020000",
+                "extraData": "0x00",
+                "gasLimit": "0x7fffffffffffffff",
+                "mixHash": "0x0000000000000000000000000000000000000000000000000000000000000000",
+                "nonce": "0x0000000000000000",
+                "timestamp": "0x00"
+            },
+            "sealEngine": "NoProof"
+        }))
+        .unwrap();
+        let (state, block) = build(&config).unwrap();
+        let address = Address::from(hex!("a94f5374fce5edbc8e2a8697c15331677e6ebf0b"));
+        assert_eq!(
+            state.balance(&address).await.unwrap(),
+            u256h!("0000000000000000000000000000000000000000000000000de0b6b3a7640000")
+        );
+        assert_eq!(
+            block.header.ommers_hash,
+            u256h!("1dcc4de8dec75d7aab85b567b6ccd41ad312451b948a7413f0a142fd40d49347")
+        );
+        assert_eq!(block.header.gas_limit, 0x7fff_ffff_ffff_ffff);
+    }
+
+    /// `customg` in go-ethereum's `core/genesis_test.go`
+    #[test]
+    fn test_geth_fixture() {
+        let config: GenesisConfig = from_value(json!({
+            "accounts": {
+                "0x0100000000000000000000000000000000000000": {
+                    "balance": "0x01",
+                    "code": "0x",
+                    "nonce": "0x00",
+                    "storage": {
+                        "0x0100000000000000000000000000000000000000000000000000000000000000":
+                            "0x0100000000000000000000000000000000000000000000000000000000000000"
+                    }
+                }
+            },
+            "genesis": {
+                "author": "0x0000000000000000000000000000000000000000",
+                "difficulty": "0x020000",
+                "extraData": "0x",
+                "gasLimit": "0x47e7c4",
+                "mixHash": "0x0000000000000000000000000000000000000000000000000000000000000000",
+                "nonce": "0x0000000000000000",
+                "timestamp": "0x00"
+            },
+            "sealEngine": "NoProof"
+        }))
+        .unwrap();
+        let (_state, block) = build(&config).unwrap();
+        assert_eq!(
+            block.header.state_root,
+            u256h!("8257aee1cdaa2c42ae5dfad636710ee73437466b7504770feb19d63e2be3c913")
+        );
+        assert_eq!(
+            block.header.rlp_hash(),
+            u256h!("89c99d90b79719238d2645c7642f2c9295246e80775b38cfd162b696817fbd50")
+        );
+    }
+}
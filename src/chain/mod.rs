@@ -1,42 +1,200 @@
-// TODO: async_trait and error handling
-
-// TODO: Instead of hashmaps we can record all the read values. When
-// re-executing, we can feed the values in in the same order they where read,
-// ignoring parameters.
-
+mod account;
+mod block_store;
 mod cache;
+pub mod dump;
 mod empty;
+mod error;
+mod follow;
 mod fork;
+pub mod genesis;
+pub mod overrides;
+pub mod persist;
+mod recorder;
+mod replay;
 mod rpc_chain;
+mod state_diff;
+mod state_index;
 mod state_set;
+pub mod trie;
 pub mod types;
 
-pub use self::{cache::Cache, empty::Empty, fork::Fork, rpc_chain::RpcChain, state_set::StateSet};
+pub use self::{
+    account::{Account, EMPTY_CODE_HASH},
+    block_store::{BlockStore, StoredBlock},
+    cache::{Cache, CachePolicy, CacheStats},
+    empty::Empty,
+    error::Error,
+    follow::{Follower, HeadSource, HeadUpdate},
+    fork::{Fork, Writes},
+    recorder::{Read, Recorder, Recording},
+    replay::Replay,
+    rpc_chain::RpcChain,
+    state_diff::{Delta, StateDiff},
+    state_index::StateIndex,
+    state_set::StateSet,
+};
 
-use self::types::rpc::BlockNumber;
-use crate::{prelude::*, rpc};
+use self::types::{rpc::BlockNumber, Address};
+use crate::{prelude::*, rpc, utils::keccak256};
+use async_trait::async_trait;
+use std::{str::FromStr, sync::Arc};
 
 /// Constant for the current block
-#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+#[derive(Clone, Default, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub struct BlockInfo {
+    #[serde(default)]
+    pub number:    u64,
     pub timestamp: u64,
 }
 
 /// Read only chain state
+///
+/// Reads may go to the network and can fail. A failed read is returned to the
+/// caller and is not cached.
+///
+/// Accounts are keyed by [`Address`]. [`StateSet`] and other stored state use
+/// the zero padded word the EVM uses, see [`Address::to_word`].
 #[allow(clippy::module_name_repetitions)]
-pub trait ChainState {
-    fn block(&self) -> BlockInfo;
-    fn nonce(&self, address: &U256) -> usize;
-    fn balance(&self, address: &U256) -> U256;
-    fn code(&self, address: &U256) -> Vec<u8>;
-    fn storage(&self, address: &U256, slot: &U256) -> U256;
+#[async_trait]
+pub trait ChainState: Send + Sync {
+    async fn block(&self) -> Result<BlockInfo, Error>;
+    async fn nonce(&self, address: &Address) -> Result<usize, Error>;
+    async fn balance(&self, address: &Address) -> Result<U256, Error>;
+    async fn code(&self, address: &Address) -> Result<Vec<u8>, Error>;
+    async fn storage(&self, address: &Address, slot: &U256) -> Result<U256, Error>;
+
+    /// Keccak256 hash of the code, [`EMPTY_CODE_HASH`] if there is none
+    ///
+    /// Backends that can should answer without transferring the code. The
+    /// default hashes the code.
+    async fn code_hash(&self, address: &Address) -> Result<U256, Error> {
+        Ok(keccak256(&self.code(address).await?))
+    }
+
+    /// Nonce, balance and code hash of an account
+    ///
+    /// Backends that can should fetch all of them in one round trip. The
+    /// default reads them concurrently.
+    async fn account(&self, address: &Address) -> Result<Account, Error> {
+        let (nonce, balance, code_hash) = future::try_join3(
+            self.nonce(address),
+            self.balance(address),
+            self.code_hash(address),
+        )
+        .await?;
+        Ok(Account {
+            nonce,
+            balance,
+            code_hash,
+        })
+    }
+
+    /// Read many storage slots, returned in the order of `keys`
+    ///
+    /// Backends that can should fetch all slots in as few round trips as
+    /// possible. The default reads all slots concurrently.
+    async fn storage_batch(&self, keys: &[(Address, U256)]) -> Result<Vec<U256>, Error> {
+        future::try_join_all(
+            keys.iter()
+                .map(|(address, slot)| self.storage(address, slot)),
+        )
+        .await
+    }
+
+    /// Hint that the given storage slots are about to be read
+    ///
+    /// Caching backends use this to fetch all of them at once before
+    /// execution starts. The keys can come from an access list or from a
+    /// previous execution of the same call.
+    async fn prefetch(&self, _keys: &[(Address, U256)]) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Share a chain state, for example a [`Cache`] between many [`Fork`]s
+#[async_trait]
+impl<T: ChainState + ?Sized> ChainState for Arc<T> {
+    async fn block(&self) -> Result<BlockInfo, Error> {
+        (**self).block().await
+    }
+
+    async fn nonce(&self, address: &Address) -> Result<usize, Error> {
+        (**self).nonce(address).await
+    }
+
+    async fn balance(&self, address: &Address) -> Result<U256, Error> {
+        (**self).balance(address).await
+    }
+
+    async fn code(&self, address: &Address) -> Result<Vec<u8>, Error> {
+        (**self).code(address).await
+    }
+
+    async fn code_hash(&self, address: &Address) -> Result<U256, Error> {
+        (**self).code_hash(address).await
+    }
+
+    async fn account(&self, address: &Address) -> Result<Account, Error> {
+        (**self).account(address).await
+    }
+
+    async fn storage(&self, address: &Address, slot: &U256) -> Result<U256, Error> {
+        (**self).storage(address, slot).await
+    }
+
+    async fn storage_batch(&self, keys: &[(Address, U256)]) -> Result<Vec<U256>, Error> {
+        (**self).storage_batch(keys).await
+    }
+
+    async fn prefetch(&self, keys: &[(Address, U256)]) -> Result<(), Error> {
+        (**self).prefetch(keys).await
+    }
+}
+
+#[async_trait]
+impl<'a, T: ChainState + ?Sized> ChainState for &'a T {
+    async fn block(&self) -> Result<BlockInfo, Error> {
+        (**self).block().await
+    }
+
+    async fn nonce(&self, address: &Address) -> Result<usize, Error> {
+        (**self).nonce(address).await
+    }
+
+    async fn balance(&self, address: &Address) -> Result<U256, Error> {
+        (**self).balance(address).await
+    }
+
+    async fn code(&self, address: &Address) -> Result<Vec<u8>, Error> {
+        (**self).code(address).await
+    }
+
+    async fn code_hash(&self, address: &Address) -> Result<U256, Error> {
+        (**self).code_hash(address).await
+    }
+
+    async fn account(&self, address: &Address) -> Result<Account, Error> {
+        (**self).account(address).await
+    }
+
+    async fn storage(&self, address: &Address, slot: &U256) -> Result<U256, Error> {
+        (**self).storage(address, slot).await
+    }
+
+    async fn storage_batch(&self, keys: &[(Address, U256)]) -> Result<Vec<U256>, Error> {
+        (**self).storage_batch(keys).await
+    }
+
+    async fn prefetch(&self, keys: &[(Address, U256)]) -> Result<(), Error> {
+        (**self).prefetch(keys).await
+    }
 }
 
 pub trait WriteableChainState: ChainState {
-    fn set_nonce(&mut self, address: &U256, nonce: usize);
-    fn set_balance(&mut self, address: &U256, balance: &U256);
-    fn set_code(&mut self, address: &U256, code: &[u8]);
-    fn set_storage(&mut self, address: &U256, slot: &U256, value: &U256);
+    fn set_nonce(&mut self, address: &Address, nonce: usize);
+    fn set_balance(&mut self, address: &Address, balance: &U256);
+    fn set_code(&mut self, address: &Address, code: &[u8]);
+    fn set_storage(&mut self, address: &Address, slot: &U256, value: &U256);
 }
 
 /// Create an empty chain
@@ -44,24 +202,101 @@ pub async fn new() -> AnyResult<Fork<Empty>> {
     Ok(Fork::from(Empty))
 }
 
+/// Block to fork from
+#[derive(Clone, Debug, PartialEq)]
+pub enum ForkBlock {
+    Number(u64),
+    Hash(U256),
+}
+
+/// Parses a decimal or `0x` prefixed hex number, or a 32 byte hash
+impl FromStr for ForkBlock {
+    type Err = anyhow::Error;
+
+    fn from_str(src: &str) -> AnyResult<Self> {
+        match src.strip_prefix("0x") {
+            Some(hex) if hex.len() == 64 => {
+                require!(
+                    hex.chars().all(|c| c.is_ascii_hexdigit()),
+                    anyhow!("Invalid block hash {}", src)
+                );
+                Ok(Self::Hash(U256::from_hex_str(hex)))
+            }
+            Some(hex) => Ok(Self::Number(u64::from_str_radix(hex, 16)?)),
+            None => Ok(Self::Number(src.parse()?)),
+        }
+    }
+}
+
 /// Create a fork from a JSON-RPC URL.
-pub async fn fork(url: &str) -> AnyResult<Fork<Cache<RpcChain>>> {
+///
+/// Pins to `block` or the latest block. A block hash is pinned by its number,
+/// so it should be on the canonical chain.
+///
+/// The cache is shared, so parallel overlays can be created with
+/// `Fork::from(chain.inner().clone())`.
+pub async fn fork(
+    url: &str,
+    policy: CachePolicy,
+    block: Option<ForkBlock>,
+) -> AnyResult<Fork<Arc<Cache<RpcChain>>>> {
     let client = rpc::client(url)
         .await
         .context("Creating RPC client to fork from")?;
 
-    // Pin to latest block
-    let latest = client
-        .get_block_by_number(BlockNumber::Latest, false)
-        .await
-        .map_err(|err| anyhow!("Error: {}", err))
-        .context("Fetching latest block number")?
-        .ok_or_else(|| anyhow!("Latest block not found"))?
-        .header
-        .number;
-    info!("Forking from block number {}", latest);
-    let block_number = BlockNumber::Number(latest);
+    // Pin to the requested or latest block
+    let number = match block {
+        Some(ForkBlock::Number(number)) => number,
+        Some(ForkBlock::Hash(hash)) => {
+            client
+                .get_block_by_hash(hash.clone(), false)
+                .await
+                .map_err(|err| anyhow!("Error: {}", err))
+                .context("Fetching block to fork from")?
+                .ok_or_else(|| anyhow!("Block {:?} not found", hash))?
+                .header
+                .number
+        }
+        None => {
+            client
+                .get_block_by_number(BlockNumber::Latest, false)
+                .await
+                .map_err(|err| anyhow!("Error: {}", err))
+                .context("Fetching latest block number")?
+                .ok_or_else(|| anyhow!("Latest block not found"))?
+                .header
+                .number
+        }
+    };
+    info!("Forking from block number {}", number);
+    let block_number = BlockNumber::Number(number);
 
     // Create monad stack
-    Ok(Fork::from(Cache::from(RpcChain::new(client, block_number))))
+    let batch = rpc::BatchClient::new(url);
+    let chain = RpcChain::new(client, batch, block_number);
+    Ok(Fork::from(Arc::new(Cache::with_policy(chain, policy))))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_parse_fork_block() {
+        assert_eq!(
+            "1234".parse::<ForkBlock>().unwrap(),
+            ForkBlock::Number(1234)
+        );
+        assert_eq!("0x10".parse::<ForkBlock>().unwrap(), ForkBlock::Number(16));
+        let hash = format!("0x{}", "0".repeat(63) + "1");
+        assert_eq!(
+            hash.parse::<ForkBlock>().unwrap(),
+            ForkBlock::Hash(U256::one())
+        );
+        assert!(format!("0x{}", "g".repeat(64))
+            .parse::<ForkBlock>()
+            .is_err());
+        assert!("latest".parse::<ForkBlock>().is_err());
+    }
 }
@@ -0,0 +1,54 @@
+//! State and block overrides for simulated calls
+//!
+//! Overrides are written to a throwaway [`Fork`] layer over the pinned state,
+//! so they never leak into the chain itself.
+
+use super::{BlockInfo, ChainState, Fork, WriteableChainState};
+use crate::{
+    chain::types::rpc::{BlockOverrides, StateOverride},
+    prelude::*,
+};
+
+/// Write all account overrides to `fork`
+pub fn apply_state<Base: ChainState>(
+    fork: &mut Fork<Base>,
+    overrides: &StateOverride,
+) -> AnyResult<()> {
+    for (address, account) in overrides {
+        require!(
+            account.state.is_none() || account.state_diff.is_none(),
+            anyhow!("Account {:?} has both state and stateDiff", address)
+        );
+        if let Some(balance) = &account.balance {
+            fork.set_balance(address, balance.as_ref());
+        }
+        if let Some(nonce) = &account.nonce {
+            fork.set_nonce(address, *nonce.as_ref() as usize);
+        }
+        if let Some(code) = &account.code {
+            fork.set_code(address, code.as_ref());
+        }
+        if let Some(state) = &account.state {
+            fork.clear_storage(address);
+            for (slot, value) in state {
+                fork.set_storage(address, slot, value);
+            }
+        }
+        if let Some(state_diff) = &account.state_diff {
+            for (slot, value) in state_diff {
+                fork.set_storage(address, slot, value);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Replace the overridden fields of `block`
+pub fn apply_block(block: &mut BlockInfo, overrides: &BlockOverrides) {
+    if let Some(number) = &overrides.number {
+        block.number = *number.as_ref();
+    }
+    if let Some(time) = &overrides.time {
+        block.timestamp = *time.as_ref();
+    }
+}
@@ -0,0 +1,193 @@
+//! Persist cached state to disk
+//!
+//! A [`StateSet`] is stored per chain id and block number, so a later fork of
+//! the same block can start with a warm cache. Forks of the latest block pin a
+//! new number on every run, so persistence needs a fixed fork block. Files are
+//! zstd compressed JSON prefixed by a magic number and a keccak256 checksum of
+//! the compressed payload.
+
+use super::{types::rpc::Bytes, BlockInfo, RpcChain, StateSet};
+use crate::{chain::types::rpc::BlockNumber, prelude::*, utils::keccak256};
+use bytesize::ByteSize;
+use std::{
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+const MAGIC: [u8; 8] = *b"SUTROST1";
+
+const HEADER_SIZE: usize = MAGIC.len() + 32;
+
+/// Compression is done once on shutdown, so favour size over speed.
+const ZSTD_LEVEL: i32 = 16;
+
+/// `StateSet` with tuple keys flattened, as JSON only supports string keys
+#[derive(Default, Serialize, Deserialize)]
+struct Snapshot {
+    block:    Option<BlockInfo>,
+    nonces:   Vec<(U256, usize)>,
+    balances: Vec<(U256, U256)>,
+    codes:    Vec<(U256, Bytes)>,
+    storages: Vec<(U256, U256, U256)>,
+}
+
+impl From<&StateSet> for Snapshot {
+    fn from(state: &StateSet) -> Self {
+        Self {
+            block:    state.block.clone(),
+            nonces:   state.nonces.iter().map(|(a, n)| (a.clone(), *n)).collect(),
+            balances: state.balances.clone().into_iter().collect(),
+            codes:    state
+                .codes
+                .iter()
+                .map(|(a, c)| (a.clone(), c.clone().into()))
+                .collect(),
+            storages: state
+                .storages
+                .iter()
+                .map(|((a, s), v)| (a.clone(), s.clone(), v.clone()))
+                .collect(),
+        }
+    }
+}
+
+impl From<Snapshot> for StateSet {
+    fn from(snapshot: Snapshot) -> Self {
+        Self {
+            block:    snapshot.block,
+            nonces:   snapshot.nonces.into_iter().collect(),
+            balances: snapshot.balances.into_iter().collect(),
+            codes:    snapshot
+                .codes
+                .into_iter()
+                .map(|(a, c)| (a, c.to_vec()))
+                .collect(),
+            storages: snapshot
+                .storages
+                .into_iter()
+                .map(|(a, s, v)| ((a, s), v))
+                .collect(),
+        }
+    }
+}
+
+/// File to persist the state of a fork in
+///
+/// Returns `None` if the fork is not pinned to a block number.
+pub async fn state_path(directory: &Path, chain: &RpcChain) -> AnyResult<Option<PathBuf>> {
+    let block_number = match chain.block_number() {
+        BlockNumber::Number(number) => number,
+        _ => return Ok(None),
+    };
+    let chain_id = chain.fetch_chain_id().await?;
+    Ok(Some(
+        directory.join(format!("{}-{}.state.zstd", chain_id, block_number)),
+    ))
+}
+
+/// Write state to a file, atomically replacing any previous file
+///
+/// Fails if the uncompressed state exceeds `limit`.
+pub fn save(path: &Path, state: &StateSet, limit: ByteSize) -> AnyResult<()> {
+    let json = serde_json::to_vec(&Snapshot::from(state))?;
+    require!(
+        json.len() as u64 <= limit.as_u64(),
+        anyhow!(
+            "State of {} exceeds limit of {}",
+            ByteSize(json.len() as u64),
+            limit
+        )
+    );
+    let payload = zstd::stream::encode_all(json.as_slice(), ZSTD_LEVEL)?;
+    let mut contents = Vec::with_capacity(HEADER_SIZE + payload.len());
+    contents.extend_from_slice(&MAGIC);
+    contents.extend_from_slice(&keccak256(&payload).to_bytes_be());
+    contents.extend_from_slice(&payload);
+
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)?;
+    }
+    let temp = path.with_extension("tmp");
+    fs::write(&temp, &contents).with_context(|| format!("Writing {}", temp.display()))?;
+    fs::rename(&temp, path)?;
+    info!(
+        "Saved {} of state to {}",
+        ByteSize(contents.len() as u64),
+        path.display()
+    );
+    Ok(())
+}
+
+/// Read state from a file
+///
+/// Fails if the file is corrupt or the state exceeds `limit`.
+pub fn load(path: &Path, limit: ByteSize) -> AnyResult<StateSet> {
+    let size = fs::metadata(path)?.len();
+    require!(
+        size <= limit.as_u64(),
+        anyhow!("File of {} exceeds limit of {}", ByteSize(size), limit)
+    );
+    let contents = fs::read(path).with_context(|| format!("Reading {}", path.display()))?;
+    require!(
+        contents.len() >= HEADER_SIZE && contents[..MAGIC.len()] == MAGIC,
+        anyhow!("Not a state file")
+    );
+    let (checksum, payload) = contents[MAGIC.len()..].split_at(32);
+    require!(
+        keccak256(payload).to_bytes_be() == checksum,
+        anyhow!("Checksum mismatch")
+    );
+
+    // Decompress at most `limit` bytes
+    let mut json = Vec::new();
+    let _size = zstd::stream::read::Decoder::new(payload)?
+        .take(limit.as_u64() + 1)
+        .read_to_end(&mut json)?;
+    require!(
+        json.len() as u64 <= limit.as_u64(),
+        anyhow!("State exceeds limit of {}", limit)
+    );
+    let snapshot: Snapshot = serde_json::from_slice(&json)?;
+    Ok(snapshot.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    fn state() -> StateSet {
+        let mut state = StateSet::default();
+        let _previous = state.nonces.insert(U256::one(), 3);
+        let _previous = state.codes.insert(U256::one(), vec![0x60, 0x00]);
+        let _previous = state
+            .storages
+            .insert((U256::one(), U256::zero()), U256::from(42_u64));
+        state
+    }
+
+    #[test]
+    fn test_save_load() {
+        let path = std::env::temp_dir().join(format!("sutro-state-{}.zstd", std::process::id()));
+        let limit = ByteSize::mib(1);
+        save(&path, &state(), limit).unwrap();
+        let loaded = load(&path, limit).unwrap();
+        assert_eq!(loaded.nonces, state().nonces);
+        assert_eq!(loaded.codes, state().codes);
+        assert_eq!(loaded.storages, state().storages);
+
+        // Limit
+        assert!(load(&path, ByteSize::b(10)).is_err());
+        assert!(save(&path, &state(), ByteSize::b(10)).is_err());
+
+        // Corruption
+        let mut contents = fs::read(&path).unwrap();
+        let last = contents.len() - 1;
+        contents[last] ^= 1;
+        fs::write(&path, &contents).unwrap();
+        assert!(load(&path, limit).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
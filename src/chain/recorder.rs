@@ -0,0 +1,227 @@
+//! Recording chain
+//!
+//! Takes a given read-only chain implementation and records every successful
+//! read in the order it was issued. The resulting [`Recording`] can be
+//! serialized and fed to [`Replay`](super::Replay) to re-execute without
+//! access to the base chain.
+//!
+//! Concurrent reads can complete in any order, so each read reserves its
+//! position in the log before it goes to the base. Reads that fail leave no
+//! entry.
+
+use super::{
+    types::{rpc::Bytes, Address},
+    Account, BlockInfo, ChainState, Error,
+};
+use crate::prelude::*;
+use async_trait::async_trait;
+use std::sync::{Mutex, MutexGuard};
+
+/// A single read and its result
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(tag = "read", rename_all = "camelCase")]
+pub enum Read {
+    Block {
+        result: BlockInfo,
+    },
+    Nonce {
+        address: Address,
+        result:  usize,
+    },
+    Balance {
+        address: Address,
+        result:  U256,
+    },
+    Code {
+        address: Address,
+        result:  Bytes,
+    },
+    CodeHash {
+        address: Address,
+        result:  U256,
+    },
+    Account {
+        address: Address,
+        result:  Account,
+    },
+    Storage {
+        address: Address,
+        slot:    U256,
+        result:  U256,
+    },
+}
+
+/// All reads of an execution in order
+#[derive(Clone, Default, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Recording {
+    pub reads: Vec<Read>,
+}
+
+#[derive(Debug)]
+pub struct Recorder<Base: ChainState> {
+    base:  Base,
+    /// Reads in the order they were issued, `None` until they complete
+    reads: Mutex<Vec<Option<Read>>>,
+}
+
+impl<Base: ChainState> Recorder<Base> {
+    pub fn inner(&self) -> &Base {
+        &self.base
+    }
+
+    pub fn recording(&self) -> Recording {
+        Recording {
+            reads: self.reads().iter().flatten().cloned().collect(),
+        }
+    }
+
+    pub fn into_recording(self) -> Recording {
+        let reads = self.reads.into_inner().expect("Recorder lock poisoned.");
+        Recording {
+            reads: reads.into_iter().flatten().collect(),
+        }
+    }
+
+    fn reads(&self) -> MutexGuard<Vec<Option<Read>>> {
+        self.reads.lock().expect("Recorder lock poisoned.")
+    }
+
+    /// Reserve `count` positions at the end of the log, returns the first
+    fn reserve(&self, count: usize) -> usize {
+        let mut reads = self.reads();
+        let position = reads.len();
+        reads.resize(position + count, None);
+        position
+    }
+
+    /// Fill the positions reserved at `position`
+    fn fill(&self, position: usize, completed: impl IntoIterator<Item = Read>) {
+        let mut reads = self.reads();
+        for (entry, read) in reads[position..].iter_mut().zip(completed) {
+            *entry = Some(read);
+        }
+    }
+}
+
+impl<Base: ChainState> From<Base> for Recorder<Base> {
+    fn from(base: Base) -> Self {
+        Self {
+            base,
+            reads: Mutex::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl<Base: ChainState> ChainState for Recorder<Base> {
+    async fn block(&self) -> Result<BlockInfo, Error> {
+        let position = self.reserve(1);
+        let result = self.base.block().await?;
+        self.fill(
+            position,
+            Some(Read::Block {
+                result: result.clone(),
+            }),
+        );
+        Ok(result)
+    }
+
+    async fn nonce(&self, address: &Address) -> Result<usize, Error> {
+        let position = self.reserve(1);
+        let result = self.base.nonce(address).await?;
+        self.fill(
+            position,
+            Some(Read::Nonce {
+                address: address.clone(),
+                result,
+            }),
+        );
+        Ok(result)
+    }
+
+    async fn balance(&self, address: &Address) -> Result<U256, Error> {
+        let position = self.reserve(1);
+        let result = self.base.balance(address).await?;
+        self.fill(
+            position,
+            Some(Read::Balance {
+                address: address.clone(),
+                result:  result.clone(),
+            }),
+        );
+        Ok(result)
+    }
+
+    async fn code(&self, address: &Address) -> Result<Vec<u8>, Error> {
+        let position = self.reserve(1);
+        let result = self.base.code(address).await?;
+        self.fill(
+            position,
+            Some(Read::Code {
+                address: address.clone(),
+                result:  result.clone().into(),
+            }),
+        );
+        Ok(result)
+    }
+
+    async fn code_hash(&self, address: &Address) -> Result<U256, Error> {
+        let position = self.reserve(1);
+        let result = self.base.code_hash(address).await?;
+        self.fill(
+            position,
+            Some(Read::CodeHash {
+                address: address.clone(),
+                result:  result.clone(),
+            }),
+        );
+        Ok(result)
+    }
+
+    async fn account(&self, address: &Address) -> Result<Account, Error> {
+        let position = self.reserve(1);
+        let result = self.base.account(address).await?;
+        self.fill(
+            position,
+            Some(Read::Account {
+                address: address.clone(),
+                result:  result.clone(),
+            }),
+        );
+        Ok(result)
+    }
+
+    async fn storage(&self, address: &Address, slot: &U256) -> Result<U256, Error> {
+        let position = self.reserve(1);
+        let result = self.base.storage(address, slot).await?;
+        self.fill(
+            position,
+            Some(Read::Storage {
+                address: address.clone(),
+                slot:    slot.clone(),
+                result:  result.clone(),
+            }),
+        );
+        Ok(result)
+    }
+
+    async fn storage_batch(&self, keys: &[(Address, U256)]) -> Result<Vec<U256>, Error> {
+        let position = self.reserve(keys.len());
+        let results = self.base.storage_batch(keys).await?;
+        self.fill(
+            position,
+            keys.iter().zip(&results).map(|((address, slot), result)| {
+                Read::Storage {
+                    address: address.clone(),
+                    slot:    slot.clone(),
+                    result:  result.clone(),
+                }
+            }),
+        );
+        Ok(results)
+    }
+
+    async fn prefetch(&self, keys: &[(Address, U256)]) -> Result<(), Error> {
+        self.base.prefetch(keys).await
+    }
+}
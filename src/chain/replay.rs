@@ -0,0 +1,256 @@
+//! Replayed chain
+//!
+//! Serves the reads of a [`Recording`] in the order they were recorded. Any
+//! read that does not match the next recorded read fails with
+//! [`Error::Diverged`].
+
+use super::{types::Address, Account, BlockInfo, ChainState, Error, Read, Recording};
+use crate::prelude::*;
+use async_trait::async_trait;
+use std::sync::Mutex;
+
+#[derive(Debug)]
+pub struct Replay {
+    reads:    Vec<Read>,
+    position: Mutex<usize>,
+}
+
+impl Replay {
+    /// Number of recorded reads not yet replayed
+    pub fn remaining(&self) -> usize {
+        self.reads.len() - *self.position.lock().expect("Replay lock poisoned.")
+    }
+
+    /// Return the next recorded read if `matches` accepts it
+    fn next<T, F>(&self, expected: &str, matches: F) -> Result<T, Error>
+    where
+        F: FnOnce(&Read) -> Option<T>,
+    {
+        let mut position = self.position.lock().expect("Replay lock poisoned.");
+        let read = self.reads.get(*position).ok_or_else(|| {
+            Error::Diverged(format!(
+                "expected {} after the last of {} recorded reads",
+                expected,
+                self.reads.len()
+            ))
+        })?;
+        let result = matches(read).ok_or_else(|| {
+            Error::Diverged(format!(
+                "expected {} at read {}, recorded {:?}",
+                expected, *position, read
+            ))
+        })?;
+        *position += 1;
+        Ok(result)
+    }
+}
+
+impl From<Recording> for Replay {
+    fn from(recording: Recording) -> Self {
+        Self {
+            reads:    recording.reads,
+            position: Mutex::new(0),
+        }
+    }
+}
+
+#[async_trait]
+impl ChainState for Replay {
+    async fn block(&self) -> Result<BlockInfo, Error> {
+        self.next("block", |read| {
+            match read {
+                Read::Block { result } => Some(result.clone()),
+                _ => None,
+            }
+        })
+    }
+
+    async fn nonce(&self, address: &Address) -> Result<usize, Error> {
+        self.next(&format!("nonce of {:?}", address), |read| {
+            match read {
+                Read::Nonce {
+                    address: recorded,
+                    result,
+                } if recorded == address => Some(*result),
+                _ => None,
+            }
+        })
+    }
+
+    async fn balance(&self, address: &Address) -> Result<U256, Error> {
+        self.next(&format!("balance of {:?}", address), |read| {
+            match read {
+                Read::Balance {
+                    address: recorded,
+                    result,
+                } if recorded == address => Some(result.clone()),
+                _ => None,
+            }
+        })
+    }
+
+    async fn code(&self, address: &Address) -> Result<Vec<u8>, Error> {
+        self.next(&format!("code of {:?}", address), |read| {
+            match read {
+                Read::Code {
+                    address: recorded,
+                    result,
+                } if recorded == address => Some(result.as_slice().to_vec()),
+                _ => None,
+            }
+        })
+    }
+
+    async fn code_hash(&self, address: &Address) -> Result<U256, Error> {
+        self.next(&format!("code hash of {:?}", address), |read| {
+            match read {
+                Read::CodeHash {
+                    address: recorded,
+                    result,
+                } if recorded == address => Some(result.clone()),
+                _ => None,
+            }
+        })
+    }
+
+    async fn account(&self, address: &Address) -> Result<Account, Error> {
+        self.next(&format!("account {:?}", address), |read| {
+            match read {
+                Read::Account {
+                    address: recorded,
+                    result,
+                } if recorded == address => Some(result.clone()),
+                _ => None,
+            }
+        })
+    }
+
+    async fn storage(&self, address: &Address, slot: &U256) -> Result<U256, Error> {
+        self.next(
+            &format!("storage slot {:?} of {:?}", slot, address),
+            |read| {
+                match read {
+                    Read::Storage {
+                        address: recorded_address,
+                        slot: recorded_slot,
+                        result,
+                    } if recorded_address == address && recorded_slot == slot => {
+                        Some(result.clone())
+                    }
+                    _ => None,
+                }
+            },
+        )
+    }
+
+    /// Replayed in order, as they were recorded.
+    async fn storage_batch(&self, keys: &[(Address, U256)]) -> Result<Vec<U256>, Error> {
+        let mut results = Vec::with_capacity(keys.len());
+        for (address, slot) in keys {
+            results.push(self.storage(address, slot).await?);
+        }
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        chain::{Empty, Fork, Recorder, WriteableChainState},
+        test::prelude::assert_eq,
+    };
+
+    #[tokio::test]
+    async fn test_record_replay() {
+        let (address, slot) = (Address::from_word(&U256::one()), U256::zero());
+        let mut chain = Fork::from(Empty);
+        chain.set_storage(&address, &slot, &U256::from(42_u64));
+        chain.set_code(&address, &[0x60, 0x00]);
+
+        let recorder = Recorder::from(chain);
+        assert_eq!(recorder.code(&address).await, Ok(vec![0x60, 0x00]));
+        assert_eq!(
+            recorder.storage(&address, &slot).await,
+            Ok(U256::from(42_u64))
+        );
+        let recording = recorder.into_recording();
+
+        // Recordings survive serialization
+        let json = serde_json::to_string(&recording).unwrap();
+        let recording: Recording = serde_json::from_str(&json).unwrap();
+
+        let replay = Replay::from(recording.clone());
+        assert_eq!(replay.code(&address).await, Ok(vec![0x60, 0x00]));
+        assert_eq!(
+            replay.storage(&address, &slot).await,
+            Ok(U256::from(42_u64))
+        );
+        assert_eq!(replay.remaining(), 0);
+        assert!(replay.balance(&address).await.is_err());
+
+        // Diverging reads fail
+        let replay = Replay::from(recording);
+        assert!(replay.storage(&address, &slot).await.is_err());
+    }
+
+    /// Storage reads complete after all other reads issued with them
+    struct SlowStorage;
+
+    #[async_trait]
+    impl ChainState for SlowStorage {
+        async fn block(&self) -> Result<BlockInfo, Error> {
+            Empty.block().await
+        }
+
+        async fn nonce(&self, address: &Address) -> Result<usize, Error> {
+            Empty.nonce(address).await
+        }
+
+        async fn balance(&self, address: &Address) -> Result<U256, Error> {
+            Empty.balance(address).await
+        }
+
+        async fn code(&self, address: &Address) -> Result<Vec<u8>, Error> {
+            Empty.code(address).await
+        }
+
+        async fn storage(&self, address: &Address, slot: &U256) -> Result<U256, Error> {
+            tokio::task::yield_now().await;
+            Empty.storage(address, slot).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_reads() {
+        let address = Address::from_word(&U256::one());
+        let recorder = Recorder::from(SlowStorage);
+        let (storage, account) = future::join(
+            recorder.storage(&address, &U256::zero()),
+            recorder.account(&address),
+        )
+        .await;
+        assert_eq!(
+            (storage, account),
+            (Ok(U256::zero()), Ok(Account::default()))
+        );
+
+        // Reads are recorded in the order they were issued
+        let recording = recorder.into_recording();
+        assert!(matches!(recording.reads[..], [
+            Read::Storage { .. },
+            Read::Account { .. }
+        ]));
+        let replay = Replay::from(recording);
+        let (storage, account) = future::join(
+            replay.storage(&address, &U256::zero()),
+            replay.account(&address),
+        )
+        .await;
+        assert_eq!(
+            (storage, account),
+            (Ok(U256::zero()), Ok(Account::default()))
+        );
+        assert_eq!(replay.remaining(), 0);
+    }
+}
@@ -1,53 +1,228 @@
-use super::{BlockInfo, ChainState};
-use crate::{chain::types::rpc::BlockNumber, prelude::*, rpc::EthereumRpcClient};
-use futures::executor::block_on;
+//! Chain backed by a JSON-RPC node
+//!
+//! Reads all state from an upstream node, pinned to a block number. The pinned
+//! block only moves when following the upstream head, see [`Follower`].
+//!
+//! [`Follower`]: super::Follower
+
+use super::{Account, BlockInfo, ChainState, Error};
+use crate::{
+    chain::types::{
+        rpc::{BlockNumber, Hex, HexFull, PrestateTrace},
+        Address, ConciseBlock,
+    },
+    prelude::*,
+    rpc::{BatchClient, EthereumRpcClient},
+};
+use async_trait::async_trait;
+use serde_json::json;
+use std::sync::RwLock;
+
+/// Maximum number of calls in a single JSON-RPC batch request
+const BATCH_SIZE: usize = 100;
 
 pub struct RpcChain {
     client:       EthereumRpcClient,
-    block_number: BlockNumber,
+    batch:        BatchClient,
+    block_number: RwLock<BlockNumber>,
 }
 
 impl RpcChain {
-    pub fn new(client: EthereumRpcClient, block_number: BlockNumber) -> Self {
+    pub fn new(client: EthereumRpcClient, batch: BatchClient, block_number: BlockNumber) -> Self {
         Self {
             client,
-            block_number,
+            batch,
+            block_number: RwLock::new(block_number),
         }
     }
+
+    pub fn block_number(&self) -> BlockNumber {
+        *self
+            .block_number
+            .read()
+            .expect("Block number lock poisoned.")
+    }
+
+    /// Read all further state at `block_number`
+    ///
+    /// Values already read by a cache on top are not refetched.
+    pub fn set_block_number(&self, block_number: BlockNumber) {
+        *self
+            .block_number
+            .write()
+            .expect("Block number lock poisoned.") = block_number;
+    }
+
+    pub async fn fetch_chain_id(&self) -> AnyResult<u64> {
+        let chain_id = self
+            .client
+            .chain_id()
+            .await
+            .map_err(|err| anyhow!("Error: {}", err))
+            .context("Fetching chain id")?;
+        Ok(chain_id.into_inner())
+    }
+
+    pub async fn fetch_block(&self) -> AnyResult<BlockInfo> {
+        let block = self
+            .client
+            .get_block_by_number(self.block_number(), false)
+            .await
+            .map_err(|err| anyhow!("Error: {}", err))
+            .context("Fetching block")?
+            .ok_or_else(|| anyhow!("Block {:?} not found", self.block_number()))?;
+        Ok(BlockInfo {
+            number:    block.header.number,
+            timestamp: block.header.timestamp,
+        })
+    }
+
+    pub async fn fetch_nonce(&self, address: &Address) -> AnyResult<usize> {
+        let nonce = self
+            .client
+            .get_nonce(address.clone(), self.block_number())
+            .await
+            .map_err(|err| anyhow!("Error: {}", err))
+            .with_context(|| format!("Fetching nonce of {:?}", address))?;
+        Ok(nonce.into_inner() as usize)
+    }
+
+    pub async fn fetch_balance(&self, address: &Address) -> AnyResult<U256> {
+        let balance = self
+            .client
+            .get_balance(address.clone(), self.block_number())
+            .await
+            .map_err(|err| anyhow!("Error: {}", err))
+            .with_context(|| format!("Fetching balance of {:?}", address))?;
+        Ok(balance.into_inner())
+    }
+
+    pub async fn fetch_code(&self, address: &Address) -> AnyResult<Vec<u8>> {
+        let code = self
+            .client
+            .get_code(address.clone(), self.block_number())
+            .await
+            .map_err(|err| anyhow!("Error: {}", err))
+            .with_context(|| format!("Fetching code of {:?}", address))?;
+        Ok(code.to_vec())
+    }
+
+    /// Fetch nonce, balance and code hash in one call using `eth_getProof`
+    pub async fn fetch_account(&self, address: &Address) -> AnyResult<Account> {
+        let proof = self
+            .client
+            .get_proof(address.clone(), Vec::new(), self.block_number())
+            .await
+            .map_err(|err| anyhow!("Error: {}", err))
+            .with_context(|| format!("Fetching account {:?}", address))?;
+        Ok(Account {
+            nonce:     proof.nonce.into_inner() as usize,
+            balance:   proof.balance.into_inner(),
+            code_hash: proof.code_hash,
+        })
+    }
+
+    pub async fn fetch_storage(&self, address: &Address, slot: &U256) -> AnyResult<U256> {
+        let value = self
+            .client
+            .get_storage_at(address.clone(), slot.clone().into(), self.block_number())
+            .await
+            .map_err(|err| anyhow!("Error: {}", err))
+            .with_context(|| format!("Fetching storage slot {:?} of {:?}", slot, address))?;
+        Ok(value.into_inner())
+    }
+
+    /// Fetch storage slots in batches, all batches concurrently
+    pub async fn fetch_storage_batch(&self, keys: &[(Address, U256)]) -> AnyResult<Vec<U256>> {
+        let block_number = serde_json::to_value(self.block_number())?;
+        let batches = keys.chunks(BATCH_SIZE).map(|chunk| {
+            let params = chunk
+                .iter()
+                .map(|(address, slot)| {
+                    Ok(vec![
+                        serde_json::to_value(address)?,
+                        serde_json::to_value(Hex::from(slot.clone()))?,
+                        block_number.clone(),
+                    ])
+                })
+                .collect::<AnyResult<Vec<_>>>();
+            async move {
+                self.batch
+                    .call::<HexFull<U256>>("eth_getStorageAt", params?)
+                    .await
+            }
+        });
+        let values = future::try_join_all(batches)
+            .await
+            .with_context(|| format!("Fetching {} storage slots", keys.len()))?;
+        Ok(values
+            .into_iter()
+            .flatten()
+            .map(HexFull::into_inner)
+            .collect())
+    }
+
+    /// Fetch a block with only transaction hashes, `None` if it does not exist
+    pub async fn fetch_header(&self, block_number: BlockNumber) -> AnyResult<Option<ConciseBlock>> {
+        let params = vec![serde_json::to_value(block_number)?, json!(false)];
+        let mut blocks = self
+            .batch
+            .call::<Option<ConciseBlock>>("eth_getBlockByNumber", vec![params])
+            .await
+            .with_context(|| format!("Fetching block {:?}", block_number))?;
+        Ok(blocks.pop().flatten())
+    }
+
+    /// Fetch the state changes of every transaction in a block
+    pub async fn fetch_prestate_traces(&self, block_number: u64) -> AnyResult<Vec<PrestateTrace>> {
+        let params = vec![
+            serde_json::to_value(BlockNumber::Number(block_number))?,
+            json!({ "tracer": "prestateTracer", "tracerConfig": { "diffMode": true } }),
+        ];
+        let mut traces = self
+            .batch
+            .call::<Vec<PrestateTrace>>("debug_traceBlockByNumber", vec![params])
+            .await
+            .with_context(|| format!("Tracing block {}", block_number))?;
+        Ok(traces.pop().unwrap_or_default())
+    }
 }
 
-// TODO: Async & Result ?
+#[async_trait]
 impl ChainState for RpcChain {
-    fn block(&self) -> BlockInfo {
-        todo!();
-        // let result: AnyResult<BlockHeader> = block_on(async {
-        //     Ok(self
-        //         .client
-        //         .get_block_by_number(BlockNumber::Latest, false)
-        //         .await
-        //         .map_err(|err| anyhow!("Error: {}", err))
-        //         .context("Fetching latest block number")?
-        //         .ok_or_else(|| anyhow!("Latest block not found"))?)
-        // });
-        // let block = result.unwrap();
-        // BlockInfo {
-        //     timestamp: block.timestamp.into_inner(),
-        // }
+    async fn block(&self) -> Result<BlockInfo, Error> {
+        self.fetch_block().await.map_err(Error::upstream)
+    }
+
+    async fn nonce(&self, address: &Address) -> Result<usize, Error> {
+        self.fetch_nonce(address).await.map_err(Error::upstream)
+    }
+
+    async fn balance(&self, address: &Address) -> Result<U256, Error> {
+        self.fetch_balance(address).await.map_err(Error::upstream)
+    }
+
+    async fn code(&self, address: &Address) -> Result<Vec<u8>, Error> {
+        self.fetch_code(address).await.map_err(Error::upstream)
     }
 
-    fn nonce(&self, _address: &U256) -> usize {
-        todo!()
+    async fn code_hash(&self, address: &Address) -> Result<U256, Error> {
+        Ok(self.account(address).await?.code_hash)
     }
 
-    fn balance(&self, _address: &U256) -> U256 {
-        todo!()
+    async fn account(&self, address: &Address) -> Result<Account, Error> {
+        self.fetch_account(address).await.map_err(Error::upstream)
     }
 
-    fn code(&self, _address: &U256) -> Vec<u8> {
-        todo!()
+    async fn storage(&self, address: &Address, slot: &U256) -> Result<U256, Error> {
+        self.fetch_storage(address, slot)
+            .await
+            .map_err(Error::upstream)
     }
 
-    fn storage(&self, _address: &U256, _slot: &U256) -> U256 {
-        todo!()
+    async fn storage_batch(&self, keys: &[(Address, U256)]) -> Result<Vec<U256>, Error> {
+        self.fetch_storage_batch(keys)
+            .await
+            .map_err(Error::upstream)
     }
 }
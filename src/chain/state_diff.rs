@@ -0,0 +1,220 @@
+//! Differences between chain states
+//!
+//! A [`StateDiff`] lists every value a [`StateSet`] changes compared to a base
+//! chain, with the value before and after. Writes that leave a value as it
+//! was are not listed.
+
+use super::{types::Address, ChainState, Error, Fork, StateSet, WriteableChainState};
+use crate::prelude::*;
+use std::collections::HashMap;
+
+/// A changed value
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Delta<T> {
+    pub before: T,
+    pub after:  T,
+}
+
+/// Values changed by an overlay, keyed by address
+#[derive(Clone, Default, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateDiff {
+    pub nonces:   HashMap<U256, Delta<usize>>,
+    pub balances: HashMap<U256, Delta<U256>>,
+    pub codes:    HashMap<U256, Delta<Vec<u8>>>,
+    pub storages: HashMap<U256, HashMap<U256, Delta<U256>>>,
+}
+
+impl StateDiff {
+    pub fn is_empty(&self) -> bool {
+        self.nonces.is_empty()
+            && self.balances.is_empty()
+            && self.codes.is_empty()
+            && self.storages.is_empty()
+    }
+
+    /// Write all after values to `chain`
+    pub fn apply<C: WriteableChainState + ?Sized>(&self, chain: &mut C) {
+        for (address, delta) in &self.nonces {
+            chain.set_nonce(&Address::from_word(address), delta.after);
+        }
+        for (address, delta) in &self.balances {
+            chain.set_balance(&Address::from_word(address), &delta.after);
+        }
+        for (address, delta) in &self.codes {
+            chain.set_code(&Address::from_word(address), &delta.after);
+        }
+        for (address, slots) in &self.storages {
+            let address = Address::from_word(address);
+            for (slot, delta) in slots {
+                chain.set_storage(&address, slot, &delta.after);
+            }
+        }
+    }
+
+    /// Diff that undoes this one
+    pub fn inverse(&self) -> Self {
+        Self {
+            nonces:   inverse_map(&self.nonces),
+            balances: inverse_map(&self.balances),
+            codes:    inverse_map(&self.codes),
+            storages: self
+                .storages
+                .iter()
+                .map(|(address, slots)| (address.clone(), inverse_map(slots)))
+                .collect(),
+        }
+    }
+
+    /// Combine with a diff of a later state
+    ///
+    /// Keeps the before values of `self` and the after values of `later`.
+    /// Values changed back to what they were are dropped.
+    pub fn merge(&mut self, later: Self) {
+        merge_map(&mut self.nonces, later.nonces);
+        merge_map(&mut self.balances, later.balances);
+        merge_map(&mut self.codes, later.codes);
+        for (address, slots) in later.storages {
+            let existing = self.storages.entry(address.clone()).or_default();
+            merge_map(existing, slots);
+            if existing.is_empty() {
+                let _removed = self.storages.remove(&address);
+            }
+        }
+    }
+}
+
+fn inverse_map<T: Clone>(map: &HashMap<U256, Delta<T>>) -> HashMap<U256, Delta<T>> {
+    map.iter()
+        .map(|(key, delta)| {
+            (key.clone(), Delta {
+                before: delta.after.clone(),
+                after:  delta.before.clone(),
+            })
+        })
+        .collect()
+}
+
+fn merge_map<T: PartialEq>(map: &mut HashMap<U256, Delta<T>>, later: HashMap<U256, Delta<T>>) {
+    for (key, delta) in later {
+        let unchanged = match map.get_mut(&key) {
+            Some(existing) => {
+                existing.after = delta.after;
+                existing.before == existing.after
+            }
+            None => {
+                let _previous = map.insert(key.clone(), delta);
+                false
+            }
+        };
+        if unchanged {
+            let _removed = map.remove(&key);
+        }
+    }
+}
+
+fn delta<T: Clone + PartialEq>(before: T, after: &T) -> Option<Delta<T>> {
+    if before == *after {
+        None
+    } else {
+        Some(Delta {
+            before,
+            after: after.clone(),
+        })
+    }
+}
+
+impl StateSet {
+    /// Compare all values in this set to those in `base`
+    pub async fn diff<B: ChainState + ?Sized>(&self, base: &B) -> Result<StateDiff, Error> {
+        let mut result = StateDiff::default();
+        for (address, nonce) in &self.nonces {
+            if let Some(delta) = delta(base.nonce(&Address::from_word(address)).await?, nonce) {
+                let _previous = result.nonces.insert(address.clone(), delta);
+            }
+        }
+        for (address, balance) in &self.balances {
+            if let Some(delta) = delta(base.balance(&Address::from_word(address)).await?, balance) {
+                let _previous = result.balances.insert(address.clone(), delta);
+            }
+        }
+        for (address, code) in &self.codes {
+            if let Some(delta) = delta(base.code(&Address::from_word(address)).await?, code) {
+                let _previous = result.codes.insert(address.clone(), delta);
+            }
+        }
+        let keys = self.storages.keys().cloned().collect::<Vec<_>>();
+        let reads = keys
+            .iter()
+            .map(|(address, slot)| (Address::from_word(address), slot.clone()))
+            .collect::<Vec<_>>();
+        let values = base.storage_batch(&reads).await?;
+        for ((address, slot), before) in keys.into_iter().zip(values) {
+            if let Some(delta) = delta(before, &self.storages[&(address.clone(), slot.clone())]) {
+                let _previous = result
+                    .storages
+                    .entry(address)
+                    .or_default()
+                    .insert(slot, delta);
+            }
+        }
+        Ok(result)
+    }
+}
+
+impl<Base: ChainState> Fork<Base> {
+    /// Everything this fork changed compared to its base
+    pub async fn diff(&self) -> Result<StateDiff, Error> {
+        self.writes().state.diff(self.inner()).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{chain::Empty, test::prelude::assert_eq};
+
+    #[tokio::test]
+    async fn test_diff_and_merge() {
+        let (word, slot) = (U256::one(), U256::from(2_u64));
+        let address = Address::from_word(&word);
+        let mut base = Fork::from(Empty);
+        base.set_balance(&address, &U256::from(10_u64));
+        base.set_storage(&address, &slot, &U256::from(7_u64));
+
+        let mut first: Fork<Fork<Empty>> = Fork::from(base.clone());
+        first.set_balance(&address, &U256::from(20_u64));
+        first.set_storage(&address, &slot, &U256::from(7_u64));
+        first.set_nonce(&address, 1);
+        let diff = first.diff().await.unwrap();
+        assert_eq!(diff.balances[&word], Delta {
+            before: U256::from(10_u64),
+            after:  U256::from(20_u64),
+        });
+        assert_eq!(diff.nonces[&word], Delta {
+            before: 0,
+            after:  1,
+        });
+        // Unchanged writes are not listed
+        assert!(diff.storages.is_empty());
+
+        let mut second = Fork::from(Empty);
+        diff.apply(&mut second);
+        assert_eq!(second.balance(&address).await, Ok(U256::from(20_u64)));
+
+        let mut merged = diff.clone();
+        merged.merge(StateDiff {
+            balances: vec![(word.clone(), Delta {
+                before: U256::from(20_u64),
+                after:  U256::from(10_u64),
+            })]
+            .into_iter()
+            .collect(),
+            ..StateDiff::default()
+        });
+        assert!(merged.balances.is_empty());
+        assert_eq!(merged.nonces, diff.nonces);
+
+        let json = serde_json::to_string(&merged).unwrap();
+        assert_eq!(serde_json::from_str::<StateDiff>(&json).unwrap(), merged);
+    }
+}
@@ -0,0 +1,106 @@
+//! Ordered index of accounts and storage slots
+//!
+//! Accounts and slots are ordered by the keccak hash of their address or slot,
+//! which is their order in the state trie. This is the order in which
+//! `debug_accountRange` and `debug_storageRangeAt` paginate.
+
+use super::{types::Address, StateSet};
+use crate::{prelude::*, utils::keccak256};
+use std::collections::{BTreeMap, HashMap};
+
+/// Maps hashed keys back to their preimages, in hash order
+#[derive(Clone, Debug, Default)]
+pub struct StateIndex {
+    accounts: BTreeMap<U256, U256>,
+    storages: HashMap<U256, BTreeMap<U256, U256>>,
+}
+
+impl StateIndex {
+    pub fn insert_account(&mut self, address: &U256) {
+        let _previous = self.accounts.insert(hash_address(address), address.clone());
+    }
+
+    pub fn insert_slot(&mut self, address: &U256, slot: &U256) {
+        self.insert_account(address);
+        let _previous = self
+            .storages
+            .entry(address.clone())
+            .or_default()
+            .insert(keccak256(&slot.to_bytes_be()), slot.clone());
+    }
+
+    /// Index every account and slot written in `state`
+    pub fn apply(&mut self, state: &StateSet) {
+        let addresses = state
+            .nonces
+            .keys()
+            .chain(state.balances.keys())
+            .chain(state.codes.keys());
+        for address in addresses {
+            self.insert_account(address);
+        }
+        for (address, slot) in state.storages.keys() {
+            self.insert_slot(address, slot);
+        }
+    }
+
+    /// Hashes and addresses of accounts, starting at hash `start`
+    pub fn accounts_from<'a>(&'a self, start: &U256) -> impl Iterator<Item = (&'a U256, &'a U256)> {
+        self.accounts.range(start.clone()..)
+    }
+
+    /// Hashes and slots of storage of `address`, starting at hash `start`
+    pub fn slots_from<'a>(
+        &'a self,
+        address: &U256,
+        start: &U256,
+    ) -> impl Iterator<Item = (&'a U256, &'a U256)> {
+        let start = start.clone();
+        self.storages
+            .get(address)
+            .into_iter()
+            .flat_map(move |slots| slots.range(start.clone()..))
+    }
+}
+
+/// Addresses are hashed as their 20 byte big-endian representation
+fn hash_address(address: &U256) -> U256 {
+    keccak256(Address::from_word(address).as_slice())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_pagination() {
+        let mut state = StateSet::default();
+        for i in 0..10_u64 {
+            let _previous = state.balances.insert(U256::from(i), U256::one());
+            let _previous = state
+                .storages
+                .insert((U256::one(), U256::from(i)), U256::one());
+        }
+        let mut index = StateIndex::default();
+        index.apply(&state);
+
+        let hashes = index
+            .accounts_from(&U256::zero())
+            .map(|(hash, _)| hash.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(hashes.len(), 10);
+        assert!(hashes.windows(2).all(|pair| pair[0] < pair[1]));
+
+        // Resume from the hash of the fifth account
+        let rest = index.accounts_from(&hashes[4]).count();
+        assert_eq!(rest, 6);
+
+        let (hash, slot) = index
+            .slots_from(&U256::one(), &U256::zero())
+            .next()
+            .unwrap();
+        assert_eq!(*hash, keccak256(&slot.to_bytes_be()));
+        assert_eq!(index.slots_from(&U256::from(2), &U256::zero()).count(), 0);
+    }
+}
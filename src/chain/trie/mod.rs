@@ -0,0 +1,468 @@
+//! Merkle Patricia Trie
+//!
+//! In-memory trie that caches the encoding of every node. Modifications only
+//! invalidate the nodes on the modified path, so recomputing the root after a
+//! few changes only rehashes those paths. [`TrieHash`](crate::utils::TrieHash)
+//! builds a trie from scratch instead, which is fine for one-off list roots.
+//!
+//! See <https://eth.wiki/fundamentals/patricia-tree>
+
+mod proof;
+mod state;
+
+pub use self::state::StateTrie;
+
+use crate::{prelude::*, utils::keccak256};
+use std::mem;
+
+/// Root of the empty trie, `keccak256(rlp(""))`
+pub const EMPTY_ROOT: U256 =
+    u256h!("56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421");
+
+#[derive(Clone, Debug, Default)]
+pub struct Trie {
+    root: Cached,
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Empty,
+    Leaf(Vec<u8>, Vec<u8>),
+    Extension(Vec<u8>, Box<Cached>),
+    Branch(Box<[Cached; 16]>, Option<Vec<u8>>),
+}
+
+impl Default for Node {
+    fn default() -> Self {
+        Self::Empty
+    }
+}
+
+/// A node with its reference: the encoding if shorter than 32 bytes, else
+/// the encoded hash of the encoding. `None` if modified since last hashed.
+#[derive(Clone, Debug, Default)]
+struct Cached {
+    node:      Node,
+    reference: Option<Vec<u8>>,
+}
+
+impl Cached {
+    fn new(node: Node) -> Self {
+        Self {
+            node,
+            reference: None,
+        }
+    }
+}
+
+impl Trie {
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        let path = nibbles(key);
+        let mut path = path.as_slice();
+        let mut node = &self.root.node;
+        loop {
+            match node {
+                Node::Empty => return None,
+                Node::Leaf(leaf, value) => {
+                    return if leaf.as_slice() == path {
+                        Some(value)
+                    } else {
+                        None
+                    };
+                }
+                Node::Extension(prefix, child) => {
+                    path = path.strip_prefix(prefix.as_slice())?;
+                    node = &child.node;
+                }
+                Node::Branch(children, value) => {
+                    match path.split_first() {
+                        None => return value.as_deref(),
+                        Some((nibble, rest)) => {
+                            path = rest;
+                            node = &children[*nibble as usize].node;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Insert a value. An empty value removes the key.
+    pub fn insert(&mut self, key: &[u8], value: Vec<u8>) {
+        if value.is_empty() {
+            self.remove(key);
+        } else {
+            let _changed = insert(&mut self.root, &nibbles(key), value);
+        }
+    }
+
+    pub fn remove(&mut self, key: &[u8]) {
+        let _changed = remove(&mut self.root, &nibbles(key));
+    }
+
+    pub fn root(&mut self) -> U256 {
+        let root = reference(&mut self.root);
+        if root.len() < 32 {
+            keccak256(root)
+        } else {
+            // Skip the RLP string prefix
+            to_u256(&root[1..])
+        }
+    }
+
+    /// The encoding of every node on the path to `key`, starting at the root
+    ///
+    /// This is a proof of the value of `key`, or of its absence. Nodes shorter
+    /// than 32 bytes are embedded in their parent and not listed separately.
+    pub fn proof(&mut self, key: &[u8]) -> Vec<Vec<u8>> {
+        // Make sure all references are computed
+        let _root = self.root();
+        let path = nibbles(key);
+        let mut path = path.as_slice();
+        let mut result = Vec::new();
+        let mut node = &self.root.node;
+        loop {
+            let encoded = encode_cached(node);
+            if result.is_empty() || encoded.len() >= 32 {
+                result.push(encoded);
+            }
+            match node {
+                Node::Empty | Node::Leaf(..) => break,
+                Node::Extension(prefix, child) => {
+                    match path.strip_prefix(prefix.as_slice()) {
+                        Some(rest) => path = rest,
+                        None => break,
+                    }
+                    node = &child.node;
+                }
+                Node::Branch(children, _) => {
+                    match path.split_first() {
+                        Some((nibble, rest)) => {
+                            path = rest;
+                            node = &children[*nibble as usize].node;
+                        }
+                        None => break,
+                    }
+                }
+            }
+        }
+        result
+    }
+}
+
+/// Split bytes into nibbles, most significant first
+fn nibbles(bytes: &[u8]) -> Vec<u8> {
+    bytes
+        .iter()
+        .flat_map(|byte| vec![byte >> 4, byte & 0xf])
+        .collect()
+}
+
+fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+fn empty_children() -> Box<[Cached; 16]> {
+    Box::new(Default::default())
+}
+
+/// Insert `value` at `path` below `slot`. Returns `false` if unchanged.
+fn insert(slot: &mut Cached, path: &[u8], value: Vec<u8>) -> bool {
+    let changed = match &mut slot.node {
+        Node::Empty => {
+            slot.node = Node::Leaf(path.to_vec(), value);
+            true
+        }
+        Node::Leaf(leaf, existing) if leaf.as_slice() == path => {
+            if *existing == value {
+                false
+            } else {
+                *existing = value;
+                true
+            }
+        }
+        Node::Leaf(leaf, _) => {
+            // Replace by a branch holding both values
+            let common = common_prefix(leaf, path);
+            let (leaf, existing) = match mem::take(&mut slot.node) {
+                Node::Leaf(leaf, existing) => (leaf, existing),
+                _ => unreachable!(),
+            };
+            let mut branch = Cached::new(Node::Branch(empty_children(), None));
+            let _changed = insert(&mut branch, &leaf[common..], existing);
+            let _changed = insert(&mut branch, &path[common..], value);
+            slot.node = extend(&path[..common], branch).node;
+            true
+        }
+        Node::Extension(prefix, child) if path.starts_with(prefix.as_slice()) => {
+            let prefix = prefix.len();
+            insert(child, &path[prefix..], value)
+        }
+        Node::Extension(..) => {
+            // Split the extension at the first differing nibble
+            let (prefix, child) = match mem::take(&mut slot.node) {
+                Node::Extension(prefix, child) => (prefix, child),
+                _ => unreachable!(),
+            };
+            let common = common_prefix(&prefix, path);
+            let mut children = empty_children();
+            children[prefix[common] as usize] = extend(&prefix[common + 1..], *child);
+            let mut branch = Cached::new(Node::Branch(children, None));
+            let _changed = insert(&mut branch, &path[common..], value);
+            slot.node = extend(&path[..common], branch).node;
+            true
+        }
+        Node::Branch(_, existing) if path.is_empty() => {
+            if existing.as_ref() == Some(&value) {
+                false
+            } else {
+                *existing = Some(value);
+                true
+            }
+        }
+        Node::Branch(children, _) => insert(&mut children[path[0] as usize], &path[1..], value),
+    };
+    if changed {
+        slot.reference = None;
+    }
+    changed
+}
+
+/// Remove the value at `path` below `slot`. Returns `false` if unchanged.
+fn remove(slot: &mut Cached, path: &[u8]) -> bool {
+    let changed = match &mut slot.node {
+        Node::Empty => false,
+        Node::Leaf(leaf, _) => {
+            if leaf.as_slice() == path {
+                slot.node = Node::Empty;
+                true
+            } else {
+                false
+            }
+        }
+        Node::Extension(prefix, child) => {
+            match path.strip_prefix(prefix.as_slice()) {
+                Some(rest) => remove(child, rest),
+                None => false,
+            }
+        }
+        Node::Branch(_, value) if path.is_empty() => value.take().is_some(),
+        Node::Branch(children, _) => remove(&mut children[path[0] as usize], &path[1..]),
+    };
+    if changed {
+        slot.reference = None;
+        normalize(slot);
+    }
+    changed
+}
+
+/// Prefix `child` with an extension path, merging with the child if possible
+fn extend(prefix: &[u8], child: Cached) -> Cached {
+    if prefix.is_empty() {
+        return child;
+    }
+    Cached::new(match child.node {
+        Node::Empty => Node::Empty,
+        Node::Leaf(path, value) => Node::Leaf([prefix, &path].concat(), value),
+        Node::Extension(path, grandchild) => Node::Extension([prefix, &path].concat(), grandchild),
+        node @ Node::Branch(..) => {
+            Node::Extension(
+                prefix.to_vec(),
+                Box::new(Cached {
+                    node,
+                    reference: child.reference,
+                }),
+            )
+        }
+    })
+}
+
+/// Restore the canonical form after a removal
+fn normalize(slot: &mut Cached) {
+    slot.node = match mem::take(&mut slot.node) {
+        Node::Extension(prefix, child) => extend(&prefix, *child).node,
+        Node::Branch(mut children, value) => {
+            let mut occupied = children
+                .iter()
+                .enumerate()
+                .filter(|(_, child)| !matches!(child.node, Node::Empty))
+                .map(|(index, _)| index);
+            match (occupied.next(), occupied.next(), value) {
+                (None, _, None) => Node::Empty,
+                (None, _, Some(value)) => Node::Leaf(Vec::new(), value),
+                (Some(index), None, None) => {
+                    let child = mem::take(&mut children[index]);
+                    extend(&[index as u8], child).node
+                }
+                (_, _, value) => Node::Branch(children, value),
+            }
+        }
+        node => node,
+    }
+}
+
+fn reference(slot: &mut Cached) -> &[u8] {
+    if slot.reference.is_none() {
+        let encoded = encode(&mut slot.node);
+        slot.reference = Some(if encoded.len() < 32 {
+            encoded
+        } else {
+            rlp_bytes(&keccak256(&encoded).to_bytes_be())
+        });
+    }
+    slot.reference.as_ref().unwrap()
+}
+
+fn encode(node: &mut Node) -> Vec<u8> {
+    match node {
+        Node::Empty => rlp_bytes(&[]),
+        Node::Leaf(path, value) => {
+            rlp_list(&[rlp_bytes(&hex_prefix(path, true)), rlp_bytes(value)])
+        }
+        Node::Extension(path, child) => {
+            rlp_list(&[
+                rlp_bytes(&hex_prefix(path, false)),
+                reference(child).to_vec(),
+            ])
+        }
+        Node::Branch(children, value) => {
+            let mut items = children
+                .iter_mut()
+                .map(|child| reference(child).to_vec())
+                .collect::<Vec<_>>();
+            items.push(rlp_bytes(value.as_deref().unwrap_or_default()));
+            rlp_list(&items)
+        }
+    }
+}
+
+/// Encode a node whose children have up to date references
+fn encode_cached(node: &Node) -> Vec<u8> {
+    let child = |child: &Cached| child.reference.clone().expect("Reference is computed");
+    match node {
+        Node::Empty => rlp_bytes(&[]),
+        Node::Leaf(path, value) => {
+            rlp_list(&[rlp_bytes(&hex_prefix(path, true)), rlp_bytes(value)])
+        }
+        Node::Extension(path, grandchild) => {
+            rlp_list(&[rlp_bytes(&hex_prefix(path, false)), child(grandchild)])
+        }
+        Node::Branch(children, value) => {
+            let mut items = children.iter().map(child).collect::<Vec<_>>();
+            items.push(rlp_bytes(value.as_deref().unwrap_or_default()));
+            rlp_list(&items)
+        }
+    }
+}
+
+/// Compact encoding of a nibble path with a leaf flag
+fn hex_prefix(path: &[u8], leaf: bool) -> Vec<u8> {
+    let flag = if leaf { 2 } else { 0 };
+    let mut result = Vec::with_capacity(1 + path.len() / 2);
+    let rest = if path.len() % 2 == 1 {
+        result.push(((flag + 1) << 4) | path[0]);
+        &path[1..]
+    } else {
+        result.push(flag << 4);
+        path
+    };
+    result.extend(rest.chunks(2).map(|pair| (pair[0] << 4) | pair[1]));
+    result
+}
+
+/// RLP encode a byte string
+pub(crate) fn rlp_bytes(bytes: &[u8]) -> Vec<u8> {
+    match bytes.len() {
+        1 if bytes[0] < 0x80 => bytes.to_vec(),
+        length => {
+            let mut result = rlp_length(0x80, length);
+            result.extend_from_slice(bytes);
+            result
+        }
+    }
+}
+
+/// RLP encode a list of already encoded items
+pub(crate) fn rlp_list(items: &[Vec<u8>]) -> Vec<u8> {
+    let payload = items.concat();
+    let mut result = rlp_length(0xc0, payload.len());
+    result.extend_from_slice(&payload);
+    result
+}
+
+fn rlp_length(offset: u8, length: usize) -> Vec<u8> {
+    if length <= 55 {
+        vec![offset + length as u8]
+    } else {
+        let bytes = (length as u64).to_be_bytes();
+        let bytes = &bytes[(length as u64).leading_zeros() as usize / 8..];
+        let mut result = vec![offset + 55 + bytes.len() as u8];
+        result.extend_from_slice(bytes);
+        result
+    }
+}
+
+/// Interpret up to 32 big-endian bytes as a number
+pub(crate) fn to_u256(bytes: &[u8]) -> U256 {
+    let mut padded = [0_u8; 32];
+    padded[32 - bytes.len()..].copy_from_slice(bytes);
+    U256::from_bytes_be(&padded)
+}
+
+/// Big-endian bytes without leading zeros, as RLP encodes integers
+pub(crate) fn minimal_bytes(value: &U256) -> Vec<u8> {
+    let bytes = value.to_bytes_be();
+    let zeros = bytes.iter().take_while(|byte| **byte == 0).count();
+    bytes[zeros..].to_vec()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::{assert_eq, *};
+    use std::collections::HashMap;
+
+    fn reference_root(map: &HashMap<Vec<u8>, Vec<u8>>) -> U256 {
+        let (root, _) = ::trie::build(map);
+        U256::from_bytes_be(&root.0)
+    }
+
+    #[test]
+    fn test_empty() {
+        assert_eq!(Trie::default().root(), EMPTY_ROOT);
+    }
+
+    #[test]
+    fn test_hex_prefix() {
+        assert_eq!(hex_prefix(&[1, 2, 3], false), vec![0x11, 0x23]);
+        assert_eq!(hex_prefix(&[1, 2], true), vec![0x20, 0x12]);
+    }
+
+    proptest! {
+        #[test]
+        fn test_matches_reference(
+            entries in proptest::collection::vec(
+                (proptest::collection::vec(0..4_u8, 1..4), proptest::collection::vec(any::<u8>(), 1..40)),
+                0..20
+            ),
+            removals in proptest::collection::vec(proptest::collection::vec(0..4_u8, 1..4), 0..10),
+        ) {
+            let mut trie = Trie::default();
+            let mut map = HashMap::new();
+            for (key, value) in entries {
+                trie.insert(&key, value.clone());
+                let _previous = map.insert(key, value);
+                // Interleave hashing to exercise the caches
+                let _root = trie.root();
+            }
+            for key in removals {
+                trie.remove(&key);
+                let _previous = map.remove(&key);
+            }
+            for (key, value) in &map {
+                prop_assert_eq!(trie.get(key), Some(value.as_slice()));
+            }
+            prop_assert_eq!(trie.root(), reference_root(&map));
+        }
+    }
+}
@@ -0,0 +1,256 @@
+//! Merkle proof verification
+//!
+//! Checks [EIP-1186](https://eips.ethereum.org/EIPS/eip-1186) proofs as
+//! returned by `eth_getProof` against a block's state root.
+
+use super::{minimal_bytes, nibbles, rlp_bytes, state::encode_account, to_u256};
+use crate::{
+    chain::types::{rpc::AccountProof, BlockHeader},
+    prelude::*,
+    utils::keccak256,
+};
+
+#[derive(Clone, Error, Debug, Eq, PartialEq)]
+pub enum ProofError {
+    #[error("proof node {0} does not match its hash")]
+    HashMismatch(usize),
+
+    #[error("proof node {0} is malformed")]
+    Malformed(usize),
+
+    #[error("proof ends before reaching a value")]
+    Incomplete,
+
+    #[error("account does not match the proven value")]
+    AccountMismatch,
+
+    #[error("storage slot {0:?} does not match the proven value")]
+    StorageMismatch(U256),
+}
+
+/// Next node to visit: referenced by hash or embedded in its parent
+enum Next<'a> {
+    Hash(U256),
+    Inline(&'a [u8]),
+}
+
+/// Check an account proof and all its storage proofs against the header
+pub fn verify(header: &BlockHeader, proof: &AccountProof) -> Result<(), ProofError> {
+    let key = keccak256(proof.address.as_slice()).to_bytes_be();
+    let value = verify_proof(&header.state_root, &key, &proof.account_proof)?;
+    let expected = encode_account(
+        *proof.nonce.as_ref(),
+        proof.balance.as_ref(),
+        &proof.storage_hash,
+        &proof.code_hash,
+    );
+    require!(value == expected, ProofError::AccountMismatch);
+    for slot in &proof.storage_proof {
+        let key = keccak256(&slot.key.as_ref().to_bytes_be()).to_bytes_be();
+        let value = verify_proof(&proof.storage_hash, &key, &slot.proof)?;
+        let expected = if slot.value.as_ref().is_zero() {
+            None
+        } else {
+            Some(rlp_bytes(&minimal_bytes(slot.value.as_ref())))
+        };
+        require!(
+            value == expected,
+            ProofError::StorageMismatch(slot.key.as_ref().clone())
+        );
+    }
+    Ok(())
+}
+
+/// Walk the proof from `root` to `key` and return the proven value
+///
+/// Returns `None` if the proof shows that `key` is absent.
+pub fn verify_proof<P: AsRef<[u8]>>(
+    root: &U256,
+    key: &[u8],
+    proof: &[P],
+) -> Result<Option<Vec<u8>>, ProofError> {
+    let path = nibbles(key);
+    let mut path = path.as_slice();
+    let mut nodes = proof.iter().map(P::as_ref).enumerate();
+    let mut next = Next::Hash(root.clone());
+    let mut index = 0;
+    loop {
+        let node = match next {
+            Next::Hash(hash) => {
+                let (position, node) = nodes.next().ok_or(ProofError::Incomplete)?;
+                require!(keccak256(node) == hash, ProofError::HashMismatch(position));
+                index = position;
+                node
+            }
+            Next::Inline(node) => node,
+        };
+        let malformed = || ProofError::Malformed(index);
+        if rlp_string(node).map_or(false, <[u8]>::is_empty) {
+            // Empty trie
+            return Ok(None);
+        }
+        let items = rlp_items(node).ok_or_else(malformed)?;
+        let child = match items.len() {
+            17 => {
+                match path.split_first() {
+                    None => {
+                        let value = rlp_string(items[16]).ok_or_else(malformed)?;
+                        return Ok(Some(value.to_vec()).filter(|value| !value.is_empty()));
+                    }
+                    Some((nibble, rest)) => {
+                        path = rest;
+                        items[*nibble as usize]
+                    }
+                }
+            }
+            2 => {
+                let encoded = rlp_string(items[0]).ok_or_else(malformed)?;
+                let (prefix, leaf) = decode_hex_prefix(encoded).ok_or_else(malformed)?;
+                if leaf {
+                    if path != prefix.as_slice() {
+                        return Ok(None);
+                    }
+                    let value = rlp_string(items[1]).ok_or_else(malformed)?;
+                    return Ok(Some(value.to_vec()));
+                }
+                match path.strip_prefix(prefix.as_slice()) {
+                    Some(rest) => path = rest,
+                    None => return Ok(None),
+                }
+                items[1]
+            }
+            _ => return Err(malformed()),
+        };
+        next = match rlp_split(child) {
+            Some((true, ..)) => Next::Inline(child),
+            Some((false, payload, _)) if payload.is_empty() => return Ok(None),
+            Some((false, payload, _)) if payload.len() == 32 => Next::Hash(to_u256(payload)),
+            _ => return Err(malformed()),
+        };
+    }
+}
+
+fn decode_hex_prefix(encoded: &[u8]) -> Option<(Vec<u8>, bool)> {
+    let (first, rest) = encoded.split_first()?;
+    let flag = first >> 4;
+    if flag > 3 {
+        return None;
+    }
+    let mut path = if flag & 1 == 1 {
+        vec![first & 0xf]
+    } else {
+        Vec::new()
+    };
+    path.extend(nibbles(rest));
+    Some((path, flag & 2 == 2))
+}
+
+/// Split the first RLP item from `input` into (is list, payload, remainder)
+fn rlp_split(input: &[u8]) -> Option<(bool, &[u8], &[u8])> {
+    let (first, rest) = input.split_first()?;
+    let (list, header, length) = match *first {
+        0x00..=0x7f => return Some((false, &input[..1], rest)),
+        0x80..=0xb7 => (false, 1, (first - 0x80) as usize),
+        0xb8..=0xbf => {
+            let bytes = (first - 0xb7) as usize;
+            (false, 1 + bytes, be_usize(rest.get(..bytes)?)?)
+        }
+        0xc0..=0xf7 => (true, 1, (first - 0xc0) as usize),
+        0xf8..=0xff => {
+            let bytes = (first - 0xf7) as usize;
+            (true, 1 + bytes, be_usize(rest.get(..bytes)?)?)
+        }
+    };
+    let end = header.checked_add(length)?;
+    Some((list, input.get(header..end)?, &input[end..]))
+}
+
+fn be_usize(bytes: &[u8]) -> Option<usize> {
+    if bytes.len() > 8 {
+        return None;
+    }
+    Some(
+        bytes
+            .iter()
+            .fold(0, |result, byte| (result << 8) | *byte as usize),
+    )
+}
+
+/// Payload of a single RLP string
+pub(super) fn rlp_string(input: &[u8]) -> Option<&[u8]> {
+    match rlp_split(input)? {
+        (false, payload, rest) if rest.is_empty() => Some(payload),
+        _ => None,
+    }
+}
+
+/// Encoded items of a single RLP list
+fn rlp_items(input: &[u8]) -> Option<Vec<&[u8]>> {
+    let (list, mut payload, rest) = rlp_split(input)?;
+    if !list || !rest.is_empty() {
+        return None;
+    }
+    let mut items = Vec::new();
+    while !payload.is_empty() {
+        let (_, _, next) = rlp_split(payload)?;
+        items.push(&payload[..payload.len() - next.len()]);
+        payload = next;
+    }
+    Some(items)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        chain::{
+            trie::{StateTrie, EMPTY_ROOT},
+            types::Address,
+            StateSet,
+        },
+        test::prelude::assert_eq,
+    };
+
+    #[test]
+    fn test_verify() {
+        let mut state = StateSet::default();
+        for i in 1..50_u64 {
+            let _previous = state.balances.insert(U256::from(i), U256::from(i << 40));
+            let _previous = state
+                .storages
+                .insert((U256::from(7), U256::from(i)), U256::from(i));
+        }
+        let _previous = state.codes.insert(U256::from(7), vec![0x60, 0x00]);
+        let mut trie = StateTrie::from(&state);
+        let header = BlockHeader {
+            state_root: trie.root(),
+            ..BlockHeader::default()
+        };
+        let mut address = [0_u8; 20];
+        address[19] = 7;
+        let slots = [U256::from(3), U256::from(100)];
+
+        let proof = trie.proof(Address::from(address), &slots);
+        assert_eq!(proof.balance.as_ref(), &U256::from(7_u64 << 40));
+        assert_eq!(proof.storage_proof[0].value.as_ref(), &U256::from(3));
+        assert_eq!(proof.storage_proof[1].value.as_ref(), &U256::zero());
+        assert_eq!(verify(&header, &proof), Ok(()));
+
+        let mut tampered = proof.clone();
+        tampered.balance = U256::from(1).into();
+        assert_eq!(verify(&header, &tampered), Err(ProofError::AccountMismatch));
+
+        let mut tampered = proof;
+        tampered.storage_proof[0].value = U256::from(4).into();
+        assert_eq!(
+            verify(&header, &tampered),
+            Err(ProofError::StorageMismatch(U256::from(3)))
+        );
+
+        // Absent account
+        address[0] = 1;
+        let proof = trie.proof(Address::from(address), &[]);
+        assert_eq!(proof.storage_hash, EMPTY_ROOT);
+        assert_eq!(verify(&header, &proof), Ok(()));
+    }
+}
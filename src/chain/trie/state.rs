@@ -0,0 +1,432 @@
+//! Ethereum world state trie
+//!
+//! Accounts are keyed by `keccak256(address)`, storage slots by
+//! `keccak256(slot)`. Applying a [`StateSet`] only marks the accounts whose
+//! values changed, and only those are rehashed by the next [`StateTrie::root`].
+
+use super::{minimal_bytes, proof::rlp_string, rlp_bytes, rlp_list, to_u256, Trie, EMPTY_ROOT};
+use crate::{
+    chain::{
+        types::{
+            rpc::{AccountProof, Bytes, StorageProof},
+            Address,
+        },
+        StateSet, Writes, EMPTY_CODE_HASH,
+    },
+    prelude::*,
+    utils::keccak256,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    mem,
+};
+
+#[derive(Clone, Debug)]
+struct Account {
+    nonce:     usize,
+    balance:   U256,
+    code_hash: U256,
+    storage:   Trie,
+}
+
+impl Default for Account {
+    fn default() -> Self {
+        Self {
+            nonce:     0,
+            balance:   U256::zero(),
+            code_hash: EMPTY_CODE_HASH,
+            storage:   Trie::default(),
+        }
+    }
+}
+
+impl Account {
+    fn encode(&mut self) -> Option<Vec<u8>> {
+        let storage_root = self.storage.root();
+        encode_account(
+            self.nonce as u64,
+            &self.balance,
+            &storage_root,
+            &self.code_hash,
+        )
+    }
+}
+
+/// RLP encoding of `[nonce, balance, storage_root, code_hash]`, `None` if the
+/// account is empty.
+pub(super) fn encode_account(
+    nonce: u64,
+    balance: &U256,
+    storage_root: &U256,
+    code_hash: &U256,
+) -> Option<Vec<u8>> {
+    if nonce == 0
+        && balance.is_zero()
+        && *code_hash == EMPTY_CODE_HASH
+        && *storage_root == EMPTY_ROOT
+    {
+        return None;
+    }
+    Some(rlp_list(&[
+        rlp_bytes(&minimal_bytes(&U256::from(nonce))),
+        rlp_bytes(&minimal_bytes(balance)),
+        rlp_bytes(&storage_root.to_bytes_be()),
+        rlp_bytes(&code_hash.to_bytes_be()),
+    ]))
+}
+
+/// Keys of applied [`Writes`]
+#[derive(Clone, Debug, Default)]
+struct Keys {
+    nonces:   HashSet<U256>,
+    balances: HashSet<U256>,
+    codes:    HashSet<U256>,
+    storages: HashSet<(U256, U256)>,
+    cleared:  HashSet<U256>,
+}
+
+impl From<&Writes> for Keys {
+    fn from(writes: &Writes) -> Self {
+        let state = &writes.state;
+        Self {
+            nonces:   state.nonces.keys().cloned().collect(),
+            balances: state.balances.keys().cloned().collect(),
+            codes:    state.codes.keys().cloned().collect(),
+            storages: state.storages.keys().cloned().collect(),
+            cleared:  writes.cleared.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct StateTrie {
+    accounts: HashMap<U256, Account>,
+    trie:     Trie,
+    /// Accounts changed since the last root computation
+    dirty:    HashSet<U256>,
+    /// Keys written by the last [`StateTrie::apply`]
+    applied:  Keys,
+    /// State the trie was created from, restored when writes are undone
+    base:     StateSet,
+}
+
+impl StateTrie {
+    /// Make the trie hold the values in `writes`
+    ///
+    /// Values that are equal to the current ones do not cause rehashing, so
+    /// the full writes of a [`Fork`](crate::chain::Fork) can be applied after
+    /// every block. Values applied before but missing from `writes`, for
+    /// example after a revert, are reset to those the trie was created from.
+    /// Storage of cleared accounts only holds the written slots.
+    pub fn apply(&mut self, writes: &Writes) {
+        let state = &writes.state;
+        let previous = mem::replace(&mut self.applied, Keys::from(writes));
+        let (base, cleared) = (&self.base, &self.applied.cleared);
+        for address in previous.nonces.difference(&self.applied.nonces) {
+            Self::write(&mut self.accounts, &mut self.dirty, address, |account| {
+                account.nonce = base.nonces.get(address).copied().unwrap_or_default();
+            });
+        }
+        for address in previous.balances.difference(&self.applied.balances) {
+            Self::write(&mut self.accounts, &mut self.dirty, address, |account| {
+                account.balance = base.balances.get(address).cloned().unwrap_or_default();
+            });
+        }
+        for address in previous.codes.difference(&self.applied.codes) {
+            Self::write(&mut self.accounts, &mut self.dirty, address, |account| {
+                account.code_hash = base
+                    .codes
+                    .get(address)
+                    .map_or(EMPTY_CODE_HASH, |code| keccak256(code));
+            });
+        }
+        for key in previous.storages.difference(&self.applied.storages) {
+            Self::write(&mut self.accounts, &mut self.dirty, &key.0, |account| {
+                let value = match base.storages.get(key) {
+                    Some(value) if !cleared.contains(&key.0) => value.clone(),
+                    _ => U256::zero(),
+                };
+                account
+                    .storage
+                    .insert(&storage_key(&key.1), storage_value(&value));
+            });
+        }
+        for address in previous.cleared.difference(cleared) {
+            for ((owner, slot), value) in &base.storages {
+                if owner == address {
+                    Self::write(&mut self.accounts, &mut self.dirty, owner, |account| {
+                        account
+                            .storage
+                            .insert(&storage_key(slot), storage_value(value));
+                    });
+                }
+            }
+        }
+        for address in cleared.difference(&previous.cleared) {
+            for (owner, slot) in base.storages.keys() {
+                if owner == address {
+                    Self::write(&mut self.accounts, &mut self.dirty, owner, |account| {
+                        account.storage.remove(&storage_key(slot));
+                    });
+                }
+            }
+        }
+
+        for (address, nonce) in &state.nonces {
+            let account = self.accounts.entry(address.clone()).or_default();
+            if account.nonce != *nonce {
+                account.nonce = *nonce;
+                let _new = self.dirty.insert(address.clone());
+            }
+        }
+        for (address, balance) in &state.balances {
+            let account = self.accounts.entry(address.clone()).or_default();
+            if account.balance != *balance {
+                account.balance = balance.clone();
+                let _new = self.dirty.insert(address.clone());
+            }
+        }
+        for (address, code) in &state.codes {
+            let account = self.accounts.entry(address.clone()).or_default();
+            let code_hash = keccak256(code);
+            if account.code_hash != code_hash {
+                account.code_hash = code_hash;
+                let _new = self.dirty.insert(address.clone());
+            }
+        }
+        for ((address, slot), value) in &state.storages {
+            let account = self.accounts.entry(address.clone()).or_default();
+            let key = storage_key(slot);
+            let value = storage_value(value);
+            if account.storage.get(&key).unwrap_or_default() != value.as_slice() {
+                account.storage.insert(&key, value);
+                let _new = self.dirty.insert(address.clone());
+            }
+        }
+    }
+
+    /// Modify an existing account and mark it for rehashing
+    fn write(
+        accounts: &mut HashMap<U256, Account>,
+        dirty: &mut HashSet<U256>,
+        address: &U256,
+        modify: impl FnOnce(&mut Account),
+    ) {
+        if let Some(account) = accounts.get_mut(address) {
+            modify(account);
+            let _new = dirty.insert(address.clone());
+        }
+    }
+
+    /// Root of the account trie, rehashing accounts changed since last call
+    pub fn root(&mut self) -> U256 {
+        for address in self.dirty.drain() {
+            let key = account_key(&address);
+            let account = self
+                .accounts
+                .get_mut(&address)
+                .expect("Dirty account exists");
+            match account.encode() {
+                Some(encoded) => self.trie.insert(&key, encoded),
+                None => self.trie.remove(&key),
+            }
+        }
+        self.trie.root()
+    }
+
+    /// Account and storage proofs as returned by `eth_getProof`
+    pub fn proof(&mut self, address: Address, slots: &[U256]) -> AccountProof {
+        let account_address = address.to_word();
+        let account_proof = self
+            .proof_nodes(&account_key(&account_address))
+            .into_iter()
+            .map(Bytes::from)
+            .collect();
+        let mut empty = Account::default();
+        let account = self
+            .accounts
+            .get_mut(&account_address)
+            .unwrap_or(&mut empty);
+        let storage_proof = slots
+            .iter()
+            .map(|slot| {
+                let key = storage_key(slot);
+                let value = account
+                    .storage
+                    .get(&key)
+                    .and_then(rlp_string)
+                    .map_or_else(U256::zero, to_u256);
+                StorageProof {
+                    key:   slot.clone().into(),
+                    value: value.into(),
+                    proof: account
+                        .storage
+                        .proof(&key)
+                        .into_iter()
+                        .map(Bytes::from)
+                        .collect(),
+                }
+            })
+            .collect();
+        AccountProof {
+            address,
+            account_proof,
+            balance: account.balance.clone().into(),
+            code_hash: account.code_hash.clone(),
+            nonce: (account.nonce as u64).into(),
+            storage_hash: account.storage.root(),
+            storage_proof,
+        }
+    }
+
+    fn proof_nodes(&mut self, key: &[u8]) -> Vec<Vec<u8>> {
+        let _root = self.root();
+        self.trie.proof(key)
+    }
+
+    /// Root of the storage trie of `address`
+    pub fn storage_root(&mut self, address: &U256) -> U256 {
+        self.accounts
+            .get_mut(address)
+            .map_or(EMPTY_ROOT, |account| account.storage.root())
+    }
+}
+
+/// The trie of `state`, which later writes are applied on top of
+impl From<&StateSet> for StateTrie {
+    fn from(state: &StateSet) -> Self {
+        let mut result = Self::default();
+        let writes = Writes::from(state.clone());
+        result.apply(&writes);
+        result.applied = Keys::default();
+        result.base = writes.state;
+        result
+    }
+}
+
+/// Slots are hashed as their 32 byte big-endian representation
+fn storage_key(slot: &U256) -> [u8; 32] {
+    keccak256(&slot.to_bytes_be()).to_bytes_be()
+}
+
+/// RLP encoding of a storage value, empty for zero
+fn storage_value(value: &U256) -> Vec<u8> {
+    if value.is_zero() {
+        Vec::new()
+    } else {
+        rlp_bytes(&minimal_bytes(value))
+    }
+}
+
+/// Addresses are hashed as their 20 byte big-endian representation
+fn account_key(address: &U256) -> Vec<u8> {
+    keccak256(Address::from_word(address).as_slice())
+        .to_bytes_be()
+        .to_vec()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_empty() {
+        assert_eq!(keccak256(&[]), EMPTY_CODE_HASH);
+        let mut trie = StateTrie::from(&StateSet::default());
+        assert_eq!(trie.root(), EMPTY_ROOT);
+    }
+
+    #[test]
+    fn test_incremental() {
+        let mut state = StateSet::default();
+        for i in 1..20_u64 {
+            let _previous = state.balances.insert(U256::from(i), U256::from(1000 * i));
+            let _previous = state
+                .storages
+                .insert((U256::from(i), U256::from(i % 3)), U256::from(i));
+        }
+        let _previous = state.codes.insert(U256::from(5), vec![0x60, 0x00]);
+        let mut incremental = StateTrie::from(&state);
+        let _root = incremental.root();
+
+        let _previous = state.nonces.insert(U256::from(3), 1);
+        let _previous = state
+            .storages
+            .insert((U256::from(7), U256::one()), U256::zero());
+        let _previous = state.balances.insert(U256::from(8), U256::zero());
+        let _previous = state
+            .storages
+            .insert((U256::from(8), U256::from(2)), U256::zero());
+        incremental.apply(&Writes::from(state.clone()));
+        assert_eq!(incremental.dirty.len(), 3);
+
+        let mut scratch = StateTrie::from(&state);
+        assert_eq!(incremental.root(), scratch.root());
+        assert_eq!(incremental.storage_root(&U256::from(7)), EMPTY_ROOT);
+    }
+
+    #[test]
+    fn test_apply_removes_missing() {
+        let mut state = StateSet::default();
+        let _previous = state.balances.insert(U256::one(), U256::from(5));
+        let mut trie = StateTrie::from(&state);
+        let root = trie.root();
+
+        let mut written = state.clone();
+        let _previous = written.nonces.insert(U256::from(2), 1);
+        let _previous = written.codes.insert(U256::one(), vec![0x00]);
+        let _previous = written
+            .storages
+            .insert((U256::one(), U256::zero()), U256::from(7));
+        let written = Writes::from(written);
+        trie.apply(&written);
+        assert_ne!(trie.root(), root);
+
+        trie.apply(&Writes::from(state));
+        assert_eq!(trie.root(), root);
+        assert_eq!(trie.storage_root(&U256::one()), EMPTY_ROOT);
+
+        // Values the trie was created from are not written, but restored
+        trie.apply(&written);
+        trie.apply(&Writes::default());
+        assert_eq!(trie.root(), root);
+    }
+
+    #[test]
+    fn test_apply_cleared() {
+        let address = U256::one();
+        let mut state = StateSet::default();
+        let _previous = state.balances.insert(address.clone(), U256::from(5));
+        for slot in 0..3_u64 {
+            let _previous = state
+                .storages
+                .insert((address.clone(), U256::from(slot)), U256::from(slot + 1));
+        }
+        let mut trie = StateTrie::from(&state);
+        let root = trie.root();
+
+        // Only the written slot is left
+        let mut writes = Writes::default();
+        let _previous = writes
+            .state
+            .storages
+            .insert((address.clone(), U256::one()), U256::from(9));
+        let _inserted = writes.cleared.insert(address.clone());
+        trie.apply(&writes);
+        let mut expected = state.clone();
+        expected.storages.clear();
+        let _previous = expected
+            .storages
+            .insert((address.clone(), U256::one()), U256::from(9));
+        assert_eq!(trie.root(), StateTrie::from(&expected).root());
+
+        // A slot that is no longer written stays cleared
+        writes.state.storages.clear();
+        trie.apply(&writes);
+        assert_eq!(trie.storage_root(&address), EMPTY_ROOT);
+
+        trie.apply(&Writes::default());
+        assert_eq!(trie.root(), root);
+    }
+}
@@ -1,5 +1,5 @@
 use crate::{prelude::*, serde::fixed20};
-use std::{fmt, fmt::Debug};
+use std::{fmt, fmt::Debug, str::FromStr};
 
 /// Ethereum address
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize)]
@@ -11,7 +11,33 @@ impl From<[u8; 20]> for Address {
     }
 }
 
+impl FromStr for Address {
+    type Err = hex::FromHexError;
+
+    /// Parse from hexadecimal with optional `0x` prefix
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        let str = str.strip_prefix("0x").unwrap_or(str);
+        let mut result = [0_u8; 20];
+        hex::decode_to_slice(str, &mut result)?;
+        Ok(Self(result))
+    }
+}
+
 impl Address {
+    /// The least significant 20 bytes of a word, as used by the EVM
+    pub fn from_word(word: &U256) -> Self {
+        let mut result = [0_u8; 20];
+        result.copy_from_slice(&word.to_bytes_be()[12..]);
+        Self(result)
+    }
+
+    /// The address zero padded to a word, as used by the EVM
+    pub fn to_word(&self) -> U256 {
+        let mut padded = [0_u8; 32];
+        padded[12..].copy_from_slice(&self.0);
+        U256::from_bytes_be(&padded)
+    }
+
     pub fn to_array(self) -> [u8; 20] {
         self.0
     }
@@ -69,4 +95,33 @@ mod test {
         let de: Address = from_value(json).unwrap();
         assert_eq!(de, obj);
     }
+
+    #[test]
+    fn test_from_str() {
+        let expected = Address::from(hex!("0f572e5295c57f15886f9b263e2f6d2d6c7b5ec6"));
+        assert_eq!(
+            "0x0f572e5295c57f15886f9b263e2f6d2d6c7b5ec6".parse::<Address>(),
+            Ok(expected.clone())
+        );
+        assert_eq!(
+            "0f572e5295c57f15886f9b263e2f6d2d6c7b5ec6".parse::<Address>(),
+            Ok(expected)
+        );
+        assert!("0x0f572e".parse::<Address>().is_err());
+    }
+
+    #[test]
+    fn test_word() {
+        let address = Address::from(hex!("0f572e5295c57f15886f9b263e2f6d2d6c7b5ec6"));
+        let word = address.to_word();
+        assert_eq!(
+            word,
+            u256h!("0000000000000000000000000f572e5295c57f15886f9b263e2f6d2d6c7b5ec6")
+        );
+        assert_eq!(Address::from_word(&word), address);
+
+        // Upper bytes are dropped
+        let word = word + (U256::one() << 160);
+        assert_eq!(Address::from_word(&word), address);
+    }
 }
@@ -0,0 +1,24 @@
+use super::super::Address;
+use crate::prelude::*;
+
+/// Access list entry
+///
+/// See <https://eips.ethereum.org/EIPS/eip-2930>
+#[derive(Default, Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AccessListItem {
+    pub address:      Address,
+    pub storage_keys: Vec<U256>,
+}
+
+/// Flatten an access list into `(address, slot)` keys for prefetching
+pub fn access_list_keys(access_list: &[AccessListItem]) -> Vec<(Address, U256)> {
+    access_list
+        .iter()
+        .flat_map(|item| {
+            item.storage_keys
+                .iter()
+                .map(move |slot| (item.address.clone(), slot.clone()))
+        })
+        .collect()
+}
@@ -1,11 +1,23 @@
-use super::super::{Address, Transaction};
+use super::{super::Address, AccessListItem, Bytes, Hex};
 use crate::prelude::*;
 
 /// Call request
+///
+/// See <https://geth.ethereum.org/docs/rpc/ns-eth#eth_call>
 #[allow(clippy::module_name_repetitions)]
 #[derive(Default, Debug, PartialEq, Clone, Serialize, Deserialize)]
 #[serde(default, rename_all = "camelCase")]
 pub struct CallRequest {
     pub from:        Address,
-    pub transaction: Transaction,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to:          Option<Address>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gas:         Option<Hex<u64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gas_price:   Option<Hex<U256>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value:       Option<Hex<U256>>,
+    #[serde(alias = "input", skip_serializing_if = "Option::is_none")]
+    pub data:        Option<Bytes>,
+    pub access_list: Vec<AccessListItem>,
 }
@@ -4,7 +4,7 @@ use crate::{
     serde::{bytes, short_u64},
 };
 use arrayvec::ArrayVec;
-use std::collections::HashMap;
+use std::{collections::HashMap, path::PathBuf};
 
 // See <https://github.com/ethereum/retesteth/wiki/RPC-Methods#test_setchainparams>
 #[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
@@ -13,6 +13,10 @@ pub struct GenesisConfig {
     pub accounts:    HashMap<Address, GenesisAccount>,
     pub genesis:     BlockHeader,
     pub seal_engine: SealEngine,
+    /// Extension: load the state in this `geth dump` or genesis alloc file
+    /// before applying `accounts`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state_dump:  Option<PathBuf>,
 }
 
 #[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
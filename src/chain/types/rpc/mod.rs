@@ -1,6 +1,7 @@
 //! These types are mostly copied from the `web3` crate, but adjusted for our
 //! needs.
 
+mod access_list;
 mod account_range;
 mod block_number;
 mod bytes;
@@ -12,23 +13,32 @@ mod hex_mid;
 mod hexable;
 mod log;
 mod log_filter;
+mod prestate;
+mod proof;
+mod reset;
+mod state_override;
 mod storage_range;
 mod transaction;
 mod transaction_receipt;
 mod value_or_array;
 
 pub use self::{
+    access_list::{access_list_keys, AccessListItem},
     account_range::AccountRange,
     block_number::BlockNumber,
     bytes::Bytes,
     call::CallRequest,
-    genesis_config::GenesisConfig,
+    genesis_config::{GenesisAccount, GenesisConfig},
     hex::Hex,
     hex_full::HexFull,
     hex_mid::HexMid,
     hexable::Hexable,
     log::{Log, LogBlock},
     log_filter::LogFilter,
+    prestate::{PrestateAccount, PrestateDiff, PrestateTrace},
+    proof::{AccountProof, StorageProof},
+    reset::{Forking, ResetParams},
+    state_override::{AccountOverride, BlockOverrides, StateOverride},
     storage_range::{StorageRange, StorageSlot},
     transaction::Transaction,
     transaction_receipt::{TransactionReceipt, TransactionStatus},
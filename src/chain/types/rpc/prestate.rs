@@ -0,0 +1,36 @@
+use super::{super::Address, Bytes, Hex};
+use crate::prelude::*;
+use std::collections::HashMap;
+
+/// Result of `debug_traceBlockByNumber` with the `prestateTracer` in diff mode,
+/// one per transaction
+///
+/// See <https://geth.ethereum.org/docs/developers/evm-tracing/built-in-tracers#prestate-tracer>
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PrestateTrace {
+    pub result: PrestateDiff,
+}
+
+/// `pre` holds the modified accounts before the transaction, `post` only the
+/// fields that changed. Accounts in `pre` but not in `post` were deleted.
+/// Slots in `pre` but not in `post` were set to zero.
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PrestateDiff {
+    pub pre:  HashMap<Address, PrestateAccount>,
+    pub post: HashMap<Address, PrestateAccount>,
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PrestateAccount {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub balance: Option<Hex<U256>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce:   Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code:    Option<Bytes>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub storage: HashMap<U256, U256>,
+}
@@ -0,0 +1,23 @@
+use super::{super::Address, Bytes, Hex};
+use crate::prelude::*;
+
+/// See <https://eips.ethereum.org/EIPS/eip-1186>
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountProof {
+    pub address:       Address,
+    pub account_proof: Vec<Bytes>,
+    pub balance:       Hex<U256>,
+    pub code_hash:     U256,
+    pub nonce:         Hex<u64>,
+    pub storage_hash:  U256,
+    pub storage_proof: Vec<StorageProof>,
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageProof {
+    pub key:   Hex<U256>,
+    pub value: Hex<U256>,
+    pub proof: Vec<Bytes>,
+}
@@ -0,0 +1,21 @@
+use crate::prelude::*;
+
+/// Parameters of `hardhat_reset`
+///
+/// See <https://hardhat.org/hardhat-network/docs/reference#hardhat_reset>
+#[derive(Default, Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ResetParams {
+    /// Starts an empty chain if not specified
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub forking: Option<Forking>,
+}
+
+#[derive(Default, Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Forking {
+    pub json_rpc_url: String,
+    /// Forks from the latest block if not specified
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block_number: Option<u64>,
+}
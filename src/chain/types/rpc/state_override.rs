@@ -0,0 +1,64 @@
+use super::{super::Address, Bytes, Hex};
+use crate::prelude::*;
+use std::collections::HashMap;
+
+/// Per account state replaced for the duration of a call
+///
+/// See <https://geth.ethereum.org/docs/rpc/ns-eth#3-object---state-override-set>
+pub type StateOverride = HashMap<Address, AccountOverride>;
+
+/// `state` replaces all storage of the account, `state_diff` only the given
+/// slots. At most one of them can be set.
+#[derive(Default, Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct AccountOverride {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub balance:    Option<Hex<U256>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce:      Option<Hex<u64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code:       Option<Bytes>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state:      Option<HashMap<U256, U256>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_diff: Option<HashMap<U256, U256>>,
+}
+
+/// Block fields replaced for the duration of a call
+#[derive(Default, Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct BlockOverrides {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub number: Option<Hex<u64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time:   Option<Hex<u64>>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+    use serde_json::{from_value, json};
+
+    #[test]
+    fn test_deserialize() {
+        let overrides: StateOverride = from_value(json!({
+            "0x1111111111111111111111111111111111111111": {
+                "balance": "0x10",
+                "stateDiff": {
+                    "0x0000000000000000000000000000000000000000000000000000000000000001":
+                        "0x0000000000000000000000000000000000000000000000000000000000000002",
+                },
+            }
+        }))
+        .unwrap();
+        let address: Address = "0x1111111111111111111111111111111111111111"
+            .parse()
+            .unwrap();
+        let account = &overrides[&address];
+        assert_eq!(account.balance, Some(Hex::from(U256::from(16_u64))));
+        assert_eq!(account.nonce, None);
+        assert_eq!(account.state, None);
+        assert_eq!(account.state_diff.as_ref().unwrap().len(), 1);
+    }
+}
@@ -8,6 +8,8 @@ use std::collections::HashMap;
 pub struct StorageRange {
     pub storage:  HashMap<U256, StorageSlot>,
     pub complete: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_key: Option<U256>,
 }
 
 #[derive(Default, Debug, PartialEq, Clone, Serialize, Deserialize)]
@@ -0,0 +1,161 @@
+/// Disassemble contract bytecode and export its control flow graph
+use crate::{
+    chain::types::{rpc::BlockNumber, Address},
+    evm::{jit::Program, Opcode, SignatureDatabase},
+    prelude::*,
+    rpc,
+};
+use std::{
+    io::{stdout, Write},
+    path::{Path, PathBuf},
+};
+
+/// Read hex encoded bytecode from a file
+pub fn read_bytecode(file: &Path) -> AnyResult<Vec<u8>> {
+    let contents = std::fs::read_to_string(file)
+        .with_context(|| format!("Reading bytecode from {}", file.display()))?;
+    let contents = contents.trim();
+    let contents = contents.strip_prefix("0x").unwrap_or(contents);
+    hex::decode(contents).context("Decoding hex bytecode")
+}
+
+/// Fetch the code of a contract from a JSON-RPC node
+pub async fn fetch_bytecode(node: &str, address: Address) -> AnyResult<Vec<u8>> {
+    let client = rpc::client(node)
+        .await
+        .context("Creating RPC client to fetch code from")?;
+    let code = client
+        .get_code(address, BlockNumber::Latest)
+        .await
+        .map_err(|err| anyhow!("Error: {}", err))
+        .context("Fetching contract code")?
+        .to_vec();
+    require!(!code.is_empty(), anyhow!("No code at address"));
+    Ok(code)
+}
+
+/// Read bytecode from either a file or a contract address
+pub async fn load_bytecode(
+    file: Option<PathBuf>,
+    address: Option<Address>,
+    node: &str,
+) -> AnyResult<Vec<u8>> {
+    match (file, address) {
+        (Some(file), None) => read_bytecode(&file),
+        (None, Some(address)) => fetch_bytecode(node, address).await,
+        _ => Err(anyhow!("Specify exactly one of --file or --address")),
+    }
+}
+
+pub async fn disasm(
+    file: Option<PathBuf>,
+    address: Option<Address>,
+    node: String,
+    dot: Option<PathBuf>,
+    optimize: bool,
+    signatures: Option<PathBuf>,
+) -> AnyResult<()> {
+    let bytecode = load_bytecode(file, address, &node).await?;
+    info!("Disassembling {} bytes of code", bytecode.len());
+
+    // Recovering control flow can fail on unresolvable jumps. The linear
+    // disassembly is still useful in that case.
+    let program = match Program::from(bytecode.clone()) {
+        Ok(mut program) => {
+            if optimize {
+                program.optimize();
+            }
+            Some(program)
+        }
+        Err(err) => {
+            warn!("Could not recover control flow: {}", err);
+            None
+        }
+    };
+
+    let signatures = match signatures {
+        Some(path) => SignatureDatabase::load(&path)?,
+        None => SignatureDatabase::default(),
+    };
+
+    let stdout = stdout();
+    let mut output = stdout.lock();
+    if let Some(program) = &program {
+        write_functions(&mut output, program, &signatures)?;
+    }
+    write_disassembly(&mut output, &bytecode, program.as_ref())?;
+
+    if let Some(dot) = dot {
+        let program = program.ok_or_else(|| anyhow!("No control flow graph to export"))?;
+        let mut file =
+            std::fs::File::create(&dot).with_context(|| format!("Creating {}", dot.display()))?;
+        program.write_dot(&mut file)?;
+        info!("Wrote control flow graph to {}", dot.display());
+    }
+    Ok(())
+}
+
+/// List the external functions found in the dispatcher
+pub fn write_functions<W: Write>(
+    output: &mut W,
+    program: &Program,
+    signatures: &SignatureDatabase,
+) -> std::io::Result<()> {
+    for function in program.functions() {
+        writeln!(
+            output,
+            "; function 0x{:08x} at 0x{:04x}{}{} {}",
+            function.selector,
+            function.entry,
+            if function.payable { " payable" } else { "" },
+            if function.view { " view" } else { "" },
+            signatures.lookup(function.selector).join(" | ")
+        )?;
+    }
+    Ok(())
+}
+
+/// Linear disassembly annotated with the recovered blocks
+///
+/// Code that is not reachable in the control flow graph (like the Solidity
+/// metadata) is still disassembled.
+pub fn write_disassembly<W: Write>(
+    output: &mut W,
+    bytecode: &[u8],
+    program: Option<&Program>,
+) -> std::io::Result<()> {
+    let mut pc = 0;
+    while pc < bytecode.len() {
+        if let Some(block) = program.and_then(|program| program.blocks.get(&pc)) {
+            writeln!(output)?;
+            let successors = block
+                .successors()
+                .iter()
+                .map(|pc| format!("0x{:04x}", pc))
+                .join(", ");
+            writeln!(
+                output,
+                "; block 0x{:04x} ({} gas) -> [{}]",
+                pc,
+                block.gas_cost(),
+                successors
+            )?;
+        }
+        let opcode = Opcode::from(bytecode[pc]);
+        let end = std::cmp::min(pc + opcode.encoded_size(), bytecode.len());
+        match opcode {
+            Opcode::Push(_) => {
+                writeln!(
+                    output,
+                    "0x{:04x}: {} 0x{}",
+                    pc,
+                    opcode,
+                    hex::encode(&bytecode[pc + 1..end])
+                )?
+            }
+            _ => writeln!(output, "0x{:04x}: {}", pc, opcode)?,
+        }
+        pc += opcode.encoded_size();
+    }
+    Ok(())
+}
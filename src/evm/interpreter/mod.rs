@@ -1,11 +1,21 @@
 //! Simple EVM interpreter
+//!
+//! Blocks found by control flow recovery run in their optimized form, see
+//! [`Program::optimize`]. Code outside those blocks is stepped through one
+//! opcode at a time.
 // TODO: Error handling
 
 use crate::{
-    chain::{BlockInfo, ChainState},
-    evm::{precompiles::keccak256, CallInfo, ExecutionResult, Opcode, TransactionInfo},
+    chain::{types::Address, BlockInfo, ChainState, Error, EMPTY_CODE_HASH},
+    evm::{
+        jit::{address_mask, Block, Instruction, Program},
+        precompiles::keccak256,
+        CallInfo, ExecutionResult, Opcode, TransactionInfo,
+    },
     prelude::*,
 };
+use futures::future::BoxFuture;
+use std::sync::Arc;
 
 /// Variables during execution
 struct ExecutionState<'a> {
@@ -14,6 +24,8 @@ struct ExecutionState<'a> {
     transaction: &'a TransactionInfo,
     call:        &'a CallInfo,
     code:        &'a [u8],
+    /// Optimized blocks of `code`, `None` if control flow recovery failed
+    program:     Option<Arc<Program>>,
     pc:          usize,
     gas:         usize,
     stack:       Vec<U256>,
@@ -21,39 +33,121 @@ struct ExecutionState<'a> {
     return_data: Vec<u8>,
 }
 
-pub fn evaluate(
-    chain: &mut dyn ChainState,
-    block: &BlockInfo,
-    transaction: &TransactionInfo,
-    call: &CallInfo,
-) -> ExecutionResult {
-    let code = chain.code(&call.address);
-    let mut exec = ExecutionState {
-        chain,
-        block,
-        transaction,
-        call,
-        code: code.as_slice(),
-        pc: 0,
-        gas: call.initial_gas,
-        stack: Vec::new(),
-        memory: vec![0_u8; 1_000_000],
-        return_data: Vec::new(),
-    };
-    exec.run()
+/// Evaluate a call
+///
+/// Fails if reading chain state fails. Boxed to allow recursion on calls.
+pub fn evaluate<'a>(
+    chain: &'a mut dyn ChainState,
+    block: &'a BlockInfo,
+    transaction: &'a TransactionInfo,
+    call: &'a CallInfo,
+) -> BoxFuture<'a, Result<ExecutionResult, Error>> {
+    evaluate_with_gas(chain, block, transaction, call)
+        .map_ok(|(result, _)| result)
+        .boxed()
+}
+
+/// Evaluate a call and return the remaining gas with the result
+pub fn evaluate_with_gas<'a>(
+    chain: &'a mut dyn ChainState,
+    block: &'a BlockInfo,
+    transaction: &'a TransactionInfo,
+    call: &'a CallInfo,
+) -> BoxFuture<'a, Result<(ExecutionResult, usize), Error>> {
+    async move {
+        let code = chain.code(&Address::from_word(&call.address)).await?;
+        let mut exec = ExecutionState {
+            chain,
+            block,
+            transaction,
+            call,
+            code: code.as_slice(),
+            program: optimized(&code).map(Arc::new),
+            pc: 0,
+            gas: call.initial_gas,
+            stack: Vec::new(),
+            memory: vec![0_u8; 1_000_000],
+            return_data: Vec::new(),
+        };
+        let result = exec.run().await?;
+        Ok((result, exec.gas))
+    }
+    .boxed()
+}
+
+/// Recover and optimize the blocks of `code`
+fn optimized(code: &[u8]) -> Option<Program> {
+    let mut program = Program::from(code.to_vec()).ok()?;
+    program.optimize();
+    Some(program)
 }
 
 impl<'a> ExecutionState<'a> {
-    pub fn run(&mut self) -> ExecutionResult {
+    pub async fn run(&mut self) -> Result<ExecutionResult, Error> {
+        let program = self.program.clone();
         loop {
-            if let Some(result) = self.step() {
-                return result;
+            let block = program
+                .as_ref()
+                .and_then(|program| program.blocks.get(&self.pc));
+            let result = match block {
+                Some(block) => self.run_block(block).await?,
+                None => self.step().await?,
+            };
+            if let Some(result) = result {
+                return Ok(result);
             }
         }
     }
 
-    #[allow(clippy::too_many_lines)] // TODO: Simplify
-    pub fn step(&mut self) -> Option<ExecutionResult> {
+    /// Run the optimized block starting at the current program counter
+    ///
+    /// Gas is charged per instruction, so opcodes observing gas see the same
+    /// value as when stepping through the original bytecode.
+    async fn run_block(&mut self, block: &Block) -> Result<Option<ExecutionResult>, Error> {
+        for inst in &block.instructions {
+            match inst {
+                Instruction::Opcode(op) => {
+                    if let Some(result) = self.execute(*op).await? {
+                        return Ok(Some(result));
+                    }
+                }
+                Instruction::Jump(_) => return self.execute(Opcode::Jump).await,
+                Instruction::CondJump(_, fallthrough) => {
+                    self.pc = *fallthrough;
+                    return self.execute(Opcode::JumpI).await;
+                }
+                Instruction::Fallthrough(fallthrough) => {
+                    self.pc = *fallthrough;
+                    return Ok(None);
+                }
+                inst => {
+                    if !self.charge(inst.base_gas()) {
+                        return Ok(Some(ExecutionResult::OutOfGas));
+                    }
+                    match inst {
+                        Instruction::Push(value) => self.stack.push(value.clone()),
+                        Instruction::AddressMask => self.op1(|value| value & address_mask()),
+                        Instruction::Selector => self.op1(|value| value >> 224_usize),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        unreachable!("Blocks end in a block-final instruction")
+    }
+
+    /// Subtract `cost` from the remaining gas, or consume all of it and
+    /// return `false` if there is not enough.
+    fn charge(&mut self, cost: usize) -> bool {
+        if self.gas < cost {
+            self.gas = 0;
+            return false;
+        }
+        self.gas -= cost;
+        true
+    }
+
+    pub async fn step(&mut self) -> Result<Option<ExecutionResult>, Error> {
         // Read from zero-extended bytecode
         // NOTE: Does the zero-extending work for Push(..) too?
         let op = self
@@ -65,6 +159,17 @@ impl<'a> ExecutionState<'a> {
         // op => println!("{:05} {}", self.pc, op),
         // }
         self.pc += 1;
+        self.execute(op).await
+    }
+
+    /// Charge the static gas of `op` and execute it
+    ///
+    /// `PUSH` reads its argument from the bytecode at the program counter.
+    #[allow(clippy::too_many_lines)] // TODO: Simplify
+    async fn execute(&mut self, op: Opcode) -> Result<Option<ExecutionResult>, Error> {
+        if !self.charge(op.base_gas()) {
+            return Ok(Some(ExecutionResult::OutOfGas));
+        }
 
         // Dispatch opcode
         #[allow(clippy::match_same_arms)]
@@ -159,6 +264,9 @@ impl<'a> ExecutionState<'a> {
             Opcode::Timestamp => {
                 self.stack.push(U256::from(self.block.timestamp));
             }
+            Opcode::Number => {
+                self.stack.push(U256::from(self.block.number));
+            }
             Opcode::CallValue => {
                 self.stack.push(self.call.call_value.clone());
             }
@@ -189,14 +297,31 @@ impl<'a> ExecutionState<'a> {
             Opcode::SLoad => {
                 let slot = self.stack.pop().unwrap();
                 println!("SLOAD {:?}", slot);
-                self.stack
-                    .push(self.chain.storage(&self.call.address, &slot));
+                let address = Address::from_word(&self.call.address);
+                let value = self.chain.storage(&address, &slot).await?;
+                self.stack.push(value);
             }
             Opcode::ExtCodeSize => {
-                let address = self.stack.pop().unwrap();
-                let size = self.chain.code(&address).len();
+                let address = Address::from_word(&self.stack.pop().unwrap());
+                // Most accounts have no code, answer those without fetching it
+                let size = if self.chain.code_hash(&address).await? == EMPTY_CODE_HASH {
+                    0
+                } else {
+                    self.chain.code(&address).await?.len()
+                };
                 self.stack.push(U256::from(size));
             }
+            Opcode::ExtCodeHash => {
+                let address = Address::from_word(&self.stack.pop().unwrap());
+                let account = self.chain.account(&address).await?;
+                // Empty accounts hash to zero, see EIP-1052
+                let hash = if account.is_empty() {
+                    U256::zero()
+                } else {
+                    account.code_hash
+                };
+                self.stack.push(hash);
+            }
             Opcode::StaticCall => {
                 let initial_gas = self.stack.pop().unwrap().as_usize();
                 let address = self.stack.pop().unwrap();
@@ -213,14 +338,15 @@ impl<'a> ExecutionState<'a> {
                 };
                 // TODO: Print using bytes4-dictionary based ABI decoder.
                 info!("Calling {:?} {}", &call.address, hex::encode(&call.input));
-                let result = evaluate(self.chain, self.block, self.transaction, &call);
+                let result =
+                    evaluate(&mut *self.chain, self.block, self.transaction, &call).await?;
                 self.stack.push(match result {
                     ExecutionResult::Return(_) => U256::one(),
-                    ExecutionResult::Revert(_) => U256::zero(),
+                    ExecutionResult::Revert(_) | ExecutionResult::OutOfGas => U256::zero(),
                 });
                 self.return_data = match result {
-                    ExecutionResult::Return(a) => a,
-                    ExecutionResult::Revert(a) => a,
+                    ExecutionResult::Return(a) | ExecutionResult::Revert(a) => a,
+                    ExecutionResult::OutOfGas => Vec::new(),
                 };
                 // TODO: Use slice::fill
                 for byte in self.memory[out_offset..out_offset + out_size].iter_mut() {
@@ -235,23 +361,20 @@ impl<'a> ExecutionState<'a> {
                 let size = self.stack.pop().unwrap().as_usize();
                 let return_data = &self.memory[offset..offset + size];
                 println!("Return 0x{}", hex::encode(return_data));
-                return Some(ExecutionResult::Return(return_data.to_vec()));
+                return Ok(Some(ExecutionResult::Return(return_data.to_vec())));
             }
             Opcode::Revert => {
                 let offset = self.stack.pop().unwrap().as_usize();
                 let size = self.stack.pop().unwrap().as_usize();
                 let return_data = &self.memory[offset..offset + size];
                 println!("Revert 0x{}", hex::encode(return_data));
-                return Some(ExecutionResult::Revert(return_data.to_vec()));
-            }
-            Opcode::Gas => {
-                // Fake no gas consumption
-                self.stack.push(U256::from(self.gas))
+                return Ok(Some(ExecutionResult::Revert(return_data.to_vec())));
             }
+            Opcode::Gas => self.stack.push(U256::from(self.gas)),
             op => todo!("opcode {:?} is not yet implemented", op),
         };
 
-        None
+        Ok(None)
     }
 
     fn op1<F, T>(&mut self, f: F)
@@ -303,3 +426,50 @@ impl<'a> ExecutionState<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        chain::{Empty, Fork, WriteableChainState},
+        test::prelude::assert_eq,
+    };
+
+    #[tokio::test]
+    async fn test_optimized_block() {
+        // PUSH1 0 CALLDATALOAD PUSH20 0xff..ff AND PUSH1 1 PUSH1 2 ADD ADD PUSH1 0
+        // MSTORE PUSH1 0x20 PUSH1 0 RETURN
+        let mut code = hex!("600035").to_vec();
+        code.push(0x73);
+        code.extend_from_slice(&[0xff; 20]);
+        code.extend_from_slice(&hex!("1660016002010160005260206000f3"));
+        let program = optimized(&code).unwrap();
+        assert!(program.blocks[&0]
+            .instructions
+            .contains(&Instruction::AddressMask));
+
+        let address = U256::from(1_u64);
+        let mut chain = Fork::from(Empty);
+        chain.set_code(&Address::from_word(&address), &code);
+        let call = CallInfo {
+            address,
+            initial_gas: 1000,
+            input: vec![0xff; 32],
+            ..CallInfo::default()
+        };
+        let (result, gas) = evaluate_with_gas(
+            &mut chain,
+            &BlockInfo::default(),
+            &TransactionInfo::default(),
+            &call,
+        )
+        .await
+        .unwrap();
+        let expected = address_mask() + U256::from(3_u64);
+        match result {
+            ExecutionResult::Return(data) => assert_eq!(data, expected.to_bytes_be().to_vec()),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        assert_eq!(gas, 1000 - Block::from(&code[..]).gas_cost());
+    }
+}
@@ -0,0 +1,313 @@
+//! Ahead-of-time compilation
+//!
+//! Compiles a [`Program`] to a relocatable object using Cranelift's object
+//! backend and caches the result on disk. Artifacts are keyed by code hash,
+//! Sutro version and target ISA. Artifacts with a different key are discarded
+//! when the cache is opened, the others are mapped as executable [`Compiled`]
+//! code.
+//!
+//! Compiled code does not model memory, calls or storage yet, so the RPC server
+//! still executes calls in the interpreter.
+
+use super::{Compiled, Program};
+use crate::{prelude::*, utils::keccak256};
+use cranelift::{
+    codegen::{binemit::NullTrapSink, isa::TargetIsa},
+    prelude::*,
+};
+use cranelift_module::{Linkage, Module};
+use cranelift_object::{ObjectBuilder, ObjectModule};
+use object::{Object, ObjectSection, ObjectSymbol};
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+};
+
+const EXTENSION: &str = "aot";
+
+/// Identifies a compiled artifact
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArtifactKey {
+    pub code_hash: U256,
+    pub version:   String,
+    pub isa:       String,
+}
+
+/// Version string that changes whenever code generation may have changed
+pub fn version() -> String {
+    format!("{}-{}", env!("CARGO_PKG_VERSION"), env!("COMMIT_SHA"))
+}
+
+/// The native ISA configured for position independent code
+pub fn host_isa() -> AnyResult<Box<dyn TargetIsa>> {
+    let mut flags = settings::builder();
+    flags
+        .set("is_pic", "true")
+        .map_err(|err| anyhow!("Error: {}", err))?;
+    let isa = cranelift_native::builder().map_err(|err| anyhow!("Error: {}", err))?;
+    Ok(isa.finish(settings::Flags::new(flags)))
+}
+
+/// Symbol name of the compiled function for a contract
+pub fn symbol_name(code_hash: &U256) -> String {
+    format!("sutro_{}", hex::encode(code_hash.to_bytes_be()))
+}
+
+/// Compile to a relocatable object exporting a single function `name`
+pub fn compile_object(
+    program: &Program,
+    isa: Box<dyn TargetIsa>,
+    name: &str,
+) -> AnyResult<Vec<u8>> {
+    let builder = ObjectBuilder::new(isa, name, cranelift_module::default_libcall_names())?;
+    let mut module = ObjectModule::new(builder);
+    let mut ctx = module.make_context();
+    Program::signature(
+        module.target_config().pointer_type(),
+        &mut ctx.func.signature,
+    );
+    {
+        let mut func_ctx = FunctionBuilderContext::new();
+        let mut builder = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+        program.render(&mut builder);
+    }
+    let func = module.declare_function(name, Linkage::Export, &ctx.func.signature)?;
+    let _compiled = module.define_function(func, &mut ctx, &mut NullTrapSink {})?;
+    module
+        .finish()
+        .emit()
+        .map_err(|err| anyhow!("Error: {}", err))
+        .context("Emitting object file")
+}
+
+/// Extract the machine code of the function `name` from an object file
+///
+/// Compiled programs only use relative jumps, so the code can run anywhere.
+/// Fails if the code needs relocating.
+pub fn function_code(object: &[u8], name: &str) -> AnyResult<Vec<u8>> {
+    let file = object::File::parse(object).map_err(|err| anyhow!("Error: {}", err))?;
+    // Mach-O prefixes symbol names with an underscore
+    let symbol = file
+        .symbols()
+        .find(|symbol| {
+            symbol
+                .name()
+                .map_or(false, |symbol| symbol.trim_start_matches('_') == name)
+        })
+        .ok_or_else(|| anyhow!("Missing symbol {}", name))?;
+    let section = symbol
+        .section_index()
+        .ok_or_else(|| anyhow!("Undefined symbol {}", name))
+        .and_then(|index| {
+            file.section_by_index(index)
+                .map_err(|err| anyhow!("Error: {}", err))
+        })?;
+    require!(
+        section.relocations().next().is_none(),
+        anyhow!("Code of {} needs relocating", name)
+    );
+    let data = section.data().map_err(|err| anyhow!("Error: {}", err))?;
+    let start = (symbol.address() - section.address()) as usize;
+    let end = start + symbol.size() as usize;
+    let code = data
+        .get(start..end)
+        .ok_or_else(|| anyhow!("Symbol {} outside of its section", name))?;
+    Ok(code.to_vec())
+}
+
+/// A cached object file and its code mapped for execution
+#[derive(Debug)]
+pub struct Artifact {
+    pub object:   Vec<u8>,
+    pub compiled: Compiled,
+}
+
+impl Artifact {
+    pub fn load(code_hash: &U256, object: Vec<u8>) -> AnyResult<Self> {
+        let code = function_code(&object, &symbol_name(code_hash))?;
+        let compiled = Compiled::from_code(&code)?;
+        Ok(Self { object, compiled })
+    }
+}
+
+/// On-disk cache of compiled artifacts
+///
+/// Each artifact is stored in its own file as a single line JSON
+/// [`ArtifactKey`] followed by the object file.
+#[derive(Debug)]
+pub struct ArtifactCache {
+    directory: PathBuf,
+    version:   String,
+    isa:       String,
+    artifacts: HashMap<U256, Artifact>,
+}
+
+impl ArtifactCache {
+    /// Open the cache for the host ISA and load all valid artifacts
+    pub fn open(directory: PathBuf) -> AnyResult<Self> {
+        let isa = host_isa()?.triple().to_string();
+        Self::open_with(directory, version(), isa)
+    }
+
+    pub fn open_with(directory: PathBuf, version: String, isa: String) -> AnyResult<Self> {
+        fs::create_dir_all(&directory)
+            .with_context(|| format!("Creating {}", directory.display()))?;
+        let mut result = Self {
+            directory,
+            version,
+            isa,
+            artifacts: HashMap::new(),
+        };
+        for entry in fs::read_dir(&result.directory)? {
+            let path = entry?.path();
+            if path.extension() != Some(OsStr::new(EXTENSION)) {
+                continue;
+            }
+            let artifact = read_artifact(&path).and_then(|(key, object)| {
+                require!(result.is_current(&key, &path), anyhow!("Stale artifact"));
+                Ok((
+                    key.code_hash.clone(),
+                    Artifact::load(&key.code_hash, object)?,
+                ))
+            });
+            match artifact {
+                Ok((code_hash, artifact)) => {
+                    let _previous = result.artifacts.insert(code_hash, artifact);
+                }
+                Err(err) => {
+                    debug!("Discarding artifact {}: {:#}", path.display(), err);
+                    if let Err(err) = fs::remove_file(&path) {
+                        warn!("Removing artifact {}: {}", path.display(), err);
+                    }
+                }
+            }
+        }
+        info!(
+            "Loaded {} compiled artifacts from {}",
+            result.artifacts.len(),
+            result.directory.display()
+        );
+        Ok(result)
+    }
+
+    pub fn len(&self) -> usize {
+        self.artifacts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.artifacts.is_empty()
+    }
+
+    pub fn get(&self, code_hash: &U256) -> Option<&Artifact> {
+        self.artifacts.get(code_hash)
+    }
+
+    /// Store an artifact for the current version and ISA
+    ///
+    /// Fails without storing if the object can not be loaded.
+    pub fn insert(&mut self, code_hash: U256, object: Vec<u8>) -> AnyResult<()> {
+        let artifact = Artifact::load(&code_hash, object)?;
+        let key = ArtifactKey {
+            code_hash: code_hash.clone(),
+            version:   self.version.clone(),
+            isa:       self.isa.clone(),
+        };
+        let mut contents = serde_json::to_vec(&key)?;
+        contents.push(b'\n');
+        contents.extend_from_slice(&artifact.object);
+
+        // Write to a temporary file first so readers never see partial files
+        let path = self.path(&code_hash);
+        let temp = path.with_extension("tmp");
+        fs::write(&temp, &contents).with_context(|| format!("Writing {}", temp.display()))?;
+        fs::rename(&temp, &path)?;
+        let _previous = self.artifacts.insert(code_hash, artifact);
+        Ok(())
+    }
+
+    /// Return the cached artifact or compile the bytecode
+    pub fn get_or_compile(&mut self, bytecode: &[u8]) -> AnyResult<&Artifact> {
+        let code_hash = keccak256(bytecode);
+        if !self.artifacts.contains_key(&code_hash) {
+            info!("Compiling {} bytes of code", bytecode.len());
+            let mut program = Program::from(bytecode.to_vec())?;
+            program.optimize();
+            let object = compile_object(&program, host_isa()?, &symbol_name(&code_hash))?;
+            self.insert(code_hash.clone(), object)?;
+        }
+        Ok(&self.artifacts[&code_hash])
+    }
+
+    fn path(&self, code_hash: &U256) -> PathBuf {
+        self.directory
+            .join(hex::encode(code_hash.to_bytes_be()))
+            .with_extension(EXTENSION)
+    }
+
+    fn is_current(&self, key: &ArtifactKey, path: &Path) -> bool {
+        key.version == self.version && key.isa == self.isa && self.path(&key.code_hash) == path
+    }
+}
+
+fn read_artifact(path: &Path) -> AnyResult<(ArtifactKey, Vec<u8>)> {
+    let contents = fs::read(path)?;
+    let newline = contents
+        .iter()
+        .position(|byte| *byte == b'\n')
+        .ok_or_else(|| anyhow!("Missing artifact header"))?;
+    let key = serde_json::from_slice(&contents[..newline])?;
+    Ok((key, contents[newline + 1..].to_vec()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        evm::jit::{Context, ExitCode},
+        test::prelude::assert_eq,
+    };
+
+    fn open(directory: &Path, version: &str) -> ArtifactCache {
+        ArtifactCache::open_with(directory.into(), version.into(), "test-isa".into()).unwrap()
+    }
+
+    #[test]
+    fn test_artifact_cache() {
+        let directory = std::env::temp_dir().join(format!("sutro-aot-{}", std::process::id()));
+        // PUSH1 0 PUSH1 0 JUMPDEST PUSH1 1 ADD RETURN
+        let bytecode = hex!("600060005b600101f3");
+        let code_hash = keccak256(&bytecode);
+        let mut cache = open(&directory, "1");
+        let object = cache.get_or_compile(&bytecode).unwrap().object.clone();
+
+        // Reloaded with the same key and runs like freshly compiled code
+        let cache = open(&directory, "1");
+        let artifact = cache.get(&code_hash).unwrap();
+        assert_eq!(artifact.object, object);
+        let jit = Program::from(bytecode.to_vec()).unwrap().compile().unwrap();
+        for gas in &[5, 100] {
+            let mut expected = Context::new(*gas);
+            let mut context = Context::new(*gas);
+            assert_eq!(artifact.compiled.run(&mut context), jit.run(&mut expected));
+            assert_eq!(context.gas, expected.gas);
+        }
+        let mut context = Context::new(100);
+        assert_eq!(artifact.compiled.run(&mut context), ExitCode::Return);
+        assert_eq!(context.gas, 100 - 13);
+
+        // Discarded on version change
+        let cache = open(&directory, "2");
+        assert!(cache.is_empty());
+        let cache = open(&directory, "1");
+        assert!(cache.is_empty());
+
+        // Objects that can not be loaded are not stored
+        let mut cache = open(&directory, "1");
+        assert!(cache.insert(code_hash, vec![1, 2, 3]).is_err());
+        assert!(cache.is_empty());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -1,4 +1,4 @@
-use super::{Error, Instruction, Opcode};
+use super::{Context, Error, ExitCode, Instruction, Map, Opcode};
 use crate::prelude::*;
 use cranelift::prelude::{Block as JitBlock, *};
 use std::collections::HashSet;
@@ -65,14 +65,44 @@ impl Block {
         Self { instructions }
     }
 
+    /// Static gas of all instructions in the block
+    ///
+    /// This is charged in a single check on block entry.
     pub fn gas_cost(&self) -> usize {
         let mut result = 0;
         for inst in &self.instructions {
-            result += inst.opcode().map_or(0, Opcode::base_gas);
+            result += inst.base_gas();
         }
         result
     }
 
+    /// Static gas charged on block entry for instructions after `index`
+    ///
+    /// Opcodes that observe the remaining gas (`GAS` and the calls) need to
+    /// add this back to see the same value as the interpreter.
+    pub fn prepaid_gas(&self, index: usize) -> usize {
+        self.instructions[index + 1..]
+            .iter()
+            .map(Instruction::base_gas)
+            .sum()
+    }
+
+    /// Program counters of blocks control can continue in
+    ///
+    /// Only contains jump destinations found during control flow recovery.
+    pub fn successors(&self) -> Vec<usize> {
+        match self.instructions.last() {
+            Some(Instruction::Jump(branch_set)) => branch_set.iter().copied().sorted().collect(),
+            Some(Instruction::CondJump(branch_set, fallthrough)) => {
+                std::iter::once(*fallthrough)
+                    .chain(branch_set.iter().copied().sorted())
+                    .collect()
+            }
+            Some(Instruction::Fallthrough(fallthrough)) => vec![*fallthrough],
+            _ => vec![],
+        }
+    }
+
     pub fn apply(&self, stack: &mut Vec<Option<U256>>) {
         for inst in &self.instructions {
             inst.apply(stack).unwrap();
@@ -118,14 +148,150 @@ impl Block {
         })
     }
 
-    pub fn render<'a>(&self, builder: &mut FunctionBuilder<'a>) -> JitBlock {
-        let block = builder.create_block();
+    /// Lower the block starting at `pc` into `targets[pc]`
+    pub fn render<'a>(
+        &self,
+        builder: &mut FunctionBuilder<'a>,
+        targets: &Map<usize, JitBlock>,
+        pc: usize,
+        context: Value,
+        out_of_gas: JitBlock,
+    ) {
+        let block = targets[&pc];
         builder.switch_to_block(block);
         builder.seal_block(block);
-        for inst in &self.instructions {
-            inst.render(builder)
+
+        // Precharge the static gas for the whole block. If this fails part way
+        // through the block the interpreter would also have run out of gas
+        // before leaving the block. Since out of gas consumes all gas the
+        // results are identical.
+        let cost = builder.ins().iconst(types::I64, self.gas_cost() as i64);
+        charge_gas(builder, context, cost, out_of_gas);
+
+        for (index, inst) in self.instructions.iter().enumerate() {
+            if !inst.render(builder, targets, context, self.prepaid_gas(index)) {
+                return;
+            }
+        }
+        // Blocks end in a block-final instruction
+        builder.ins().trap(TrapCode::UnreachableCodeReached);
+    }
+}
+
+/// Subtract `cost` from the remaining gas or exit to `out_of_gas`
+///
+/// Only the static gas of a block is charged, on block entry. Instructions with
+/// dynamic costs, such as memory and account access, are not compiled yet.
+/// Continues in a new block.
+pub fn charge_gas<'a>(
+    builder: &mut FunctionBuilder<'a>,
+    context: Value,
+    cost: Value,
+    out_of_gas: JitBlock,
+) {
+    let gas = builder.ins().load(
+        types::I64,
+        MemFlags::trusted(),
+        context,
+        Context::GAS_OFFSET,
+    );
+    let insufficient = builder.ins().icmp(IntCC::UnsignedLessThan, gas, cost);
+    let remaining = builder.ins().isub(gas, cost);
+    let next = builder.create_block();
+    builder.ins().brnz(insufficient, out_of_gas, &[]);
+    builder.ins().jump(next, &[]);
+    builder.switch_to_block(next);
+    builder.seal_block(next);
+    builder
+        .ins()
+        .store(MemFlags::trusted(), remaining, context, Context::GAS_OFFSET);
+}
+
+/// Exit with [`ExitCode::OutOfGas`], consuming all gas
+pub fn render_out_of_gas<'a>(builder: &mut FunctionBuilder<'a>, block: JitBlock, context: Value) {
+    builder.switch_to_block(block);
+    builder.seal_block(block);
+    let zero = builder.ins().iconst(types::I64, 0);
+    builder
+        .ins()
+        .store(MemFlags::trusted(), zero, context, Context::GAS_OFFSET);
+    let exit_code = builder.ins().iconst(types::I32, ExitCode::OutOfGas as i64);
+    builder.ins().return_(&[exit_code]);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        chain::{types::Address, BlockInfo, Empty, Fork, WriteableChainState},
+        evm::{
+            interpreter::evaluate_with_gas, jit::Program, CallInfo, ExecutionResult,
+            TransactionInfo,
+        },
+        test::prelude::assert_eq,
+    };
+
+    // PUSH1 0 PUSH1 0 JUMPDEST PUSH1 1 ADD RETURN
+    const BYTECODE: [u8; 9] = hex!("600060005b600101f3");
+
+    #[test]
+    fn test_gas_cost() {
+        let first = Block::from(&BYTECODE[..]);
+        assert_eq!(first.gas_cost(), 6);
+        assert_eq!(first.prepaid_gas(0), 3);
+        let second = Block::from_pc(&BYTECODE, 4);
+        assert_eq!(second.gas_cost(), 1 + 3 + 3 + 0);
+        assert_eq!(second.prepaid_gas(0), 6);
+        assert_eq!(second.prepaid_gas(2), 0);
+    }
+
+    async fn interpret(bytecode: &[u8], initial_gas: usize) -> (ExecutionResult, usize) {
+        let address = U256::from(1_u64);
+        let mut chain = Fork::from(Empty);
+        chain.set_code(&Address::from_word(&address), bytecode);
+        let call = CallInfo {
+            address,
+            initial_gas,
+            ..CallInfo::default()
+        };
+        evaluate_with_gas(
+            &mut chain,
+            &BlockInfo::default(),
+            &TransactionInfo::default(),
+            &call,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_out_of_gas_matches_interpreter() {
+        let compiled = Program::from(BYTECODE.to_vec()).unwrap().compile().unwrap();
+        let total = Block::from(&BYTECODE[..]).gas_cost() + Block::from_pc(&BYTECODE, 4).gas_cost();
+        for initial_gas in 0..=total + 1 {
+            let (result, remaining) = interpret(&BYTECODE, initial_gas).await;
+            let mut context = Context::new(initial_gas as u64);
+            let exit = compiled.run(&mut context);
+            assert_eq!(exit == ExitCode::OutOfGas, initial_gas < total);
+            match result {
+                ExecutionResult::OutOfGas => assert_eq!(exit, ExitCode::OutOfGas),
+                ExecutionResult::Return(_) => assert_eq!(exit, ExitCode::Return),
+                ExecutionResult::Revert(_) => assert_eq!(exit, ExitCode::Revert),
+            }
+            assert_eq!(context.gas, remaining as u64);
         }
-        builder.ins().trap(TrapCode::User(0));
-        block
+    }
+
+    #[tokio::test]
+    async fn test_gas_undoes_precharge() {
+        // PUSH1 1 GAS PUSH1 0 DUP1 RETURN
+        let bytecode = hex!("60015a600080f3");
+        let compiled = Program::from(bytecode.to_vec()).unwrap().compile().unwrap();
+        let mut context = Context::new(100);
+        assert_eq!(compiled.run(&mut context), ExitCode::Return);
+        // GAS sees what the interpreter sees: only PUSH1 and GAS are charged.
+        assert_eq!(context.stack(), vec![U256::one(), U256::from(95_u64)]);
+        let (_, remaining) = interpret(&bytecode, 100).await;
+        assert_eq!(context.gas, remaining as u64);
     }
 }
@@ -0,0 +1,288 @@
+//! Solidity and Vyper function dispatcher recovery
+//!
+//! Compilers dispatch external calls by comparing the function selector
+//! against constants, either as a linear chain of `PUSH4 selector EQ JUMPI`
+//! or as a binary search using `GT`/`LT` before the `EQ` chains. Vyper uses
+//! `XOR` and jumps to the next comparison instead.
+
+use super::{Block, Instruction, Opcode, Program};
+use crate::prelude::*;
+use std::collections::HashSet;
+
+/// Number of non-dispatcher blocks to follow before giving up a path
+const MAX_HOPS: usize = 4;
+
+/// An external function found in the dispatcher
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Function {
+    pub selector: u32,
+    /// Program counter of the first block of the function
+    pub entry:    usize,
+    /// No `CALLVALUE` check guards the function
+    pub payable:  bool,
+    /// No reachable block modifies state
+    pub view:     bool,
+}
+
+impl Program {
+    /// Recover the external functions from the dispatcher.
+    ///
+    /// Payability and view-ness are heuristics. Since internal functions are
+    /// shared between external functions, `view` can be a false negative.
+    pub fn functions(&self) -> Vec<Function> {
+        // A `CALLVALUE` check on the path to a function guards only that
+        // function, so it is tracked per path.
+        let mut entries = Vec::new();
+        let mut visited = HashSet::new();
+        let mut queue = vec![(0, 0, false)];
+        while let Some((pc, hops, checked)) = queue.pop() {
+            if !visited.insert((pc, checked)) {
+                continue;
+            }
+            let block = match self.blocks.get(&pc) {
+                Some(block) => block,
+                None => continue,
+            };
+            match selector_comparison(block, &self.opcodes(pc, block)) {
+                Some((Opcode::Eq, selector, target, fallthrough)) => {
+                    entries.push((selector, target, checked));
+                    queue.push((fallthrough, 0, checked));
+                }
+                Some((Opcode::Xor, selector, target, fallthrough)) => {
+                    entries.push((selector, fallthrough, checked));
+                    queue.push((target, 0, checked));
+                }
+                Some((_, _, target, fallthrough)) => {
+                    queue.push((target, 0, checked));
+                    queue.push((fallthrough, 0, checked));
+                }
+                None if hops < MAX_HOPS => {
+                    let checked = checked || checks_call_value(block);
+                    queue.extend(
+                        block
+                            .successors()
+                            .into_iter()
+                            .map(|pc| (pc, hops + 1, checked)),
+                    );
+                }
+                None => {}
+            }
+        }
+        entries
+            .into_iter()
+            .sorted()
+            .dedup_by(|(selector, entry, _), (other_selector, other_entry, _)| {
+                selector == other_selector && entry == other_entry
+            })
+            .map(|(selector, entry, checked)| {
+                Function {
+                    selector,
+                    entry,
+                    payable: !checked && !self.blocks.get(&entry).map_or(false, checks_call_value),
+                    view: self.is_view(entry),
+                }
+            })
+            .collect()
+    }
+
+    /// Opcodes of the block at `pc`, one per instruction
+    ///
+    /// Unlike [`Instruction::Push`] these keep the width of each push.
+    fn opcodes(&self, mut pc: usize, block: &Block) -> Vec<Opcode> {
+        block
+            .instructions
+            .iter()
+            .map(|_| {
+                let opcode = self
+                    .bytecode
+                    .get(pc)
+                    .cloned()
+                    .map_or(Opcode::Stop, Opcode::from);
+                pc += match opcode {
+                    Opcode::Push(n) => 1 + n as usize,
+                    _ => 1,
+                };
+                opcode
+            })
+            .collect()
+    }
+
+    /// No block reachable from `entry` modifies state
+    fn is_view(&self, entry: usize) -> bool {
+        let mut visited = HashSet::new();
+        let mut queue = vec![entry];
+        while let Some(pc) = queue.pop() {
+            if !visited.insert(pc) {
+                continue;
+            }
+            if let Some(block) = self.blocks.get(&pc) {
+                if block.instructions.iter().any(modifies_state) {
+                    return false;
+                }
+                queue.extend(block.successors());
+            }
+        }
+        true
+    }
+}
+
+/// Match a block ending in `PUSH4 selector <cmp> PUSH target JUMPI`, allowing
+/// a `DUP` in between. Returns `(cmp, selector, target, fallthrough)`.
+///
+/// Only `PUSH4` is recognized, so selectors with leading zero bytes are found
+/// while narrower constants like the `CALLDATASIZE` check are not. `opcodes`
+/// are the block's [`Program::opcodes`].
+fn selector_comparison(block: &Block, opcodes: &[Opcode]) -> Option<(Opcode, u32, usize, usize)> {
+    use Instruction::{CondJump, Push};
+    let instructions = &block.instructions;
+    let len = instructions.len();
+    if len < 4 {
+        return None;
+    }
+    let (target, fallthrough, comparison) = match &instructions[len - 3..] {
+        [Instruction::Opcode(comparison), Push(target), CondJump(_, fallthrough)]
+            if target.bits() < 32 =>
+        {
+            (target.as_usize(), *fallthrough, *comparison)
+        }
+        _ => return None,
+    };
+    if !matches!(
+        comparison,
+        Opcode::Eq | Opcode::Xor | Opcode::Gt | Opcode::Lt
+    ) {
+        return None;
+    }
+    let start = len.saturating_sub(5);
+    let selector = instructions[start..len - 3]
+        .iter()
+        .zip(&opcodes[start..len - 3])
+        .find_map(|(inst, opcode)| {
+            match (inst, opcode) {
+                (Push(value), Opcode::Push(4)) => Some(value.as_usize() as u32),
+                _ => None,
+            }
+        })?;
+    Some((comparison, selector, target, fallthrough))
+}
+
+/// Block reverts on non-zero `CALLVALUE`
+fn checks_call_value(block: &Block) -> bool {
+    let instructions = &block.instructions;
+    let call_value = Instruction::Opcode(Opcode::CallValue);
+    let is_zero = Instruction::Opcode(Opcode::IsZero);
+    match instructions.iter().position(|inst| *inst == call_value) {
+        Some(position) => {
+            instructions[position..].contains(&is_zero)
+                && matches!(instructions.last(), Some(Instruction::CondJump(..)))
+        }
+        None => false,
+    }
+}
+
+fn modifies_state(inst: &Instruction) -> bool {
+    matches!(
+        inst,
+        Instruction::Opcode(
+            Opcode::SStore
+                | Opcode::Log(_)
+                | Opcode::Create
+                | Opcode::Create2
+                | Opcode::Call
+                | Opcode::CallCode
+                | Opcode::DelegateCall
+                | Opcode::SelfDestruct
+        )
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_linear_dispatcher() {
+        // Two functions: a non-payable `transfer` that writes storage and a
+        // payable `balanceOf` that only reads.
+        let bytecode = hex!(
+            "6004361060215760003560e01c8063a9059cbb14602657806370a08231146039575b600080fd5b348015603157600080fd5b506001600055005b60005460005260206000f3"
+        );
+        let program = Program::from(bytecode.to_vec()).unwrap();
+        assert_eq!(program.functions(), vec![
+            Function {
+                selector: 0x70a0_8231,
+                entry:    0x39,
+                payable:  true,
+                view:     true,
+            },
+            Function {
+                selector: 0xa905_9cbb,
+                entry:    0x26,
+                payable:  false,
+                view:     false,
+            },
+        ]);
+    }
+
+    #[test]
+    fn test_leading_zero_selector() {
+        // `PUSH4 0x00000001 EQ` is a selector despite its small value.
+        let bytecode = hex!("60003560e01c80630000000114601157005b00");
+        let program = Program::from(bytecode.to_vec()).unwrap();
+        assert_eq!(program.functions(), vec![Function {
+            selector: 0x0000_0001,
+            entry:    0x11,
+            payable:  true,
+            view:     true,
+        }]);
+    }
+
+    #[test]
+    fn test_binary_search_dispatcher() {
+        // Splits on `0x70a08231 GT` into `transfer` and `approve`. Only
+        // `transfer` checks `CALLVALUE`, the check in the fallback guards no
+        // function.
+        let bytecode = hex!(
+            "60003560e01c806370a0823111601d578063a9059cbb14603a57602b565b8063095ea7b314603857602b565b348015603657600080fd5b005b005b348015604557600080fd5b50600160005500"
+        );
+        let program = Program::from(bytecode.to_vec()).unwrap();
+        assert_eq!(program.functions(), vec![
+            Function {
+                selector: 0x095e_a7b3,
+                entry:    0x38,
+                payable:  true,
+                view:     true,
+            },
+            Function {
+                selector: 0xa905_9cbb,
+                entry:    0x3a,
+                payable:  false,
+                view:     false,
+            },
+        ]);
+    }
+
+    #[test]
+    fn test_vyper_dispatcher() {
+        // A `CALLVALUE` check before the `XOR` chain guards every function.
+        let bytecode = hex!(
+            "3415600957600080fd5b60003560e01c63a9059cbb81186020576001600055005b6370a08231811860365760005460005260206000f35b600080fd"
+        );
+        let program = Program::from(bytecode.to_vec()).unwrap();
+        assert_eq!(program.functions(), vec![
+            Function {
+                selector: 0x70a0_8231,
+                entry:    0x2b,
+                payable:  false,
+                view:     true,
+            },
+            Function {
+                selector: 0xa905_9cbb,
+                entry:    0x1a,
+                payable:  false,
+                view:     false,
+            },
+        ]);
+    }
+}
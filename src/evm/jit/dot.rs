@@ -0,0 +1,35 @@
+//! Graphviz export of the control flow graph
+//!
+//! See <https://graphviz.org/doc/info/lang.html>
+
+use super::Program;
+use itertools::Itertools;
+use std::io::{Result, Write};
+
+impl Program {
+    /// Write the control flow graph in DOT format
+    ///
+    /// Every block is labeled with its program counter, static gas cost and
+    /// instructions. Edges go to all resolved jump targets.
+    pub fn write_dot<W: Write>(&self, output: &mut W) -> Result<()> {
+        writeln!(output, "digraph program {{")?;
+        writeln!(output, "    node [shape=box fontname=monospace];")?;
+        for pc in self.blocks.keys().sorted() {
+            let block = &self.blocks[pc];
+            let mut label = format!("0x{:04x} ({} gas)\\l", pc, block.gas_cost());
+            for inst in &block.instructions {
+                label.push_str(&escape(&inst.to_string()));
+                label.push_str("\\l");
+            }
+            writeln!(output, "    b{} [label=\"{}\"];", pc, label)?;
+            for successor in block.successors() {
+                writeln!(output, "    b{} -> b{};", pc, successor)?;
+            }
+        }
+        writeln!(output, "}}")
+    }
+}
+
+fn escape(str: &str) -> String {
+    str.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -1,8 +1,9 @@
-use super::Error;
+use super::{optimize::fold, Context, Error, ExitCode, Map};
 use crate::evm::Opcode;
-use cranelift::prelude::*;
+use cranelift::prelude::{Block as JitBlock, *};
+use itertools::Itertools;
 use std::collections::HashSet;
-use zkp_u256::{Binary, U256};
+use zkp_u256::{Binary, One, U256};
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Instruction {
@@ -20,6 +21,15 @@ pub enum Instruction {
 
     // Fallthrough to next block
     Fallthrough(usize),
+
+    // Address masking `PUSH20 0xff..ff AND`
+    AddressMask,
+
+    // Function selector extraction `PUSH1 0xe0 SHR`
+    Selector,
+
+    // Static gas of instructions removed by the optimizer
+    Charge(usize),
 }
 
 impl std::fmt::Display for Instruction {
@@ -45,33 +55,83 @@ impl Instruction {
             Self::Push(value) => Some(Opcode::Push((1 + value.bits() / 8) as u8)),
             Self::Jump(_) => Some(Opcode::Jump),
             Self::CondJump(..) => Some(Opcode::JumpI),
-            Self::Fallthrough(_) => None,
+            Self::Fallthrough(_) | Self::AddressMask | Self::Selector | Self::Charge(_) => None,
+        }
+    }
+
+    /// Stack (consume, produce)
+    pub fn stack(&self) -> (usize, usize) {
+        match self {
+            Self::AddressMask | Self::Selector => (1, 1),
+            _ => self.opcode().map_or((0, 0), Opcode::stack),
         }
     }
 
+    /// Static gas, including that of any instructions this one replaces
+    pub fn base_gas(&self) -> usize {
+        match self {
+            Self::AddressMask => Opcode::Push(20).base_gas() + Opcode::And.base_gas(),
+            Self::Selector => Opcode::Push(1).base_gas() + Opcode::Shr.base_gas(),
+            Self::Charge(gas) => *gas,
+            _ => self.opcode().map_or(0, Opcode::base_gas),
+        }
+    }
+
+    /// Does the instruction observe the remaining gas
+    pub fn observes_gas(&self) -> bool {
+        matches!(
+            self,
+            Self::Opcode(
+                Opcode::Gas
+                    | Opcode::Call
+                    | Opcode::CallCode
+                    | Opcode::DelegateCall
+                    | Opcode::StaticCall
+                    | Opcode::Create
+                    | Opcode::Create2
+            )
+        )
+    }
+
     pub const fn is_block_final(&self) -> bool {
         match self {
             Self::Opcode(opcode) => opcode.is_block_final(),
-            Self::Push(_) => false,
+            Self::Push(_) | Self::AddressMask | Self::Selector | Self::Charge(_) => false,
             _ => true,
         }
     }
 
     /// Super simple symbolic executor
     pub fn apply(&self, stack: &mut Vec<Option<U256>>) -> Result<(), Error> {
-        let (pop, push) = self.opcode().map_or((0, 0), Opcode::stack);
+        let (pop, push) = self.stack();
         if pop > stack.len() {
             return Err(Error::StackUnderflow);
         }
         match self {
             Self::Push(value) => stack.push(Some(value.clone())),
+            Self::AddressMask => {
+                let value = stack.pop().unwrap();
+                stack.push(value.map(|value| value & address_mask()));
+            }
+            Self::Selector => {
+                let value = stack.pop().unwrap();
+                stack.push(value.map(|value| value >> 224_usize));
+            }
             Self::Opcode(Opcode::Dup(n)) => stack.push(stack[stack.len() - (*n as usize)].clone()),
             Self::Opcode(Opcode::Swap(n)) => {
                 let last = stack.len() - 1;
                 stack.swap(last, last - (*n as usize));
             }
-            Self::Fallthrough(_) => {}
+            Self::Fallthrough(_) | Self::Charge(_) => {}
             Self::Opcode(Opcode::Unknown(_)) => return Err(Error::InvalidOpcode),
+            Self::Opcode(opcode) if push == 1 => {
+                // Constant fold if all arguments are known
+                let args = stack
+                    .drain(stack.len() - pop..)
+                    .rev()
+                    .collect::<Option<Vec<_>>>();
+                stack.push(args.and_then(|args| fold(*opcode, &args)));
+            }
             _ => {
                 stack.truncate(stack.len() - pop);
                 stack.resize(stack.len() + push, None);
@@ -83,20 +143,226 @@ impl Instruction {
         Ok(())
     }
 
-    pub fn render<'a>(&self, builder: &mut FunctionBuilder<'a>) {
+    /// Lower to Cranelift IR
+    ///
+    /// `prepaid` is the static gas charged on block entry for the instructions
+    /// after this one. Opcodes the JIT does not support yet trap. Returns
+    /// `false` if the instruction ended the block.
+    pub fn render<'a>(
+        &self,
+        builder: &mut FunctionBuilder<'a>,
+        targets: &Map<usize, JitBlock>,
+        context: Value,
+        prepaid: usize,
+    ) -> bool {
         match self {
             Self::Push(value) => {
-                let stack_slot = builder.create_stack_slot(StackSlotData {
-                    kind:   StackSlotKind::ExplicitSlot,
-                    size:   32,
-                    offset: None,
-                });
-                for (i, limb) in value.as_limbs().iter().enumerate() {
-                    let x = builder.ins().iconst(types::I64, *limb as i64);
-                    builder.ins().stack_store(x, stack_slot, (i * 8) as i32);
-                }
+                let limbs = value.as_limbs();
+                let mut limb = |i: usize| builder.ins().iconst(types::I64, limbs[i] as i64);
+                let word = [limb(0), limb(1), limb(2), limb(3)];
+                push(builder, context, word);
+            }
+            Self::Opcode(Opcode::Pop) => resize_stack(builder, context, -1),
+            Self::Opcode(Opcode::Dup(n)) => {
+                let address = stack_address(builder, context, i64::from(*n) - 1);
+                let word = load_word(builder, address);
+                push(builder, context, word);
+            }
+            Self::Opcode(Opcode::Swap(n)) => {
+                let top = stack_address(builder, context, 0);
+                let other = stack_address(builder, context, i64::from(*n));
+                let top_word = load_word(builder, top);
+                let other_word = load_word(builder, other);
+                store_word(builder, top, other_word);
+                store_word(builder, other, top_word);
+            }
+            Self::Opcode(Opcode::Add) => {
+                let left = pop(builder, context);
+                let right = pop(builder, context);
+                let sum = add(builder, left, right);
+                push(builder, context, sum);
+            }
+            Self::AddressMask => {
+                // Keep the low 160 bits
+                let address = stack_address(builder, context, 0);
+                let word = load_word(builder, address);
+                let high = builder.ins().band_imm(word[2], 0xffff_ffff);
+                let zero = builder.ins().iconst(types::I64, 0);
+                store_word(builder, address, [word[0], word[1], high, zero]);
+            }
+            Self::Selector => {
+                // Shift right by 224 bits, leaving the top 32 bits
+                let address = stack_address(builder, context, 0);
+                let top = builder
+                    .ins()
+                    .load(types::I64, MemFlags::trusted(), address, 24);
+                let selector = builder.ins().ushr_imm(top, 32);
+                let zero = builder.ins().iconst(types::I64, 0);
+                store_word(builder, address, [selector, zero, zero, zero]);
+            }
+            Self::Opcode(Opcode::Gas) => {
+                // Undo the part of the block precharge that the interpreter would
+                // not have charged yet.
+                let gas = builder.ins().load(
+                    types::I64,
+                    MemFlags::trusted(),
+                    context,
+                    Context::GAS_OFFSET,
+                );
+                let gas = builder.ins().iadd_imm(gas, prepaid as i64);
+                let zero = builder.ins().iconst(types::I64, 0);
+                push(builder, context, [gas, zero, zero, zero]);
+            }
+            Self::Opcode(Opcode::JumpDest) | Self::Charge(_) => {}
+            Self::Opcode(Opcode::Stop) => {
+                exit(builder, ExitCode::Stop);
+                return false;
+            }
+            Self::Opcode(opcode @ (Opcode::Return | Opcode::Revert)) => {
+                // TODO: Memory is not modeled yet, so there is no return data.
+                resize_stack(builder, context, -2);
+                let code = if *opcode == Opcode::Return {
+                    ExitCode::Return
+                } else {
+                    ExitCode::Revert
+                };
+                exit(builder, code);
+                return false;
+            }
+            Self::Fallthrough(fallthrough) => {
+                builder.ins().jump(targets[fallthrough], &[]);
+                return false;
+            }
+            Self::Jump(branch_set) => {
+                let target = pop(builder, context)[0];
+                jump_to(builder, targets, target, branch_set);
+                return false;
+            }
+            Self::CondJump(branch_set, fallthrough) => {
+                let target = pop(builder, context)[0];
+                let condition = pop(builder, context);
+                let low = builder.ins().bor(condition[0], condition[1]);
+                let high = builder.ins().bor(condition[2], condition[3]);
+                let condition = builder.ins().bor(low, high);
+                let taken = builder.create_block();
+                builder.ins().brnz(condition, taken, &[]);
+                builder.ins().jump(targets[fallthrough], &[]);
+                builder.switch_to_block(taken);
+                builder.seal_block(taken);
+                jump_to(builder, targets, target, branch_set);
+                return false;
+            }
+            _ => {
+                builder.ins().trap(TrapCode::User(0));
+                return false;
             }
-            _ => {} // todo!(),
         }
+        true
+    }
+}
+
+/// Address of the stack word `depth` below the top
+///
+/// The top of the stack is at depth zero, the first free slot at depth `-1`.
+fn stack_address<'a>(builder: &mut FunctionBuilder<'a>, context: Value, depth: i64) -> Value {
+    let flags = MemFlags::trusted();
+    let base = builder
+        .ins()
+        .load(types::I64, flags, context, Context::STACK_OFFSET);
+    let size = builder
+        .ins()
+        .load(types::I64, flags, context, Context::STACK_SIZE_OFFSET);
+    let index = builder.ins().iadd_imm(size, -1 - depth);
+    let offset = builder.ins().ishl_imm(index, 5);
+    builder.ins().iadd(base, offset)
+}
+
+fn resize_stack<'a>(builder: &mut FunctionBuilder<'a>, context: Value, delta: i64) {
+    let flags = MemFlags::trusted();
+    let size = builder
+        .ins()
+        .load(types::I64, flags, context, Context::STACK_SIZE_OFFSET);
+    let size = builder.ins().iadd_imm(size, delta);
+    builder
+        .ins()
+        .store(flags, size, context, Context::STACK_SIZE_OFFSET);
+}
+
+fn load_word<'a>(builder: &mut FunctionBuilder<'a>, address: Value) -> [Value; 4] {
+    let mut limb = |i: i32| {
+        builder
+            .ins()
+            .load(types::I64, MemFlags::trusted(), address, 8 * i)
+    };
+    [limb(0), limb(1), limb(2), limb(3)]
+}
+
+fn store_word<'a>(builder: &mut FunctionBuilder<'a>, address: Value, word: [Value; 4]) {
+    for (i, limb) in word.iter().enumerate() {
+        builder
+            .ins()
+            .store(MemFlags::trusted(), *limb, address, 8 * i as i32);
     }
 }
+
+fn push<'a>(builder: &mut FunctionBuilder<'a>, context: Value, word: [Value; 4]) {
+    let address = stack_address(builder, context, -1);
+    store_word(builder, address, word);
+    resize_stack(builder, context, 1);
+}
+
+fn pop<'a>(builder: &mut FunctionBuilder<'a>, context: Value) -> [Value; 4] {
+    let address = stack_address(builder, context, 0);
+    let word = load_word(builder, address);
+    resize_stack(builder, context, -1);
+    word
+}
+
+/// Wrapping 256 bit addition
+fn add<'a>(builder: &mut FunctionBuilder<'a>, left: [Value; 4], right: [Value; 4]) -> [Value; 4] {
+    let mut result = left;
+    let mut carry = builder.ins().iconst(types::I64, 0);
+    for ((result, left), right) in result.iter_mut().zip(&left).zip(&right) {
+        let sum = builder.ins().iadd(*left, *right);
+        let overflow = builder.ins().icmp(IntCC::UnsignedLessThan, sum, *left);
+        let overflow = builder.ins().bint(types::I64, overflow);
+        *result = builder.ins().iadd(sum, carry);
+        let carried = builder.ins().icmp(IntCC::UnsignedLessThan, *result, sum);
+        let carried = builder.ins().bint(types::I64, carried);
+        carry = builder.ins().bor(overflow, carried);
+    }
+    result
+}
+
+fn exit<'a>(builder: &mut FunctionBuilder<'a>, code: ExitCode) {
+    let code = builder.ins().iconst(types::I32, code as i64);
+    builder.ins().return_(&[code]);
+}
+
+/// Jump to `target`, which control flow recovery found to be in `branch_set`
+///
+/// Only the least significant limb is compared since recovery already proved
+/// the target is one of these small constants.
+fn jump_to<'a>(
+    builder: &mut FunctionBuilder<'a>,
+    targets: &Map<usize, JitBlock>,
+    target: Value,
+    branch_set: &HashSet<usize>,
+) {
+    for destination in branch_set.iter().sorted() {
+        let matches = builder
+            .ins()
+            .icmp_imm(IntCC::Equal, target, *destination as i64);
+        let next = builder.create_block();
+        builder.ins().brnz(matches, targets[destination], &[]);
+        builder.ins().jump(next, &[]);
+        builder.switch_to_block(next);
+        builder.seal_block(next);
+    }
+    builder.ins().trap(TrapCode::UnreachableCodeReached);
+}
+
+/// The mask `2^160 - 1` used to clean addresses
+pub fn address_mask() -> U256 {
+    (U256::one() << 160_usize) - U256::one()
+}
@@ -1,19 +1,136 @@
+pub mod aot;
 mod block;
+mod dispatcher;
+mod dot;
 mod error;
 mod instruction;
+mod optimize;
 
 use crate::evm::Opcode;
-use block::Block;
-use cranelift::prelude::*;
+pub use block::Block;
+use cranelift::{codegen::binemit::NullTrapSink, prelude::*};
 use cranelift_jit::{JITBuilder, JITModule};
-use cranelift_module::Module;
-use error::Error;
-use hex_literal::hex;
-use instruction::Instruction;
+use cranelift_module::{FuncId, Linkage, Module};
+pub use dispatcher::Function;
+pub use error::Error;
+pub use instruction::{address_mask, Instruction};
+use std::{collections::HashSet, rc::Rc};
 use zkp_u256::U256;
 
 type Map<K, V> = std::collections::HashMap<K, V>;
 
+/// Maximum number of words on the EVM stack
+pub const STACK_LIMIT: usize = 1024;
+
+/// Execution context shared between compiled code and the host
+///
+/// Compiled code takes a pointer to this as its only argument. Stack words are
+/// stored as little-endian `u64` limbs, like [`U256::as_limbs`].
+#[repr(C)]
+#[derive(Clone, Debug)]
+pub struct Context {
+    pub gas:        u64,
+    pub stack_size: u64,
+    pub stack:      Box<[[u64; 4]; STACK_LIMIT]>,
+}
+
+impl Context {
+    pub const GAS_OFFSET: i32 = 0;
+    pub const STACK_OFFSET: i32 = 16;
+    pub const STACK_SIZE_OFFSET: i32 = 8;
+
+    pub fn new(gas: u64) -> Self {
+        Self {
+            gas,
+            ..Self::default()
+        }
+    }
+
+    /// The stack from bottom to top
+    pub fn stack(&self) -> Vec<U256> {
+        self.stack[..self.stack_size as usize]
+            .iter()
+            .map(|limbs| U256::from_limbs(*limbs))
+            .collect()
+    }
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Self {
+            gas:        0,
+            stack_size: 0,
+            stack:      Box::new([[0; 4]; STACK_LIMIT]),
+        }
+    }
+}
+
+/// Return value of compiled code
+#[repr(i32)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExitCode {
+    Stop     = 0,
+    Return   = 1,
+    Revert   = 2,
+    OutOfGas = 3,
+}
+
+impl ExitCode {
+    fn from_i32(code: i32) -> Self {
+        match code {
+            0 => Self::Stop,
+            1 => Self::Return,
+            2 => Self::Revert,
+            3 => Self::OutOfGas,
+            _ => panic!("Invalid exit code {}", code),
+        }
+    }
+}
+
+/// Native code of a [`Program`] for the host
+pub struct Compiled {
+    // Owns the executable memory `function` points into
+    module:   JITModule,
+    function: *const u8,
+}
+
+impl Compiled {
+    /// Load machine code that implements [`Program::signature`]
+    ///
+    /// The code must be position independent and free of relocations.
+    pub fn from_code(code: &[u8]) -> anyhow::Result<Self> {
+        let mut module = JITModule::new(JITBuilder::new(cranelift_module::default_libcall_names()));
+        let mut signature = module.make_signature();
+        Program::signature(module.target_config().pointer_type(), &mut signature);
+        let id = module.declare_function("main", Linkage::Export, &signature)?;
+        let _compiled = module.define_function_bytes(id, code, &[])?;
+        Ok(Self::finalize(module, id))
+    }
+
+    fn finalize(mut module: JITModule, id: FuncId) -> Self {
+        module.finalize_definitions();
+        let function = module.get_finalized_function(id);
+        Self { module, function }
+    }
+
+    /// Run until the code exits, updating gas and stack in `context`
+    pub fn run(&self, context: &mut Context) -> ExitCode {
+        // SAFETY: `function` was compiled with `Program::signature` and is kept
+        // alive by `module`.
+        let function: extern "C" fn(*mut Context) -> i32 =
+            unsafe { std::mem::transmute(self.function) };
+        ExitCode::from_i32(function(context))
+    }
+}
+
+impl std::fmt::Debug for Compiled {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Compiled")
+            .field("function", &self.function)
+            .finish()
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq, Default)]
 pub struct Program {
     pub bytecode: Vec<u8>,
@@ -31,61 +148,195 @@ impl Program {
     }
 
     fn recover_control_flow(&mut self, pc: usize, stack: Vec<Option<U256>>) -> Result<(), Error> {
-        // Decompile block if not done already
-        if !self.blocks.contains_key(&pc) {
-            let block = Block::from_pc(&self.bytecode, pc);
-            self.blocks.insert(pc, block);
-        }
-        let block = &mut self.blocks.get_mut(&pc).unwrap();
+        // Visit each block once for every distinct abstract stack it can be
+        // entered with. Constant folding would give a counted loop a new stack
+        // on every iteration, so when a path re-enters a block the values that
+        // changed since are widened to unknown. Loops that grow the stack end
+        // in `Error::StackOverflow`.
+        let mut visited = HashSet::new();
+        let mut queue = vec![(pc, stack, None)];
+        while let Some((pc, mut stack, trail)) = queue.pop() {
+            if let Some(previous) = Trail::find(&trail, pc) {
+                stack = widen(&previous.stack, stack);
+            }
+            if !visited.insert((pc, stack.clone())) {
+                continue;
+            }
+            let trail = Some(Rc::new(Trail {
+                pc,
+                stack: stack.clone(),
+                parent: trail,
+            }));
 
-        // Find more blocks
-        let jump_targets = block.jump_targets(stack)?;
-        for (dest, stack) in jump_targets {
-            // TODO: Fix potential infinite recursion
-            self.recover_control_flow(dest, stack)?;
-        }
+            // Decompile block if not done already
+            let bytecode = &self.bytecode;
+            let block = self
+                .blocks
+                .entry(pc)
+                .or_insert_with(|| Block::from_pc(bytecode, pc));
 
+            // Find more blocks
+            queue.extend(
+                block
+                    .jump_targets(stack)?
+                    .into_iter()
+                    .map(|(pc, stack)| (pc, stack, trail.clone())),
+            );
+        }
         Ok(())
     }
 
+    /// Signature of the compiled function, see [`Context`] and [`ExitCode`].
+    pub fn signature(pointer_type: Type, signature: &mut Signature) {
+        signature.params.push(AbiParam::new(pointer_type));
+        signature.returns.push(AbiParam::new(types::I32));
+    }
+
     pub fn render<'a>(&self, builder: &mut FunctionBuilder<'a>) {
-        for block in self.blocks.values() {
-            block.render(builder);
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+        builder.seal_block(entry);
+        let context = builder.block_params(entry)[0];
+
+        let out_of_gas = builder.create_block();
+        let blocks = self
+            .blocks
+            .keys()
+            .map(|pc| (*pc, builder.create_block()))
+            .collect::<Map<_, _>>();
+        builder.ins().jump(blocks[&0], &[]);
+
+        for (pc, block) in &self.blocks {
+            block.render(builder, &blocks, *pc, context, out_of_gas);
         }
+        block::render_out_of_gas(builder, out_of_gas, context);
         builder.finalize();
     }
+
+    /// Optimize and compile to native code for the host
+    pub fn compile(&self) -> anyhow::Result<Compiled> {
+        let mut program = self.clone();
+        program.optimize();
+        let mut module = JITModule::new(JITBuilder::new(cranelift_module::default_libcall_names()));
+        let mut ctx = module.make_context();
+        Self::signature(
+            module.target_config().pointer_type(),
+            &mut ctx.func.signature,
+        );
+        {
+            let mut func_ctx = FunctionBuilderContext::new();
+            let mut builder = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+            program.render(&mut builder);
+        }
+        let id = module.declare_function("main", Linkage::Export, &ctx.func.signature)?;
+        let _compiled = module.define_function(id, &mut ctx, &mut NullTrapSink {})?;
+        module.clear_context(&mut ctx);
+        Ok(Compiled::finalize(module, id))
+    }
+
+    /// Render to Cranelift IR for the host ISA
+    pub fn to_ir(&self) -> anyhow::Result<String> {
+        let builder = JITBuilder::new(cranelift_module::default_libcall_names());
+        let module = JITModule::new(builder);
+        let mut ctx = module.make_context();
+        Self::signature(
+            module.target_config().pointer_type(),
+            &mut ctx.func.signature,
+        );
+        let mut func_ctx = FunctionBuilderContext::new();
+        let mut builder = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+        self.render(&mut builder);
+        Ok(builder.display(Some(module.isa())).to_string())
+    }
 }
 
-fn main() -> anyhow::Result<()> {
-    println!("Sizeof Opcode {}", std::mem::size_of::<Opcode>());
-    println!("Sizeof U256 {}", std::mem::size_of::<U256>());
-    println!("Sizeof Instruction {}", std::mem::size_of::<Instruction>());
-    println!();
+/// Blocks entered along a path during control flow recovery
+struct Trail {
+    pc:     usize,
+    stack:  Vec<Option<U256>>,
+    parent: Option<Rc<Trail>>,
+}
 
-    // ZeroEx
-    let bytecode = hex!(
-        "6080604052600436106100225760003560e01c8063972fdd261461013857610029565b3661002957005b600061006f600080368080601f016020809104026020016040519081016040528093929190818152602001838380828437600092019190915250929392505061016e9050565b9050600061007c826101ba565b905073ffffffffffffffffffffffffffffffffffffffff81166100aa576100aa6100a583610213565b6102cb565b600060608273ffffffffffffffffffffffffffffffffffffffff166000366040516100d69291906103f5565b600060405180830381855af49150503d8060008114610111576040519150601f19603f3d011682016040523d82523d6000602084013e610116565b606091505b50915091508161012957610129816102cb565b610132816102d3565b50505050005b34801561014457600080fd5b506101586101533660046103ae565b6101ba565b6040516101659190610405565b60405180910390f35b6000816004018351101561018f5761018f6100a56003855185600401610309565b5001602001517fffffffff000000000000000000000000000000000000000000000000000000001690565b60006101c46102db565b7fffffffff0000000000000000000000000000000000000000000000000000000092909216600090815260209290925250604090205473ffffffffffffffffffffffffffffffffffffffff1690565b60607f734e6e1c6ec3f883cac8d13d3e7390b280f5e94424662aa29e27394ed56586c9826040516024016102479190610426565b604080517fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffe08184030181529190526020810180517bffffffffffffffffffffffffffffffffffffffffffffffffffffffff167fffffffff00000000000000000000000000000000000000000000000000000000909316929092179091529050919050565b805160208201fd5b805160208201f35b6000806102e860006102ee565b92915050565b600060808260058111156102fe57fe5b600101901b92915050565b6060632800659560e01b84848460405160240161032893929190610453565b604080517fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffe08184030181529190526020810180517bffffffffffffffffffffffffffffffffffffffffffffffffffffffff167fffffffff000000000000000000000000000000000000000000000000000000009093169290921790915290509392505050565b6000602082840312156103bf578081fd5b81357fffffffff00000000000000000000000000000000000000000000000000000000811681146103ee578182fd5b9392505050565b6000828483379101908152919050565b73ffffffffffffffffffffffffffffffffffffffff91909116815260200190565b7fffffffff0000000000000000000000000000000000000000000000000000000091909116815260200190565b606081016008851061046157fe5b93815260208101929092526040909101529056fea26469706673582212204011e5efaad3c8b897b9f518079a3f612fa6dac9577f8fe651130f5f3b423c8164736f6c634300060c0033"
-    );
+impl Trail {
+    /// The most recent entry into `pc` on the path
+    fn find(trail: &Option<Rc<Self>>, pc: usize) -> Option<&Self> {
+        let mut current = trail.as_deref();
+        while let Some(entry) = current {
+            if entry.pc == pc {
+                return Some(entry);
+            }
+            current = entry.parent.as_deref();
+        }
+        None
+    }
+}
 
-    let prog = Program::from(bytecode.to_vec())?;
-    for (pc, block) in &prog.blocks {
-        println!("{}: ({} gas)", pc, block.gas_cost());
-        println!("{}", block);
+/// Forget the values in `stack` that differ from `previous`
+fn widen(previous: &[Option<U256>], stack: Vec<Option<U256>>) -> Vec<Option<U256>> {
+    if previous.len() != stack.len() {
+        return stack;
     }
+    previous
+        .iter()
+        .zip(stack)
+        .map(|(previous, value)| if *previous == value { value } else { None })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{prelude::*, test::prelude::assert_eq};
 
-    let builder = JITBuilder::new(cranelift_module::default_libcall_names());
-    let module = JITModule::new(builder);
-    let mut ctx = module.make_context();
-    let mut func_ctx = FunctionBuilderContext::new();
-    let mut builder = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+    fn zero_ex() -> Vec<u8> {
+        // ZeroEx
+        hex!(
+            "6080604052600436106100225760003560e01c8063972fdd261461013857610029565b3661002957005b600061006f600080368080601f016020809104026020016040519081016040528093929190818152602001838380828437600092019190915250929392505061016e9050565b9050600061007c826101ba565b905073ffffffffffffffffffffffffffffffffffffffff81166100aa576100aa6100a583610213565b6102cb565b600060608273ffffffffffffffffffffffffffffffffffffffff166000366040516100d69291906103f5565b600060405180830381855af49150503d8060008114610111576040519150601f19603f3d011682016040523d82523d6000602084013e610116565b606091505b50915091508161012957610129816102cb565b610132816102d3565b50505050005b34801561014457600080fd5b506101586101533660046103ae565b6101ba565b6040516101659190610405565b60405180910390f35b6000816004018351101561018f5761018f6100a56003855185600401610309565b5001602001517fffffffff000000000000000000000000000000000000000000000000000000001690565b60006101c46102db565b7fffffffff0000000000000000000000000000000000000000000000000000000092909216600090815260209290925250604090205473ffffffffffffffffffffffffffffffffffffffff1690565b60607f734e6e1c6ec3f883cac8d13d3e7390b280f5e94424662aa29e27394ed56586c9826040516024016102479190610426565b604080517fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffe08184030181529190526020810180517bffffffffffffffffffffffffffffffffffffffffffffffffffffffff167fffffffff00000000000000000000000000000000000000000000000000000000909316929092179091529050919050565b805160208201fd5b805160208201f35b6000806102e860006102ee565b92915050565b600060808260058111156102fe57fe5b600101901b92915050565b6060632800659560e01b84848460405160240161032893929190610453565b604080517fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffe08184030181529190526020810180517bffffffffffffffffffffffffffffffffffffffffffffffffffffffff167fffffffff000000000000000000000000000000000000000000000000000000009093169290921790915290509392505050565b6000602082840312156103bf578081fd5b81357fffffffff00000000000000000000000000000000000000000000000000000000811681146103ee578182fd5b9392505050565b6000828483379101908152919050565b73ffffffffffffffffffffffffffffffffffffffff91909116815260200190565b7fffffffff0000000000000000000000000000000000000000000000000000000091909116815260200190565b606081016008851061046157fe5b93815260208101929092526040909101529056fea26469706673582212204011e5efaad3c8b897b9f518079a3f612fa6dac9577f8fe651130f5f3b423c8164736f6c634300060c0033"
+        )
+        .to_vec()
+    }
 
-    prog.render(&mut builder);
+    #[test]
+    fn test_recover_control_flow() {
+        let program = Program::from(zero_ex()).unwrap();
+        assert!(program.blocks.contains_key(&0));
+        for block in program.blocks.values() {
+            for successor in block.successors() {
+                assert!(program.blocks.contains_key(&successor));
+            }
+        }
+    }
 
-    let shared_builder = settings::builder();
-    let shared_flags = settings::Flags::new(shared_builder);
-    let isa = cranelift::codegen::isa::lookup_by_name("x86_64")?.finish(shared_flags);
+    #[test]
+    fn test_counted_loop() {
+        // PUSH1 0 JUMPDEST PUSH1 1 ADD DUP1 PUSH1 10 GT PUSH1 2 JUMPI STOP
+        let program = Program::from(hex!("60005b60010180600a1160025700").to_vec()).unwrap();
+        assert_eq!(
+            program.blocks.keys().copied().sorted().collect::<Vec<_>>(),
+            vec![0, 2, 13]
+        );
+        assert_eq!(program.blocks[&2].successors(), vec![13, 2]);
+    }
 
-    println!("{}", builder.display(Some(isa.as_ref())));
+    #[test]
+    fn test_growing_loop() {
+        // JUMPDEST PUSH1 1 PUSH1 0 JUMP
+        let program = Program::from(hex!("5b600160005600").to_vec());
+        assert_eq!(program, Err(Error::StackOverflow));
+    }
 
-    Ok(())
+    #[test]
+    fn test_optimize_preserves_gas() {
+        let mut program = Program::from(zero_ex()).unwrap();
+        let gas = program
+            .blocks
+            .iter()
+            .map(|(pc, block)| (*pc, block.gas_cost()))
+            .collect::<Map<_, _>>();
+        program.optimize();
+        for (pc, block) in &program.blocks {
+            assert_eq!(block.gas_cost(), gas[pc]);
+        }
+    }
 }
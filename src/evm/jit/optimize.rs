@@ -0,0 +1,341 @@
+//! Peephole optimizer
+//!
+//! Runs over the blocks of a [`Program`] after control flow recovery. All
+//! rewrites preserve the static gas of a block: gas of removed instructions is
+//! kept in [`Instruction::Charge`].
+//!
+//! Both [`Program::compile`] and the interpreter run the optimized blocks.
+
+use super::{instruction::address_mask, Block, Instruction, Opcode, Program};
+use crate::prelude::*;
+
+impl Program {
+    pub fn optimize(&mut self) {
+        for block in self.blocks.values_mut() {
+            block.optimize();
+        }
+    }
+}
+
+impl Block {
+    pub fn optimize(&mut self) {
+        loop {
+            let mut changed = false;
+            changed |= self.fold_constants();
+            changed |= self.remove_dead_pushes();
+            changed |= self.fold_branch();
+            changed |= self.specialise();
+            self.hoist_charges();
+            if !changed {
+                break;
+            }
+        }
+    }
+
+    /// Replace `instructions[range]` by `replacement`, charging the difference
+    /// in static gas.
+    fn replace(&mut self, start: usize, end: usize, replacement: Vec<Instruction>) {
+        let removed: usize = self.instructions[start..end]
+            .iter()
+            .map(Instruction::base_gas)
+            .sum();
+        let added: usize = replacement.iter().map(Instruction::base_gas).sum();
+        assert!(removed >= added, "Optimization increased gas cost");
+        let charge = Instruction::Charge(removed - added);
+        let _removed = self
+            .instructions
+            .splice(start..end, std::iter::once(charge).chain(replacement));
+    }
+
+    /// `PUSH a PUSH b OP` becomes `PUSH (b OP a)` and `PUSH a OP` becomes
+    /// `PUSH (OP a)`.
+    fn fold_constants(&mut self) -> bool {
+        let mut changed = false;
+        let mut i = 0;
+        while i < self.instructions.len() {
+            match &self.instructions[i..] {
+                [Instruction::Push(a), Instruction::Push(b), Instruction::Opcode(op), ..]
+                    if op.stack() == (2, 1) =>
+                {
+                    if let Some(value) = fold(*op, &[b.clone(), a.clone()]) {
+                        self.replace(i, i + 3, vec![Instruction::Push(value)]);
+                        changed = true;
+                        continue;
+                    }
+                }
+                [Instruction::Push(a), Instruction::Opcode(op), ..] if op.stack() == (1, 1) => {
+                    if let Some(value) = fold(*op, &[a.clone()]) {
+                        self.replace(i, i + 2, vec![Instruction::Push(value)]);
+                        changed = true;
+                        continue;
+                    }
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+        changed
+    }
+
+    /// `PUSH a POP` is removed.
+    fn remove_dead_pushes(&mut self) -> bool {
+        let mut changed = false;
+        let mut i = 0;
+        while i < self.instructions.len() {
+            if let [Instruction::Push(_), Instruction::Opcode(Opcode::Pop), ..] =
+                &self.instructions[i..]
+            {
+                self.replace(i, i + 2, vec![]);
+                changed = true;
+            }
+            i += 1;
+        }
+        changed
+    }
+
+    /// `PUSH cond PUSH dest JUMPI` becomes `PUSH dest JUMP` or a fallthrough.
+    fn fold_branch(&mut self) -> bool {
+        use Instruction::{CondJump, Jump, Push};
+        let len = self.instructions.len();
+        if len < 3 {
+            return false;
+        }
+        let replacement = match &self.instructions[len - 3..] {
+            [Push(condition), Push(destination), CondJump(branch_set, fallthrough)] => {
+                if condition.is_zero() {
+                    vec![Instruction::Fallthrough(*fallthrough)]
+                } else {
+                    vec![Push(destination.clone()), Jump(branch_set.clone())]
+                }
+            }
+            _ => return false,
+        };
+        self.replace(len - 3, len, replacement);
+        true
+    }
+
+    /// Recognize common Solidity idioms.
+    fn specialise(&mut self) -> bool {
+        let mut changed = false;
+        let mut i = 0;
+        while i < self.instructions.len() {
+            let replacement = match &self.instructions[i..] {
+                [Instruction::Push(mask), Instruction::Opcode(Opcode::And), ..]
+                    if *mask == address_mask() =>
+                {
+                    Some(Instruction::AddressMask)
+                }
+                [Instruction::Push(shift), Instruction::Opcode(Opcode::Shr), ..]
+                    if *shift == U256::from(224_u64) =>
+                {
+                    Some(Instruction::Selector)
+                }
+                _ => None,
+            };
+            if let Some(replacement) = replacement {
+                self.replace(i, i + 2, vec![replacement]);
+                changed = true;
+            }
+            i += 1;
+        }
+        changed
+    }
+
+    /// Move charges as early as possible and merge them.
+    ///
+    /// A charge can move past any instruction that does not observe the
+    /// remaining gas. This keeps charges from interrupting patterns.
+    fn hoist_charges(&mut self) {
+        let mut result = Vec::with_capacity(self.instructions.len());
+        let mut segment_start = 0;
+        for inst in self.instructions.drain(..) {
+            match inst {
+                Instruction::Charge(0) => {}
+                Instruction::Charge(gas) => {
+                    if let Some(Instruction::Charge(existing)) = result.get_mut(segment_start) {
+                        *existing += gas;
+                    } else {
+                        result.insert(segment_start, Instruction::Charge(gas));
+                    }
+                }
+                inst => {
+                    let observes_gas = inst.observes_gas();
+                    result.push(inst);
+                    if observes_gas {
+                        segment_start = result.len();
+                    }
+                }
+            }
+        }
+        self.instructions = result;
+    }
+}
+
+/// Evaluate an opcode on constant arguments
+///
+/// Arguments are in stack order, i.e. `args[0]` is the top of the stack.
+/// Returns `None` if the opcode can not be folded.
+pub fn fold(opcode: Opcode, args: &[U256]) -> Option<U256> {
+    Some(match (opcode, args) {
+        (Opcode::Add, [left, right]) => left.clone() + right.clone(),
+        (Opcode::Mul, [left, right]) => left.clone() * right.clone(),
+        (Opcode::Sub, [left, right]) => left.clone() - right.clone(),
+        (Opcode::Div | Opcode::Mod, [_, right]) if right.is_zero() => U256::zero(),
+        (Opcode::Div, [left, right]) => left.clone() / right.clone(),
+        (Opcode::Mod, [left, right]) => left.clone() % right.clone(),
+        (Opcode::Lt, [left, right]) => (left < right).into(),
+        (Opcode::Gt, [left, right]) => (left > right).into(),
+        (Opcode::Eq, [left, right]) => (left == right).into(),
+        (Opcode::IsZero, [value]) => value.is_zero().into(),
+        (Opcode::And, [left, right]) => left.clone() & right.clone(),
+        (Opcode::Or, [left, right]) => left.clone() | right.clone(),
+        (Opcode::Xor, [left, right]) => left.clone() ^ right.clone(),
+        (Opcode::Not, [value]) => !value.clone(),
+        (Opcode::Shl | Opcode::Shr, [shift, _]) if shift.bits() > 8 => U256::zero(),
+        (Opcode::Shl, [shift, value]) => value.clone() << shift.as_usize(),
+        (Opcode::Shr, [shift, value]) => value.clone() >> shift.as_usize(),
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        evm::jit::{Context, ExitCode},
+        test::prelude::assert_eq,
+    };
+
+    fn optimized(bytecode: &[u8]) -> Block {
+        let mut block = Block::from(bytecode);
+        let gas = block.gas_cost();
+        block.optimize();
+        assert_eq!(block.gas_cost(), gas);
+        block
+    }
+
+    #[test]
+    fn test_fold_wraps() {
+        let max = !U256::zero();
+        let two = U256::from(2_u64);
+        // Arguments are in stack order, SUB computes `args[0] - args[1]`
+        assert_eq!(
+            fold(Opcode::Add, &[max.clone(), U256::one()]),
+            Some(U256::zero())
+        );
+        assert_eq!(
+            fold(Opcode::Sub, &[U256::zero(), U256::one()]),
+            Some(max.clone())
+        );
+        assert_eq!(
+            fold(Opcode::Sub, &[U256::one(), two.clone()]),
+            Some(max.clone())
+        );
+        assert_eq!(
+            fold(Opcode::Mul, &[max.clone(), two.clone()]),
+            Some(max.clone() - U256::one())
+        );
+        assert_eq!(
+            fold(Opcode::Div, &[U256::from(7_u64), two]),
+            Some(U256::from(3_u64))
+        );
+        assert_eq!(fold(Opcode::Div, &[max, U256::one()]), Some(!U256::zero()));
+    }
+
+    #[test]
+    fn test_fold_division_by_zero() {
+        let seven = U256::from(7_u64);
+        assert_eq!(
+            fold(Opcode::Div, &[seven.clone(), U256::zero()]),
+            Some(U256::zero())
+        );
+        assert_eq!(
+            fold(Opcode::Mod, &[seven, U256::zero()]),
+            Some(U256::zero())
+        );
+        assert_eq!(
+            fold(Opcode::Div, &[U256::zero(), U256::zero()]),
+            Some(U256::zero())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compiled_specialised() {
+        // PUSH32 0xff..ff DUP1 PUSH20 0xff..ff AND SWAP1 PUSH1 0xe0 SHR PUSH1 0 DUP1
+        // RETURN
+        let mut bytecode = vec![0x7f];
+        bytecode.extend_from_slice(&[0xff; 32]);
+        bytecode.push(0x80);
+        bytecode.push(0x73);
+        bytecode.extend_from_slice(&[0xff; 20]);
+        bytecode.extend_from_slice(&hex!("169060e01c600080f3"));
+        let program = Program::from(bytecode).unwrap();
+        let mut optimized = program.clone();
+        optimized.optimize();
+        assert!(optimized.blocks[&0]
+            .instructions
+            .contains(&Instruction::AddressMask));
+        assert!(optimized.blocks[&0]
+            .instructions
+            .contains(&Instruction::Selector));
+
+        let mut context = Context::new(1000);
+        let compiled = program.compile().unwrap();
+        assert_eq!(compiled.run(&mut context), ExitCode::Return);
+        assert_eq!(context.stack(), vec![
+            address_mask(),
+            U256::from(0xffff_ffff_u64)
+        ]);
+        assert_eq!(context.gas, 1000 - program.blocks[&0].gas_cost() as u64);
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        // PUSH1 1 PUSH1 2 ADD PUSH1 0 MSTORE STOP
+        let block = optimized(&hex!("600160020160005200"));
+        assert_eq!(block.instructions, vec![
+            Instruction::Charge(6),
+            Instruction::Push(U256::from(3_u64)),
+            Instruction::Push(U256::zero()),
+            Instruction::Opcode(Opcode::MStore),
+            Instruction::Opcode(Opcode::Stop),
+        ]);
+    }
+
+    #[test]
+    fn test_dead_push() {
+        // CALLER PUSH1 1 POP STOP
+        let block = optimized(&hex!("3360015000"));
+        assert_eq!(block.instructions, vec![
+            Instruction::Charge(5),
+            Instruction::Opcode(Opcode::Caller),
+            Instruction::Opcode(Opcode::Stop),
+        ]);
+    }
+
+    #[test]
+    fn test_fold_branch() {
+        // PUSH1 1 ISZERO PUSH1 8 JUMPI
+        let block = optimized(&hex!("600115600857"));
+        assert_eq!(block.instructions, vec![
+            Instruction::Charge(19),
+            Instruction::Fallthrough(6),
+        ]);
+    }
+
+    #[test]
+    fn test_specialise() {
+        // PUSH1 0 CALLDATALOAD PUSH1 0xe0 SHR CALLER PUSH20 0xff..ff AND STOP
+        let block = optimized(&hex!(
+            "60003560e01c3373ffffffffffffffffffffffffffffffffffffffff1600"
+        ));
+        assert_eq!(block.instructions, vec![
+            Instruction::Push(U256::zero()),
+            Instruction::Opcode(Opcode::CallDataLoad),
+            Instruction::Selector,
+            Instruction::Opcode(Opcode::Caller),
+            Instruction::AddressMask,
+            Instruction::Opcode(Opcode::Stop),
+        ]);
+    }
+}
@@ -1,9 +1,14 @@
 mod interpreter;
-mod jit;
+pub mod jit;
 mod opcode;
 pub mod precompiles;
+mod signatures;
 
-pub use self::opcode::Opcode;
+pub use self::{
+    interpreter::evaluate,
+    opcode::Opcode,
+    signatures::{selector, SignatureDatabase},
+};
 use zkp_u256::U256;
 
 /// Constants for the current transaction
@@ -27,4 +32,6 @@ pub struct CallInfo {
 pub enum ExecutionResult {
     Return(Vec<u8>),
     Revert(Vec<u8>),
+    /// Execution ran out of gas. All gas is consumed.
+    OutOfGas,
 }
@@ -0,0 +1,68 @@
+//! Function signature database
+//!
+//! Maps four byte function selectors to human readable signatures like
+//! `transfer(address,uint256)`.
+
+use crate::{prelude::*, utils::keccak256};
+use std::{collections::HashMap, path::Path};
+
+/// Compute the function selector of a signature
+pub fn selector(signature: &str) -> u32 {
+    let hash = keccak256(signature.as_bytes()).to_bytes_be();
+    u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]])
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SignatureDatabase(HashMap<u32, Vec<String>>);
+
+impl SignatureDatabase {
+    /// Load from a file with one signature per line
+    ///
+    /// Empty lines and lines starting with `#` are ignored.
+    pub fn load(path: &Path) -> AnyResult<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Reading signatures from {}", path.display()))?;
+        let mut result = Self::default();
+        for line in contents.lines().map(str::trim) {
+            if !line.is_empty() && !line.starts_with('#') {
+                result.insert(line);
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn insert(&mut self, signature: &str) {
+        let signatures = self.0.entry(selector(signature)).or_default();
+        if !signatures.iter().any(|existing| existing == signature) {
+            signatures.push(signature.to_string());
+        }
+    }
+
+    /// All known signatures for a selector. Multiple signatures can collide.
+    pub fn lookup(&self, selector: u32) -> &[String] {
+        self.0.get(&selector).map_or(&[][..], Vec::as_slice)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::prelude::assert_eq;
+
+    #[test]
+    fn test_selector() {
+        assert_eq!(selector("transfer(address,uint256)"), 0xa905_9cbb);
+        assert_eq!(selector("balanceOf(address)"), 0x70a0_8231);
+    }
+
+    #[test]
+    fn test_lookup() {
+        let mut database = SignatureDatabase::default();
+        database.insert("transfer(address,uint256)");
+        database.insert("transfer(address,uint256)");
+        assert_eq!(database.lookup(0xa905_9cbb), &[
+            "transfer(address,uint256)".to_string()
+        ]);
+        assert!(database.lookup(0x70a0_8231).is_empty());
+    }
+}
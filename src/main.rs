@@ -6,6 +6,7 @@
 #![allow(dead_code)]
 
 mod chain;
+mod disasm;
 mod evm;
 mod fetch;
 mod rpc;
@@ -36,7 +37,11 @@ pub mod prelude {
     pub use zkp_u256::{Binary as _, One as _, Pow as _, Zero as _, U256};
 }
 
-use crate::prelude::*;
+use crate::{
+    chain::{types::Address, ForkBlock},
+    prelude::*,
+};
+use bytesize::ByteSize;
 use once_cell::sync::OnceCell;
 use rand_pcg::Mcg128Xsl64;
 use std::{
@@ -78,9 +83,99 @@ struct Options {
 enum Command {
     /// Run an Ethereum JSON-RPC server
     Chain {
-        /// Underlying JSON-RPC url to fork from
+        /// Underlying JSON-RPC url to fork from. Starts an empty chain if
+        /// not specified.
+        #[structopt(long)]
+        fork: Option<String>,
+
+        /// Block number or hash to fork from. Defaults to the latest block.
+        #[structopt(long, requires = "fork")]
+        fork_block: Option<ForkBlock>,
+
+        /// Memory budget for state read from the fork. Unbounded if not
+        /// specified.
+        #[structopt(long)]
+        cache_limit: Option<ByteSize>,
+
+        /// Directory to persist forked state in between runs. State is stored
+        /// per block, so this needs a fixed `--fork-block` to be reused.
+        #[structopt(long, requires = "fork-block", conflicts_with = "follow")]
+        state_cache: Option<PathBuf>,
+
+        /// Maximum size of persisted state
+        #[structopt(long, default_value = "1GiB")]
+        state_cache_limit: ByteSize,
+
+        /// Start from the state in a `geth dump` or genesis alloc JSON file
+        #[structopt(long, conflicts_with = "fork")]
+        state_dump: Option<PathBuf>,
+
+        /// Keep the fork at the upstream head instead of the block it was
+        /// created at. Requires `debug_traceBlockByNumber` upstream.
+        #[structopt(long, requires = "fork")]
+        follow: bool,
+
+        /// Seconds between polls for a new upstream head
+        #[structopt(long, default_value = "12")]
+        follow_interval: u64,
+
+        /// Directory to save every `eth_call` in, with the chain reads it
+        /// made, to run again with `replay`
+        #[structopt(long)]
+        record_calls: Option<PathBuf>,
+    },
+
+    /// Run a call saved with `chain --record-calls` without network access
+    Replay {
+        /// File with the recorded call
+        #[structopt(long)]
+        file: PathBuf,
+    },
+
+    /// Compile contract bytecode ahead of time into an artifact cache
+    Compile {
+        /// File containing hex encoded bytecode
+        #[structopt(long)]
+        file: Option<PathBuf>,
+
+        /// Address of a contract to fetch the code of
+        #[structopt(long, conflicts_with = "file")]
+        address: Option<Address>,
+
+        /// JSON-RPC url to fetch code from
         #[structopt(long, default_value = "http://localhost:8545")]
-        fork: String,
+        node: String,
+
+        /// Directory to store compiled artifacts in
+        #[structopt(long, default_value = "aot-cache")]
+        cache: PathBuf,
+    },
+
+    /// Disassemble contract bytecode
+    Disasm {
+        /// File containing hex encoded bytecode
+        #[structopt(long)]
+        file: Option<PathBuf>,
+
+        /// Address of a contract to fetch the code of
+        #[structopt(long, conflicts_with = "file")]
+        address: Option<Address>,
+
+        /// JSON-RPC url to fetch code from
+        #[structopt(long, default_value = "http://localhost:8545")]
+        node: String,
+
+        /// Write the control flow graph in Graphviz DOT format to this file
+        #[structopt(long)]
+        dot: Option<PathBuf>,
+
+        /// Run the peephole optimizer on the control flow graph
+        #[structopt(long)]
+        optimize: bool,
+
+        /// File with function signatures to label the dispatcher with
+        #[structopt(long)]
+        signatures: Option<PathBuf>,
     },
 
     /// Fetch a chain
@@ -0,0 +1,88 @@
+//! JSON-RPC batch requests
+//!
+//! The generated [`EthereumRpcClient`](super::EthereumRpcClient) sends one
+//! HTTP request per call. This client sends many calls of the same method as
+//! a single JSON-RPC batch.
+
+use crate::prelude::*;
+use jsonrpc_core::{Call, Id, MethodCall, Output, Params, Request, Response, Version};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+#[derive(Clone, Debug)]
+pub struct BatchClient {
+    url:  String,
+    http: reqwest::Client,
+}
+
+impl BatchClient {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url:  url.to_owned(),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    /// Call `method` once for every entry in `params` using one request.
+    ///
+    /// Results are returned in the same order as `params`. Fails if any of
+    /// the calls fails.
+    pub async fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: Vec<Vec<Value>>,
+    ) -> AnyResult<Vec<T>> {
+        let count = params.len();
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        let calls = params
+            .into_iter()
+            .enumerate()
+            .map(|(index, params)| {
+                Call::MethodCall(MethodCall {
+                    jsonrpc: Some(Version::V2),
+                    method:  method.to_owned(),
+                    params:  Params::Array(params),
+                    id:      Id::Num(index as u64),
+                })
+            })
+            .collect();
+        let response: Response = self
+            .http
+            .post(&self.url)
+            .json(&Request::Batch(calls))
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .with_context(|| format!("Sending batch of {} {} calls", count, method))?
+            .json()
+            .await
+            .context("Parsing batch response")?;
+        let outputs = match response {
+            Response::Batch(outputs) => outputs,
+            Response::Single(output) => vec![output],
+        };
+
+        // Responses may come back in any order
+        let mut results: Vec<Option<T>> = (0..count).map(|_| None).collect();
+        for output in outputs {
+            let (id, result) = match output {
+                Output::Success(success) => (success.id, success.result),
+                Output::Failure(failure) => {
+                    return Err(anyhow!("Error in {} call: {}", method, failure.error));
+                }
+            };
+            let slot = match id {
+                Id::Num(index) => results.get_mut(index as usize),
+                _ => None,
+            }
+            .ok_or_else(|| anyhow!("Unexpected id {:?} in batch response", id))?;
+            *slot = Some(serde_json::from_value(result)?);
+        }
+        results
+            .into_iter()
+            .map(|result| result.ok_or_else(|| anyhow!("Missing result in batch response")))
+            .collect()
+    }
+}
@@ -1,25 +1,71 @@
 use super::EthereumRpc;
 use crate::{
-    chain::types::{
-        rpc::{
-            AccountRange, BlockNumber, Bytes, CallRequest, GenesisConfig, Hex, Log, LogFilter,
-            StorageRange, StorageSlot, Transaction, TransactionReceipt,
+    chain::{
+        self, genesis, overrides,
+        trie::StateTrie,
+        types::{
+            rpc::{
+                access_list_keys, AccountProof, AccountRange, BlockNumber, BlockOverrides, Bytes,
+                CallRequest, GenesisConfig, Hex, HexFull, Log, LogFilter, ResetParams,
+                StateOverride, StorageRange, StorageSlot, Transaction, TransactionReceipt,
+            },
+            Address, Block, FullBlock, RpcTransaction,
         },
-        Address, Block, FullBlock, RpcTransaction,
+        BlockStore, CachePolicy, ChainState, Empty, Fork, ForkBlock, Recorder, Recording, Replay,
+        StateIndex,
     },
+    evm::{evaluate, CallInfo, ExecutionResult, TransactionInfo},
     prelude::*,
-    utils::RlpHash,
+    utils::{keccak256, RlpHash},
 };
 use jsonrpc_core::Result as RpcResult;
-use std::{collections::HashMap, sync::RwLock};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+};
+use tokio::runtime::Handle;
+
+/// Gas limit of calls that do not specify one
+const CALL_GAS_CAP: usize = 50_000_000;
 
 #[allow(clippy::module_name_repetitions)]
 pub struct RpcHandler {
     pub client_version: String,
     pub chain_id:       usize,
     pub gas_price:      U256,
-    pub genesis:        RwLock<Block>,
-    pub header:         RwLock<Block>,
+    pub blocks:         RwLock<BlockStore>,
+    pub chain:          RwLock<Fork<Arc<dyn ChainState>>>,
+    pub state_trie:     Mutex<StateTrie>,
+    pub state_index:    Mutex<StateIndex>,
+    /// Used for forks created by `hardhat_reset`
+    pub cache_policy:   CachePolicy,
+    /// The fork is followed or persisted, `hardhat_reset` can not replace it
+    /// with another fork
+    pub fixed_fork:     bool,
+    /// The base is a remote chain. Its state is not in `state_trie`.
+    pub forked:         AtomicBool,
+    /// Runtime for chain reads. Remote chains need a Tokio reactor, which the
+    /// RPC server threads do not provide.
+    pub runtime:        Handle,
+    /// Directory to save every `eth_call` in, with the chain reads it made.
+    /// See [`replay_call`].
+    pub record_calls:   Option<PathBuf>,
+}
+
+/// An `eth_call` with the chain reads it made
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordedCall {
+    pub call:            CallRequest,
+    pub gas:             usize,
+    pub state_override:  Option<StateOverride>,
+    pub block_overrides: Option<BlockOverrides>,
+    pub recording:       Recording,
 }
 
 impl RpcHandler {
@@ -72,10 +118,13 @@ impl EthereumRpc for RpcHandler {
         Ok(format!("{}", self.chain_id))
     }
 
+    fn chain_id(&self) -> RpcResult<Hex<u64>> {
+        Ok((self.chain_id as u64).into())
+    }
+
     fn block_number(&self) -> RpcResult<Hex<u64>> {
-        let lock = self.header.read().map_err(internal_error)?;
-        let block = lock.clone();
-        let number = block.header.number;
+        let blocks = self.blocks.read().map_err(internal_error)?;
+        let number = blocks.head().map_or(0, |stored| stored.block.header.number);
         Ok(number.into())
     }
 
@@ -84,59 +133,170 @@ impl EthereumRpc for RpcHandler {
         block_number: BlockNumber,
         _full: bool,
     ) -> RpcResult<Option<FullBlock>> {
-        // TODO: Use `block_number` and `full`
-        let mutex = match block_number {
-            BlockNumber::Number(0) => &self.genesis,
-            BlockNumber::Number(1) => &self.header,
-            _ => unimplemented!(),
-        };
-        let lock = mutex.read().map_err(internal_error)?;
-        let block = lock.clone();
-        self.return_block(block)
+        // TODO: Use `full`
+        let blocks = self.blocks.read().map_err(internal_error)?;
+        match blocks.resolve(block_number) {
+            Some(stored) => self.return_block(stored.block.clone()),
+            None => Ok(None),
+        }
     }
 
-    fn get_block_by_hash(&self, _block_hash: U256, full: bool) -> RpcResult<Option<FullBlock>> {
-        // TODO: Use `block_hash` and `full`
-        let lock = self.header.read().map_err(internal_error)?;
-        self.return_block(lock.clone())
+    fn get_block_by_hash(&self, block_hash: U256, _full: bool) -> RpcResult<Option<FullBlock>> {
+        // TODO: Use `full`
+        let blocks = self.blocks.read().map_err(internal_error)?;
+        match blocks.by_hash(&block_hash) {
+            Some(stored) => self.return_block(stored.block.clone()),
+            None => Ok(None),
+        }
     }
 
     fn get_nonce(&self, address: Address, _block_number: BlockNumber) -> RpcResult<Hex<u64>> {
-        Ok(match address.to_array() {
-            hex!("a94f5374fce5edbc8e2a8697c15331677e6ebf0b") => 1,
-            _ => 0,
+        let chain = self.chain.read().map_err(internal_error)?;
+        let nonce = self
+            .runtime
+            .block_on(chain.nonce(&address))
+            .map_err(internal_error)?;
+        Ok((nonce as u64).into())
+    }
+
+    fn get_balance(&self, address: Address, _block_number: BlockNumber) -> RpcResult<Hex<U256>> {
+        let chain = self.chain.read().map_err(internal_error)?;
+        let balance = self
+            .runtime
+            .block_on(chain.balance(&address))
+            .map_err(internal_error)?;
+        Ok(balance.into())
+    }
+
+    fn get_code(&self, address: Address, _block_number: BlockNumber) -> RpcResult<Bytes> {
+        let chain = self.chain.read().map_err(internal_error)?;
+        let code = self
+            .runtime
+            .block_on(chain.code(&address))
+            .map_err(internal_error)?;
+        Ok(code.into())
+    }
+
+    fn get_storage_at(
+        &self,
+        address: Address,
+        position: Hex<U256>,
+        _block_number: BlockNumber,
+    ) -> RpcResult<HexFull<U256>> {
+        let chain = self.chain.read().map_err(internal_error)?;
+        let value = self
+            .runtime
+            .block_on(chain.storage(&address, position.as_ref()))
+            .map_err(internal_error)?;
+        Ok(value.into())
+    }
+
+    fn get_proof(
+        &self,
+        address: Address,
+        storage_keys: Vec<Hex<U256>>,
+        block_number: BlockNumber,
+    ) -> RpcResult<AccountProof> {
+        require_latest(block_number)?;
+        let chain = self.chain.read().map_err(internal_error)?;
+        if self.fixed_fork || self.forked.load(Ordering::SeqCst) {
+            return Err(jsonrpc_core::Error::invalid_params(
+                "Proofs are not supported on a forked chain",
+            ));
         }
-        .into())
+        let mut state_trie = self.state_trie.lock().map_err(internal_error)?;
+        state_trie.apply(&chain.writes());
+        let slots = storage_keys
+            .into_iter()
+            .map(Hex::into_inner)
+            .collect::<Vec<_>>();
+        Ok(state_trie.proof(address, &slots))
     }
 
-    fn get_balance(&self, address: Address, block_number: BlockNumber) -> RpcResult<Hex<U256>> {
-        Ok(match address.to_array() {
-            hex!("0f572e5295c57f15886f9b263e2f6d2d6c7b5ec6") => {
-                u256h!("00000000000000000000000000000000000000000000d3c21bcecceda100000b")
-            }
-            hex!("2adc25665018aa1fe0e6bc666dac8fc2697ff9ba") => {
-                u256h!("0000000000000000000000000000000000000000000000001bc16d674ecf8270")
+    fn call(
+        &self,
+        call: CallRequest,
+        block_number: Option<BlockNumber>,
+        state_override: Option<StateOverride>,
+        block_overrides: Option<BlockOverrides>,
+    ) -> RpcResult<Bytes> {
+        require_latest(block_number.unwrap_or_default())?;
+        let gas = call
+            .gas
+            .as_ref()
+            .map_or(CALL_GAS_CAP, |gas| *gas.as_ref() as usize);
+        let chain = self.chain.read().map_err(internal_error)?;
+        let result = match &self.record_calls {
+            Some(directory) => {
+                let recorder = Recorder::from(&*chain);
+                let result = simulate(
+                    &self.runtime,
+                    &recorder,
+                    &call,
+                    gas,
+                    state_override.as_ref(),
+                    block_overrides.as_ref(),
+                )?;
+                let recorded = RecordedCall {
+                    call,
+                    gas,
+                    state_override,
+                    block_overrides,
+                    recording: recorder.into_recording(),
+                };
+                if let Err(err) = save_call(directory, &recorded) {
+                    warn!("Not recording call: {:#}", err);
+                }
+                result
             }
-            hex!("a94f5374fce5edbc8e2a8697c15331677e6ebf0b") => {
-                u256h!("0000000000000000000000000000000000000000000000007ffffffffff87d75")
+            None => {
+                simulate(
+                    &self.runtime,
+                    &*chain,
+                    &call,
+                    gas,
+                    state_override.as_ref(),
+                    block_overrides.as_ref(),
+                )?
             }
-            _ => U256::zero(),
-        }
-        .into())
+        };
+        Ok(call_output(result)?.into())
     }
 
-    fn get_code(&self, address: Address, _block_number: BlockNumber) -> RpcResult<Bytes> {
-        Ok(match address.to_array() {
-            hex!("0f572e5295c57f15886f9b263e2f6d2d6c7b5ec6") => {
-                hex!("7fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff7feeeeeeeeeeeeeeeeeeeeeeeeeeeeefeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee1860005500").to_vec()
+    fn estimate_gas(
+        &self,
+        call: CallRequest,
+        block_number: Option<BlockNumber>,
+        state_override: Option<StateOverride>,
+    ) -> RpcResult<Hex<U256>> {
+        require_latest(block_number.unwrap_or_default())?;
+        let cap = call
+            .gas
+            .as_ref()
+            .map_or(CALL_GAS_CAP, |gas| *gas.as_ref() as usize);
+        let chain = self.chain.read().map_err(internal_error)?;
+        let run = |gas| {
+            simulate(
+                &self.runtime,
+                &*chain,
+                &call,
+                gas,
+                state_override.as_ref(),
+                None,
+            )
+        };
+        let _output = call_output(run(cap)?)?;
+
+        // Lowest gas limit for which the call still returns
+        let (mut low, mut high) = (intrinsic_gas(&call), cap);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match run(mid)? {
+                ExecutionResult::Return(_) => high = mid,
+                _ => low = mid + 1,
             }
-            _ => Vec::new(),
         }
-        .into())
-    }
-
-    fn estimate_gas(&self, _call: CallRequest) -> RpcResult<Hex<U256>> {
-        Ok(U256::zero().into())
+        Ok(U256::from(high).into())
     }
 
     fn send_raw_transaction(&self, _data: Vec<u8>) -> RpcResult<U256> {
@@ -145,9 +305,10 @@ impl EthereumRpc for RpcHandler {
 
     fn get_transaction_receipt(
         &self,
-        _transaction_hash: U256,
+        transaction_hash: U256,
     ) -> RpcResult<Option<TransactionReceipt>> {
-        Ok(Some(TransactionReceipt::default()))
+        let blocks = self.blocks.read().map_err(internal_error)?;
+        Ok(blocks.receipt(&transaction_hash).cloned())
     }
 
     fn get_logs(&self, _filter: LogFilter) -> RpcResult<Vec<Log>> {
@@ -155,11 +316,13 @@ impl EthereumRpc for RpcHandler {
     }
 
     fn evm_snapshot(&self) -> RpcResult<Hex<u64>> {
-        Ok(1.into())
+        let mut chain = self.chain.write().map_err(internal_error)?;
+        Ok((chain.checkpoint() as u64).into())
     }
 
-    fn evm_revert(&self, _snapshot: Hex<u64>) -> RpcResult<bool> {
-        Ok(true)
+    fn evm_revert(&self, snapshot: Hex<u64>) -> RpcResult<bool> {
+        let mut chain = self.chain.write().map_err(internal_error)?;
+        Ok(chain.revert(snapshot.into_inner() as usize))
     }
 
     fn evm_increase_time(&self, _amount_sec: u64) -> RpcResult<u64> {
@@ -179,13 +342,54 @@ impl EthereumRpc for RpcHandler {
         todo!()
     }
 
-    fn test_set_chain_params(&self, genesis: GenesisConfig) -> RpcResult<bool> {
-        dbg!(genesis);
-        // TODO: Get from input
-        let genesis = crate::serde::rlp::from_rlp(&hex!("f901fdf901f8a00000000000000000000000000000000000000000000000000000000000000000a01dcc4de8dec75d7aab85b567b6ccd41ad312451b948a7413f0a142fd40d49347942adc25665018aa1fe0e6bc666dac8fc2697ff9baa0829e96e4071585b5e6f3f966a5ff59b8a2021a3827571c25bc8a739982f3f9e9a056e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421a056e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421b90100000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000008302000080887fffffffffffffff808042a00000000000000000000000000000000000000000000000000000000000000000880000000000000000c0c0")).unwrap();
-        let mut lock = self.genesis.write().unwrap();
-        *lock = genesis;
+    /// Forking is rejected in follow mode and with state persistence, which
+    /// keep updating the fork the server was started with.
+    fn hardhat_reset(&self, params: Option<ResetParams>) -> RpcResult<bool> {
+        let forking = params.and_then(|params| params.forking);
+        let forked = forking.is_some();
+        let base: Arc<dyn ChainState> = match forking {
+            Some(_) if self.fixed_fork => {
+                return Err(jsonrpc_core::Error::invalid_params(
+                    "Forking is not supported with --follow or --state-cache",
+                ));
+            }
+            Some(forking) => {
+                let block = forking.block_number.map(ForkBlock::Number);
+                self.runtime
+                    .block_on(chain::fork(&forking.json_rpc_url, self.cache_policy, block))
+                    .map_err(internal_error)?
+                    .into_inner()
+            }
+            None => Arc::new(Empty),
+        };
+        let mut chain = self.chain.write().map_err(internal_error)?;
+        let mut state_trie = self.state_trie.lock().map_err(internal_error)?;
+        *state_trie = StateTrie::default();
+        let mut state_index = self.state_index.lock().map_err(internal_error)?;
+        *state_index = StateIndex::default();
+        *chain = Fork::from(base);
+        self.forked.store(forked, Ordering::SeqCst);
+        let mut blocks = self.blocks.write().map_err(internal_error)?;
+        *blocks = BlockStore::default();
+        let _genesis = blocks.insert(Block::default(), Vec::new());
+        Ok(true)
+    }
 
+    fn test_set_chain_params(&self, genesis: GenesisConfig) -> RpcResult<bool> {
+        let (state, block) = genesis::build(&genesis).map_err(internal_error)?;
+        let mut chain = self.chain.write().map_err(internal_error)?;
+        let mut state_trie = self.state_trie.lock().map_err(internal_error)?;
+        let writes = state.writes();
+        *state_trie = StateTrie::from(&writes.state);
+        let mut state_index = self.state_index.lock().map_err(internal_error)?;
+        *state_index = StateIndex::default();
+        state_index.apply(&writes.state);
+        let base: Arc<dyn ChainState> = Arc::new(state);
+        *chain = Fork::from(base);
+        self.forked.store(false, Ordering::SeqCst);
+        let mut blocks = self.blocks.write().map_err(internal_error)?;
+        *blocks = BlockStore::default();
+        let _hash = blocks.insert(block, Vec::new());
         Ok(true)
     }
 
@@ -196,12 +400,9 @@ impl EthereumRpc for RpcHandler {
         debug!("Tx hash = {:?}", block.transactions.trie_hash());
         debug!("Ommer hash = {:?}", block.ommers.rlp_hash());
         debug!("Block hash = {:?}", block.header.rlp_hash());
-        let block_hash = block.header.rlp_hash();
-
-        let mut lock = self.header.write().map_err(internal_error)?;
-        *lock = block;
 
-        Ok(block_hash)
+        let mut blocks = self.blocks.write().map_err(internal_error)?;
+        Ok(blocks.insert(block, Vec::new()))
     }
 
     fn get_block_rlp(&self, block_number: u64) -> RpcResult<Bytes> {
@@ -210,52 +411,202 @@ impl EthereumRpc for RpcHandler {
 
     fn account_range(
         &self,
-        block_id: String,
-        tx_index: u64,
+        _block_id: String,
+        _tx_index: u64,
         start: U256,
         max_results: usize,
     ) -> RpcResult<AccountRange> {
-        let addresses = [
-            Address::from(hex!("0f572e5295c57f15886f9b263e2f6d2d6c7b5ec6")),
-            Address::from(hex!("2adc25665018aa1fe0e6bc666dac8fc2697ff9ba")),
-            Address::from(hex!("a94f5374fce5edbc8e2a8697c15331677e6ebf0b")),
-        ];
+        // TODO: Use `block_id` and `tx_index`
+        let chain = self.chain.read().map_err(internal_error)?;
+        let mut state_index = self.state_index.lock().map_err(internal_error)?;
+        state_index.apply(&chain.writes().state);
         let mut address_map = HashMap::new();
-        for addr in &addresses {
-            address_map.insert(addr.rlp_hash(), addr.clone());
+        let mut next_key = U256::zero();
+        for (hash, address) in state_index.accounts_from(&start) {
+            let address = Address::from_word(address);
+            let account = self
+                .runtime
+                .block_on(chain.account(&address))
+                .map_err(internal_error)?;
+            if account.is_empty() {
+                // Reverted and empty accounts are not part of the state
+                continue;
+            }
+            if address_map.len() == max_results {
+                next_key = hash.clone();
+                break;
+            }
+            let _previous = address_map.insert(hash.clone(), address);
         }
         Ok(AccountRange {
             address_map,
-            next_key: U256::zero(),
+            next_key,
         })
     }
 
     fn storage_range(
         &self,
-        block_id: String,
-        tx_index: u64,
+        _block_id: String,
+        _tx_index: u64,
         address: Address,
         start: U256,
         max_results: usize,
     ) -> RpcResult<StorageRange> {
-        if address.to_array() == hex!("0f572e5295c57f15886f9b263e2f6d2d6c7b5ec6") {
-            let mut storage = HashMap::new();
-            let key = U256::zero().into();
-            let value = u256h!("1111111111111111111111111111101111111111111111111111111111111111");
-            storage.insert(-U256::one(), StorageSlot { key, value });
-            Ok(StorageRange {
-                storage,
-                complete: true,
+        // TODO: Use `block_id` and `tx_index`
+        let chain = self.chain.read().map_err(internal_error)?;
+        let mut state_index = self.state_index.lock().map_err(internal_error)?;
+        state_index.apply(&chain.writes().state);
+        let mut storage = HashMap::new();
+        let mut next_key = None;
+        for (hash, slot) in state_index.slots_from(&address.to_word(), &start) {
+            let value = self
+                .runtime
+                .block_on(chain.storage(&address, slot))
+                .map_err(internal_error)?;
+            if value.is_zero() {
+                // Cleared slots are not part of the state
+                continue;
+            }
+            if storage.len() == max_results {
+                next_key = Some(hash.clone());
+                break;
+            }
+            let _previous = storage.insert(hash.clone(), StorageSlot {
+                key: slot.clone().into(),
+                value,
+            });
+        }
+        Ok(StorageRange {
+            storage,
+            complete: next_key.is_none(),
+            next_key,
+        })
+    }
+}
+
+/// Re-run a recorded call without access to the chain it was recorded on
+///
+/// Fails if the call reads anything other than the recorded reads.
+pub fn replay_call(runtime: &Handle, recorded: &RecordedCall) -> RpcResult<Vec<u8>> {
+    let replay = Replay::from(recorded.recording.clone());
+    let result = simulate(
+        runtime,
+        &replay,
+        &recorded.call,
+        recorded.gas,
+        recorded.state_override.as_ref(),
+        recorded.block_overrides.as_ref(),
+    )?;
+    if replay.remaining() > 0 {
+        warn!("{} recorded reads were not replayed", replay.remaining());
+    }
+    call_output(result)
+}
+
+/// Write `recorded` to `directory`, named by its hash
+fn save_call(directory: &Path, recorded: &RecordedCall) -> AnyResult<()> {
+    let json = serde_json::to_vec_pretty(recorded)?;
+    let name = hex::encode(keccak256(&json).to_bytes_be());
+    let path = directory.join(format!("{}.json", name));
+    fs::write(&path, json).with_context(|| format!("Writing {}", path.display()))?;
+    info!("Recorded call in {}", path.display());
+    Ok(())
+}
+
+/// Execute `call` on a throwaway layer over `chain`
+///
+/// `gas` includes the intrinsic gas of the transaction.
+fn simulate<C: ChainState>(
+    runtime: &Handle,
+    chain: C,
+    call: &CallRequest,
+    gas: usize,
+    state_override: Option<&StateOverride>,
+    block_overrides: Option<&BlockOverrides>,
+) -> RpcResult<ExecutionResult> {
+    let to = call
+        .to
+        .as_ref()
+        .ok_or_else(|| parse_error("Contract creation calls are not supported"))?;
+    let initial_gas = match gas.checked_sub(intrinsic_gas(call)) {
+        Some(initial_gas) => initial_gas,
+        None => return Ok(ExecutionResult::OutOfGas),
+    };
+    let mut layer = Fork::from(chain);
+    if let Some(state_override) = state_override {
+        overrides::apply_state(&mut layer, state_override).map_err(parse_error)?;
+    }
+    let mut block = runtime.block_on(layer.block()).map_err(internal_error)?;
+    if let Some(block_overrides) = block_overrides {
+        overrides::apply_block(&mut block, block_overrides);
+    }
+    runtime
+        .block_on(layer.prefetch(&access_list_keys(&call.access_list)))
+        .map_err(internal_error)?;
+    let sender = call.from.to_word();
+    let transaction = TransactionInfo {
+        origin:    sender.clone(),
+        gas_price: call
+            .gas_price
+            .clone()
+            .map_or_else(U256::zero, Hex::into_inner),
+    };
+    let call = CallInfo {
+        sender,
+        address: to.to_word(),
+        call_value: call.value.clone().map_or_else(U256::zero, Hex::into_inner),
+        initial_gas,
+        input: call.data.clone().map_or_else(Vec::new, Bytes::to_vec),
+    };
+    runtime
+        .block_on(evaluate(&mut layer, &block, &transaction, &call))
+        .map_err(internal_error)
+}
+
+/// Gas charged before execution starts
+fn intrinsic_gas(call: &CallRequest) -> usize {
+    let data = call.data.as_ref().map_or(&[][..], Bytes::as_slice);
+    21000
+        + data
+            .iter()
+            .map(|byte| if *byte == 0 { 4 } else { 16 })
+            .sum::<usize>()
+}
+
+/// Return data of a call, reverts are reported like geth does
+fn call_output(result: ExecutionResult) -> RpcResult<Vec<u8>> {
+    match result {
+        ExecutionResult::Return(data) => Ok(data),
+        ExecutionResult::Revert(data) => {
+            Err(jsonrpc_core::Error {
+                code:    jsonrpc_core::ErrorCode::ServerError(3),
+                message: "execution reverted".into(),
+                data:    Some(serde_json::to_value(Bytes::from(data)).map_err(internal_error)?),
             })
-        } else {
-            Ok(StorageRange {
-                storage:  HashMap::new(),
-                complete: true,
+        }
+        ExecutionResult::OutOfGas => {
+            Err(jsonrpc_core::Error {
+                code:    jsonrpc_core::ErrorCode::ServerError(-32000),
+                message: "out of gas".into(),
+                data:    None,
             })
         }
     }
 }
 
+/// Only the latest state is kept, earlier blocks can not be simulated on
+fn require_latest(block_number: BlockNumber) -> RpcResult<()> {
+    match block_number {
+        BlockNumber::Latest | BlockNumber::Pending => Ok(()),
+        other => {
+            Err(jsonrpc_core::Error::invalid_params(format!(
+                "State at block {:?} is not available",
+                other
+            )))
+        }
+    }
+}
+
 fn parse_error<T: std::fmt::Display>(err: T) -> jsonrpc_core::Error {
     warn!("Parse error in RPC handler: {}", err);
     jsonrpc_core::Error::invalid_params(err.to_string())
@@ -265,3 +616,415 @@ fn internal_error<T: std::fmt::Display>(err: T) -> jsonrpc_core::Error {
     error!("Internal error in RPC handler: {}", err);
     jsonrpc_core::Error::internal_error()
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        chain::{
+            types::rpc::{AccountOverride, Forking},
+            WriteableChainState,
+        },
+        test::prelude::assert_eq,
+    };
+    use serde_json::{from_value, json};
+    use tokio::runtime::Runtime;
+
+    /// Handler for an empty chain. The runtime must outlive the handler.
+    fn handler() -> (Runtime, RpcHandler) {
+        let runtime = Runtime::new().unwrap();
+        let mut blocks = BlockStore::default();
+        let _genesis = blocks.insert(Block::default(), Vec::new());
+        let base: Arc<dyn ChainState> = Arc::new(Empty);
+        let handler = RpcHandler {
+            client_version: "test".into(),
+            chain_id:       1337,
+            gas_price:      U256::zero(),
+            blocks:         RwLock::new(blocks),
+            chain:          RwLock::new(Fork::from(base)),
+            state_trie:     Mutex::default(),
+            state_index:    Mutex::default(),
+            cache_policy:   CachePolicy::default(),
+            fixed_fork:     false,
+            forked:         AtomicBool::default(),
+            runtime:        runtime.handle().clone(),
+            record_calls:   None,
+        };
+        (runtime, handler)
+    }
+
+    /// Returns storage slot 0, `NUMBER` and `TIMESTAMP` as three words
+    const READER: [u8; 19] = hex!("600054600052436020524260405260606000f3");
+
+    /// Reverts with the word 42
+    const REVERTER: [u8; 10] = hex!("602a60005260206000fd");
+
+    /// Returns if more than 10000 gas is left at `GAS`, reverts otherwise
+    const GAS_CHECK: [u8; 19] = hex!("5a61271010600d5760006000fd5b60006000f3");
+
+    fn words(output: &[u8]) -> Vec<U256> {
+        output
+            .chunks(32)
+            .map(|chunk| {
+                let mut word = [0_u8; 32];
+                word.copy_from_slice(chunk);
+                U256::from_bytes_be(&word)
+            })
+            .collect()
+    }
+
+    fn call_to(address: &Address) -> CallRequest {
+        CallRequest {
+            to: Some(address.clone()),
+            ..CallRequest::default()
+        }
+    }
+
+    #[test]
+    fn test_call_overrides() {
+        let (_runtime, handler) = handler();
+        let address = Address::from_word(&U256::from(0x10));
+        {
+            let mut chain = handler.chain.write().unwrap();
+            chain.set_code(&address, &READER);
+            chain.set_storage(&address, &U256::zero(), &U256::from(3));
+        }
+        let call = |state_override: Option<StateOverride>, block_overrides| {
+            let output = handler
+                .call(call_to(&address), None, state_override, block_overrides)
+                .unwrap();
+            words(output.as_slice())
+        };
+        let storage = |state, state_diff| {
+            let mut overrides = StateOverride::new();
+            let _previous = overrides.insert(address.clone(), AccountOverride {
+                state,
+                state_diff,
+                ..AccountOverride::default()
+            });
+            call(Some(overrides), None)[0].clone()
+        };
+        let slots = |value: u64| -> HashMap<U256, U256> {
+            vec![(U256::zero(), U256::from(value))]
+                .into_iter()
+                .collect()
+        };
+
+        assert_eq!(call(None, None)[0], U256::from(3));
+        // `state` replaces all storage, `stateDiff` only the given slots
+        assert_eq!(storage(Some(HashMap::new()), None), U256::zero());
+        assert_eq!(storage(None, Some(HashMap::new())), U256::from(3));
+        assert_eq!(storage(Some(slots(5)), None), U256::from(5));
+        assert_eq!(storage(None, Some(slots(7))), U256::from(7));
+
+        let block = BlockOverrides {
+            number: Some(42.into()),
+            time:   Some(100.into()),
+        };
+        assert_eq!(&call(None, Some(block))[1..], &[
+            U256::from(42),
+            U256::from(100)
+        ]);
+
+        // Overrides do not leak into the chain
+        assert_eq!(call(None, None)[0], U256::from(3));
+    }
+
+    #[test]
+    fn test_record_replay_call() {
+        let (runtime, mut handler) = handler();
+        let directory = std::env::temp_dir().join(format!("sutro-calls-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        handler.record_calls = Some(directory.clone());
+        let address = Address::from_word(&U256::from(0x10));
+        {
+            let mut chain = handler.chain.write().unwrap();
+            chain.set_code(&address, &READER);
+            chain.set_storage(&address, &U256::zero(), &U256::from(3));
+        }
+        let output = handler.call(call_to(&address), None, None, None).unwrap();
+        let files = fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        let json = fs::read(&files[0]).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(files.len(), 1);
+
+        // Runs again from the recorded reads alone
+        let recorded: RecordedCall = serde_json::from_slice(&json).unwrap();
+        assert_eq!(
+            replay_call(runtime.handle(), &recorded).unwrap(),
+            output.to_vec()
+        );
+    }
+
+    #[test]
+    fn test_call_revert() {
+        let (_runtime, handler) = handler();
+        let address = Address::from_word(&U256::from(0x10));
+        handler.chain.write().unwrap().set_code(&address, &REVERTER);
+        let error = handler
+            .call(call_to(&address), None, None, None)
+            .unwrap_err();
+        assert_eq!(error.code, jsonrpc_core::ErrorCode::ServerError(3));
+        assert_eq!(error.message, "execution reverted");
+        assert_eq!(
+            error.data,
+            Some(serde_json::json!(format!("0x{:064x}", 42)))
+        );
+
+        let error = handler
+            .estimate_gas(call_to(&address), None, None)
+            .unwrap_err();
+        assert_eq!(error.code, jsonrpc_core::ErrorCode::ServerError(3));
+    }
+
+    #[test]
+    fn test_estimate_gas() {
+        let (_runtime, handler) = handler();
+        let address = Address::from_word(&U256::from(0x10));
+        handler
+            .chain
+            .write()
+            .unwrap()
+            .set_code(&address, &GAS_CHECK);
+        let estimate = handler.estimate_gas(call_to(&address), None, None).unwrap();
+        // `GAS` costs 2 and must leave more than 10000
+        assert_eq!(estimate, Hex::from(U256::from(21000 + 10003)));
+
+        // Calldata adds to the intrinsic gas
+        let call = CallRequest {
+            data: Some(vec![1, 0].into()),
+            ..call_to(&address)
+        };
+        let estimate = handler.estimate_gas(call, None, None).unwrap();
+        assert_eq!(estimate, Hex::from(U256::from(21000 + 16 + 4 + 10003)));
+
+        // The cap applies
+        let call = CallRequest {
+            gas: Some(30000.into()),
+            ..call_to(&address)
+        };
+        assert!(handler.estimate_gas(call, None, None).is_err());
+
+        // Only the latest state can be simulated on
+        let error = handler
+            .estimate_gas(call_to(&address), Some(BlockNumber::Earliest), None)
+            .unwrap_err();
+        assert_eq!(error.code, jsonrpc_core::ErrorCode::InvalidParams);
+        assert!(handler
+            .call(call_to(&address), Some(1.into()), None, None)
+            .is_err());
+        assert!(handler
+            .call(call_to(&address), Some(BlockNumber::Pending), None, None)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_proof_after_revert() {
+        let (_runtime, handler) = handler();
+        let address = Address::from_word(&U256::one());
+        let proof = || {
+            handler
+                .get_proof(
+                    address.clone(),
+                    vec![U256::zero().into()],
+                    BlockNumber::default(),
+                )
+                .unwrap()
+        };
+        let before = proof();
+
+        let snapshot = handler.evm_snapshot().unwrap();
+        {
+            let mut chain = handler.chain.write().unwrap();
+            chain.set_balance(&address, &U256::from(3));
+            chain.set_storage(&address, &U256::zero(), &U256::from(7));
+        }
+        let written = proof();
+        assert_eq!(written.balance, Hex::from(U256::from(3)));
+        assert_eq!(written.storage_proof[0].value, Hex::from(U256::from(7)));
+
+        assert!(handler.evm_revert(snapshot).unwrap());
+        assert_eq!(proof(), before);
+    }
+
+    #[test]
+    fn test_proof_unavailable() {
+        let (_runtime, mut handler) = handler();
+        let proof = |handler: &RpcHandler, block_number| {
+            handler.get_proof(Address::default(), vec![], block_number)
+        };
+        let error = proof(&handler, BlockNumber::Earliest).unwrap_err();
+        assert_eq!(error.code, jsonrpc_core::ErrorCode::InvalidParams);
+        assert!(proof(&handler, BlockNumber::Pending).is_ok());
+
+        // Forked state is not in the state trie
+        handler.forked.store(true, Ordering::SeqCst);
+        assert!(proof(&handler, BlockNumber::Latest).is_err());
+        assert!(handler.hardhat_reset(None).unwrap());
+        assert!(proof(&handler, BlockNumber::Latest).is_ok());
+        handler.fixed_fork = true;
+        assert!(proof(&handler, BlockNumber::Latest).is_err());
+    }
+
+    /// Genesis with balance 5 and slot 0 set to 7 in account one
+    fn genesis() -> GenesisConfig {
+        from_value(json!({
+            "accounts": {
+                "0x0000000000000000000000000000000000000001": {
+                    "balance": "0x05",
+                    "code": "0x",
+                    "nonce": "0x00",
+                    "storage": {
+                        "0x0000000000000000000000000000000000000000000000000000000000000000":
+                            "0x0000000000000000000000000000000000000000000000000000000000000007"
+                    }
+                }
+            },
+            "genesis": {
+                "author": "0x0000000000000000000000000000000000000000",
+                "difficulty": "0x020000",
+                "extraData": "0x",
+                "gasLimit": "0x47e7c4",
+                "mixHash": "0x0000000000000000000000000000000000000000000000000000000000000000",
+                "nonce": "0x0000000000000000",
+                "timestamp": "0x00"
+            },
+            "sealEngine": "NoProof"
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_proof_of_genesis() {
+        let (_runtime, handler) = handler();
+        assert!(handler.test_set_chain_params(genesis()).unwrap());
+        let address = Address::from_word(&U256::one());
+        let proof = || {
+            handler
+                .get_proof(
+                    address.clone(),
+                    vec![U256::zero().into()],
+                    BlockNumber::default(),
+                )
+                .unwrap()
+        };
+        let genesis = proof();
+        assert_eq!(genesis.balance, Hex::from(U256::from(5)));
+        assert_eq!(genesis.storage_proof[0].value, Hex::from(U256::from(7)));
+
+        // Undoing writes restores the genesis values
+        let snapshot = handler.evm_snapshot().unwrap();
+        {
+            let mut chain = handler.chain.write().unwrap();
+            chain.set_balance(&address, &U256::from(3));
+            chain.set_storage(&address, &U256::zero(), &U256::zero());
+        }
+        assert_eq!(proof().balance, Hex::from(U256::from(3)));
+        assert!(handler.evm_revert(snapshot).unwrap());
+        assert_eq!(proof(), genesis);
+    }
+
+    #[test]
+    fn test_cleared_storage() {
+        let (_runtime, handler) = handler();
+        assert!(handler.test_set_chain_params(genesis()).unwrap());
+        let address = Address::from_word(&U256::one());
+        {
+            let mut chain = handler.chain.write().unwrap();
+            chain.clear_storage(&address);
+            chain.set_storage(&address, &U256::one(), &U256::from(3));
+        }
+        let proof = handler
+            .get_proof(
+                address.clone(),
+                vec![U256::zero().into(), U256::one().into()],
+                BlockNumber::default(),
+            )
+            .unwrap();
+        assert_eq!(proof.storage_proof[0].value, Hex::from(U256::zero()));
+        assert_eq!(proof.storage_proof[1].value, Hex::from(U256::from(3)));
+
+        let range = handler
+            .storage_range(String::new(), 0, address, U256::zero(), 10)
+            .unwrap();
+        let slots = range
+            .storage
+            .values()
+            .map(|slot| (slot.key.clone().into_inner(), slot.value.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(slots, vec![(U256::one(), U256::from(3))]);
+    }
+
+    #[test]
+    fn test_account_range() {
+        let (_runtime, handler) = handler();
+        {
+            let mut chain = handler.chain.write().unwrap();
+            for i in 1..=3_u64 {
+                chain.set_balance(&Address::from_word(&U256::from(i)), &U256::one());
+            }
+            // Empty accounts are skipped
+            chain.set_nonce(&Address::from_word(&U256::from(4)), 0);
+        }
+        let snapshot = handler.evm_snapshot().unwrap();
+        handler
+            .chain
+            .write()
+            .unwrap()
+            .set_balance(&Address::from_word(&U256::from(5)), &U256::one());
+        let range = handler
+            .account_range(String::new(), 0, U256::zero(), 10)
+            .unwrap();
+        assert_eq!(range.address_map.len(), 4);
+        assert!(handler.evm_revert(snapshot).unwrap());
+
+        // Reverted accounts are still indexed, but skipped
+        let range = handler
+            .account_range(String::new(), 0, U256::zero(), 10)
+            .unwrap();
+        assert_eq!(range.address_map.len(), 3);
+        assert!(!range
+            .address_map
+            .values()
+            .any(|address| address.to_word() == U256::from(5)));
+
+        let mut start = U256::zero();
+        let mut pages = Vec::new();
+        loop {
+            let range = handler
+                .account_range(String::new(), 0, start.clone(), 2)
+                .unwrap();
+            pages.push(range.address_map.len());
+            for (hash, address) in &range.address_map {
+                assert!(*hash >= start);
+                assert!(address.to_word() <= U256::from(3));
+            }
+            if range.next_key.is_zero() {
+                break;
+            }
+            assert!(range.address_map.keys().all(|hash| *hash < range.next_key));
+            start = range.next_key;
+        }
+        assert_eq!(pages, vec![2, 1]);
+    }
+
+    #[test]
+    fn test_reset_fixed_fork() {
+        let (_runtime, mut handler) = handler();
+        handler.fixed_fork = true;
+        let forking = ResetParams {
+            forking: Some(Forking {
+                json_rpc_url: "http://localhost:8545".into(),
+                block_number: Some(1),
+            }),
+        };
+        let error = handler.hardhat_reset(Some(forking)).unwrap_err();
+        assert_eq!(error.code, jsonrpc_core::ErrorCode::InvalidParams);
+
+        // Resetting to an empty chain is still possible
+        assert_eq!(handler.hardhat_reset(None), Ok(true));
+    }
+}
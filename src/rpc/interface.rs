@@ -1,8 +1,9 @@
 use crate::{
     chain::types::{
         rpc::{
-            AccountRange, BlockNumber, Bytes, CallRequest, GenesisConfig, Hex, Log, LogFilter,
-            StorageRange, Transaction, TransactionReceipt,
+            AccountProof, AccountRange, BlockNumber, BlockOverrides, Bytes, CallRequest,
+            GenesisConfig, Hex, HexFull, Log, LogFilter, ResetParams, StateOverride, StorageRange,
+            Transaction, TransactionReceipt,
         },
         Address, FullBlock,
     },
@@ -18,9 +19,13 @@ pub trait EthereumRpc {
     fn client_version(&self) -> RpcResult<String>;
 
     /// See <https://eth.wiki/json-rpc/API#net_version>
-    #[rpc(name = "net_version", alias("eth_chainId"))]
+    #[rpc(name = "net_version")]
     fn net_version(&self) -> RpcResult<String>;
 
+    /// See <https://eips.ethereum.org/EIPS/eip-695>
+    #[rpc(name = "eth_chainId")]
+    fn chain_id(&self) -> RpcResult<Hex<u64>>;
+
     /// See <https://eth.wiki/json-rpc/API#eth_sendtransaction>
     #[rpc(name = "eth_sendTransaction")]
     fn send_transaction(&self, tx: Transaction) -> RpcResult<Hex<U256>>;
@@ -49,6 +54,24 @@ pub trait EthereumRpc {
     #[rpc(name = "eth_getBalance")]
     fn get_balance(&self, address: Address, block_number: BlockNumber) -> RpcResult<Hex<U256>>;
 
+    /// See <https://eth.wiki/json-rpc/API#eth_getstorageat>
+    #[rpc(name = "eth_getStorageAt")]
+    fn get_storage_at(
+        &self,
+        address: Address,
+        position: Hex<U256>,
+        block_number: BlockNumber,
+    ) -> RpcResult<HexFull<U256>>;
+
+    /// See <https://eips.ethereum.org/EIPS/eip-1186>
+    #[rpc(name = "eth_getProof")]
+    fn get_proof(
+        &self,
+        address: Address,
+        storage_keys: Vec<Hex<U256>>,
+        block_number: BlockNumber,
+    ) -> RpcResult<AccountProof>;
+
     /// See <https://eth.wiki/json-rpc/API#eth_getlogs>
     #[rpc(name = "eth_getLogs")]
     fn get_logs(&self, filter: LogFilter) -> RpcResult<Vec<Log>>;
@@ -57,9 +80,24 @@ pub trait EthereumRpc {
     #[rpc(name = "eth_getCode")]
     fn get_code(&self, address: Address, block_number: BlockNumber) -> RpcResult<Bytes>;
 
+    /// See <https://geth.ethereum.org/docs/rpc/ns-eth#eth_call>
+    #[rpc(name = "eth_call")]
+    fn call(
+        &self,
+        call: CallRequest,
+        block_number: Option<BlockNumber>,
+        state_override: Option<StateOverride>,
+        block_overrides: Option<BlockOverrides>,
+    ) -> RpcResult<Bytes>;
+
     /// See <https://eth.wiki/json-rpc/API#eth_estimategas>
     #[rpc(name = "eth_estimateGas")]
-    fn estimate_gas(&self, call: CallRequest) -> RpcResult<Hex<U256>>;
+    fn estimate_gas(
+        &self,
+        call: CallRequest,
+        block_number: Option<BlockNumber>,
+        state_override: Option<StateOverride>,
+    ) -> RpcResult<Hex<U256>>;
 
     /// See <https://eth.wiki/json-rpc/API#eth_sendrawtransaction>
     #[rpc(name = "eth_sendRawTransaction")]
@@ -94,6 +132,13 @@ pub trait EthereumRpc {
     #[rpc(name = "evm_lockUnknownAccount")]
     fn evm_lock_unknown_account(&self, address: Address) -> RpcResult<bool>;
 
+    // Hardhat extensions
+    //
+    // See <https://hardhat.org/hardhat-network/docs/reference#hardhat-network-methods>
+
+    #[rpc(name = "hardhat_reset")]
+    fn hardhat_reset(&self, params: Option<ResetParams>) -> RpcResult<bool>;
+
     // Ethereum Test
     //
     // See <https://github.com/ethereum/retesteth/wiki/RPC-Methods>
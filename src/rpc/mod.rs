@@ -4,12 +4,14 @@
 //!
 //! Uses <https://github.com/paritytech/jsonrpc>
 
+mod batch;
 mod handler;
 mod interface;
 mod logger;
 
 pub use self::{
-    handler::RpcHandler,
+    batch::BatchClient,
+    handler::{replay_call, RecordedCall, RpcHandler},
     interface::{EthereumRpc, EthereumRpcClient},
     logger::Logger,
 };
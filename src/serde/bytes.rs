@@ -43,7 +43,7 @@ where
                 Ok(vec.into_iter().collect())
             }
         }
-        deserializer.deserialize_bytes(Visitor(PhantomData))
+        deserializer.deserialize_str(Visitor(PhantomData))
     } else {
         struct Visitor<T>(PhantomData<T>);
         impl<'de, T: FromIterator<u8>> de::Visitor<'de> for Visitor<T> {
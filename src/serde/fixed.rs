@@ -56,7 +56,7 @@ macro_rules! fixed_length_serde {
                             }
                         }
                     }
-                    deserializer.deserialize_bytes(Visitor)
+                    deserializer.deserialize_str(Visitor)
                 } else {
                     struct Visitor;
                     impl<'de> de::Visitor<'de> for Visitor {
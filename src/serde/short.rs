@@ -70,7 +70,7 @@ macro_rules! short_length_serde {
                             Ok(result)
                         }
                     }
-                    deserializer.deserialize_bytes(Visitor)
+                    deserializer.deserialize_str(Visitor)
                 } else {
                     struct Visitor;
                     impl<'de> de::Visitor<'de> for Visitor {
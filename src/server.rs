@@ -1,34 +1,208 @@
-use std::sync::RwLock;
+use std::sync::{Mutex, RwLock};
 
-use super::{fetch::fetch, Command, Options};
-use crate::{chain::types::Block, prelude::*, rpc};
+use super::{
+    disasm::{disasm, load_bytecode},
+    fetch::fetch,
+    Command, Options,
+};
+use crate::{
+    chain::{
+        dump, persist, trie::StateTrie, types::Block, BlockStore, CachePolicy, ChainState,
+        Follower, Fork, ForkBlock, StateIndex,
+    },
+    evm::jit::aot::ArtifactCache,
+    prelude::*,
+    rpc,
+};
+use bytesize::ByteSize;
+use std::{
+    path::PathBuf,
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
+use tokio::sync::broadcast::error::RecvError;
+
+const STATS_INTERVAL: Duration = Duration::from_secs(60);
 
 pub(super) async fn async_main(options: Options) -> AnyResult<()> {
     match options.command {
         Some(Command::Fetch { node, file }) => fetch(node, file).await,
-        Some(Command::Chain { fork }) => chain(fork).await,
+        Some(Command::Chain {
+            fork,
+            fork_block,
+            cache_limit,
+            state_cache,
+            state_cache_limit,
+            state_dump,
+            follow,
+            follow_interval,
+            record_calls,
+        }) => {
+            let policy = CachePolicy {
+                max_bytes: cache_limit,
+            };
+            let follow = if follow {
+                Some(Duration::from_secs(follow_interval))
+            } else {
+                None
+            };
+            chain(
+                fork,
+                fork_block,
+                policy,
+                state_cache,
+                state_cache_limit,
+                state_dump,
+                follow,
+                record_calls,
+            )
+            .await
+        }
+        Some(Command::Replay { file }) => replay(file).await,
+        Some(Command::Compile {
+            file,
+            address,
+            node,
+            cache,
+        }) => compile(file, address, node, cache).await,
+        Some(Command::Disasm {
+            file,
+            address,
+            node,
+            dot,
+            optimize,
+            signatures,
+        }) => disasm(file, address, node, dot, optimize, signatures).await,
         None => unimplemented!(),
     }
 }
 
-async fn chain(url: String) -> AnyResult<()> {
-    use crate::chain::ChainState;
+async fn compile(
+    file: Option<PathBuf>,
+    address: Option<crate::chain::types::Address>,
+    node: String,
+    cache: PathBuf,
+) -> AnyResult<()> {
+    let bytecode = load_bytecode(file, address, &node).await?;
+    let mut cache = ArtifactCache::open(cache).context("Opening artifact cache")?;
+    let artifact = cache.get_or_compile(&bytecode)?;
+    info!("Compiled object is {} bytes", artifact.object.len());
+    Ok(())
+}
 
-    // Create a forked chain
-    // let chain = crate::chain::fork(&url).await.context("Forking chain")?;
-    // let block = chain.block();
-    // info!("Block info: {:#?}", block);
+async fn replay(file: PathBuf) -> AnyResult<()> {
+    let json = std::fs::read(&file).with_context(|| format!("Reading {}", file.display()))?;
+    let recorded: rpc::RecordedCall =
+        serde_json::from_slice(&json).with_context(|| format!("Parsing {}", file.display()))?;
+    // The call blocks on chain reads, which can not happen on a runtime thread
+    let runtime = tokio::runtime::Handle::current();
+    let output = tokio::task::spawn_blocking(move || rpc::replay_call(&runtime, &recorded))
+        .await?
+        .map_err(|err| anyhow!("Error: {}", err))?;
+    println!("0x{}", hex::encode(output));
+    Ok(())
+}
+
+async fn chain(
+    fork: Option<String>,
+    fork_block: Option<ForkBlock>,
+    policy: CachePolicy,
+    state_cache: Option<PathBuf>,
+    state_cache_limit: ByteSize,
+    state_dump: Option<PathBuf>,
+    follow: Option<Duration>,
+    record_calls: Option<PathBuf>,
+) -> AnyResult<()> {
+    if let Some(directory) = &record_calls {
+        std::fs::create_dir_all(directory)
+            .with_context(|| format!("Creating {}", directory.display()))?;
+    }
+    let mut persisted = None;
+    let mut state_trie = StateTrie::default();
+    let mut state_index = StateIndex::default();
+    let forked = fork.is_some();
+    let base: Arc<dyn ChainState> = if let Some(url) = fork {
+        // Create a forked chain
+        let chain = crate::chain::fork(&url, policy, fork_block)
+            .await
+            .context("Forking chain")?;
+        let cache = chain.inner().clone();
 
-    // Create an empty chain
-    let chain = crate::chain::new();
+        // Periodically report cache usage
+        let stats_cache = cache.clone();
+        let _stats_task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(STATS_INTERVAL);
+            loop {
+                let _instant = interval.tick().await;
+                info!("State cache: {}", stats_cache.stats());
+            }
+        });
+
+        // Warm the cache with state persisted by a previous run
+        if let Some(directory) = state_cache {
+            if let Some(path) = persist::state_path(&directory, cache.inner()).await? {
+                if path.exists() {
+                    match persist::load(&path, state_cache_limit) {
+                        Ok(state) => cache.insert_state_set(state),
+                        Err(err) => warn!("Ignoring state in {}: {:#}", path.display(), err),
+                    }
+                }
+                persisted = Some((path, cache.clone()));
+            }
+        }
+
+        // Keep the cache at the upstream head
+        if let Some(interval) = follow {
+            let follower = Follower::new(cache.clone())
+                .await
+                .context("Following upstream head")?;
+            let mut updates = follower.subscribe();
+            let _follow_task = tokio::spawn(follower.run(interval));
+            let _updates_task = tokio::spawn(async move {
+                loop {
+                    match updates.recv().await {
+                        Ok(update) if update.reverted => {
+                            info!("Reorg reverted block {}", update.number)
+                        }
+                        Ok(update) => info!("Following block {}", update.number),
+                        Err(RecvError::Lagged(skipped)) => warn!("Missed {} head updates", skipped),
+                        Err(RecvError::Closed) => break,
+                    }
+                }
+            });
+        }
+
+        let block = chain.block().await?;
+        info!("Block info: {:#?}", block);
+        chain.into_inner()
+    } else if let Some(path) = state_dump {
+        // Load state from a file
+        let chain = dump::load_file(&path)?;
+        let writes = chain.writes();
+        state_trie = StateTrie::from(&writes.state);
+        state_index.apply(&writes.state);
+        Arc::new(chain)
+    } else {
+        // Create an empty chain
+        Arc::new(crate::chain::new().await?.into_inner())
+    };
 
     // Create an RPC server
+    let mut blocks = BlockStore::default();
+    let _genesis = blocks.insert(Block::default(), Vec::new());
     let rpc_handler = rpc::RpcHandler {
         client_version: "sutro/0.0.0".into(),
         chain_id:       1337,
         gas_price:      U256::zero(),
-        genesis:        RwLock::new(Block::default()),
-        header:         RwLock::new(Block::default()),
+        blocks:         RwLock::new(blocks),
+        chain:          RwLock::new(Fork::from(base)),
+        state_trie:     Mutex::new(state_trie),
+        state_index:    Mutex::new(state_index),
+        cache_policy:   policy,
+        fixed_fork:     follow.is_some() || persisted.is_some(),
+        forked:         AtomicBool::new(forked),
+        runtime:        tokio::runtime::Handle::current(),
+        record_calls,
     };
     let addr = "0.0.0.0:8545".parse()?;
     let server = rpc::serve(&addr, rpc_handler)?;
@@ -50,5 +224,11 @@ async fn chain(url: String) -> AnyResult<()> {
     server_stop.close();
     server_task.await?;
 
+    if let Some((path, cache)) = persisted {
+        if let Err(err) = persist::save(&path, &cache.to_state_set(), state_cache_limit) {
+            warn!("Not persisting state: {:#}", err);
+        }
+    }
+
     Ok(())
 }